@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::diagnostic::Span;
+use super::expression::Expression;
+
+// The directives that switch sections or lay out read-only data
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DirectiveKind {
+    Data,       // .data            statements that follow go in the read-only data section
+    Code,       // .code            statements that follow go in the code section
+    Asciiz,     // .asciiz "a", "b" NUL terminated strings
+    Word,       // .word 1, -2      32 bit integers, high byte first
+    Byte,       // .byte 1, 'a'     8 bit integers
+    Double,     // .double 1.5, 2   64 bit floats, high byte first
+    Space,      // .space 16        that many zero bytes
+    Align,      // .align 4         zero bytes up to the next multiple of a power of two
+    Equ,        // .equ SIZE, 64    defines a constant for use in expressions
+}
+
+impl DirectiveKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            DirectiveKind::Data => "data",
+            DirectiveKind::Code => "code",
+            DirectiveKind::Asciiz => "asciiz",
+            DirectiveKind::Word => "word",
+            DirectiveKind::Byte => "byte",
+            DirectiveKind::Double => "double",
+            DirectiveKind::Space => "space",
+            DirectiveKind::Align => "align",
+            DirectiveKind::Equ => "equ",
+        }
+    }
+}
+
+impl fmt::Display for DirectiveKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, ".{}", self.name())
+    }
+}
+
+// Parses a directive name without its `.` (case insensitive)
+impl FromStr for DirectiveKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "data" => Ok(DirectiveKind::Data),
+            "code" => Ok(DirectiveKind::Code),
+            "asciiz" => Ok(DirectiveKind::Asciiz),
+            "word" => Ok(DirectiveKind::Word),
+            "byte" => Ok(DirectiveKind::Byte),
+            "double" => Ok(DirectiveKind::Double),
+            "space" => Ok(DirectiveKind::Space),
+            "align" => Ok(DirectiveKind::Align),
+            "equ" => Ok(DirectiveKind::Equ),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Argument {
+    Integer(i64),       // 12, 'a'
+    Float(f64),         // 1.5
+    String(String),     // "hello"
+    Expression(Expression),     // SIZE*2, replaced by its value before the data is laid out
+}
+
+/* A parsed directive with its arguments:
+
+ 1) .data
+ 2) msg: .asciiz "hi"   => 68 69 00
+ 3) .word 1, 258        => 00 00 00 01 00 00 01 02
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Directive {
+    pub kind: DirectiveKind,
+    pub arguments: Vec<Argument>,
+    pub span: Span,                 // the directive through its last argument
+    pub argument_spans: Vec<Span>,
+}
+
+impl Directive {
+    pub fn new(kind: DirectiveKind, arguments: Vec<Argument>, span: Span) -> Directive {
+        Directive {
+            kind,
+            arguments,
+            span,
+            argument_spans: vec![],
+        }
+    }
+
+    pub fn argument_span(&self, position: usize) -> Span {
+        self.argument_spans.get(position).copied().unwrap_or(self.span)
+    }
+}
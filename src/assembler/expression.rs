@@ -0,0 +1,145 @@
+use std::fmt;
+use crate::diagnostic::Span;
+use super::AssemblerError;
+
+// The helpers that can be called in an expression
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Function {
+    Lo,     // lo(x) => the low byte of x
+    Hi,     // hi(x) => the second byte of x
+}
+
+impl Function {
+    pub fn from_name(name: &str) -> Option<Function> {
+        match name.to_lowercase().as_str() {
+            "lo" => Some(Function::Lo),
+            "hi" => Some(Function::Hi),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    Integer(i64),                                           // 64, 'a'
+    Symbol(String),                                         // SIZE, end, @end
+    Unary(char, Box<Expression>),                           // -x, ~x
+    Binary(char, Box<Expression>, Box<Expression>),         // x + y, x * y, x & y ...
+    Call(Function, Box<Expression>),                        // lo(x), hi(x)
+}
+
+/* A constant expression in an operand or directive, evaluated by the assembler once every
+   symbol it uses is known. Symbols are `.equ` constants or label addresses.
+
+ EX: #SIZE*4+1, #(end - start), #hi(table)
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+impl Expression {
+    pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+        Expression { kind, span }
+    }
+
+    pub fn binary(operator: char, lhs: Expression, rhs: Expression) -> Expression {
+        let span = lhs.span.to(rhs.span);
+        Expression::new(ExpressionKind::Binary(operator, Box::new(lhs), Box::new(rhs)), span)
+    }
+
+    // The value of the expression, looking up every symbol with `lookup`
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, AssemblerError> {
+        let overflow = || AssemblerError::InvalidExpression { span: self.span, reason: String::from("overflows 64 bits") };
+        match &self.kind {
+            ExpressionKind::Integer(value) => Ok(*value),
+            ExpressionKind::Symbol(name) => lookup(name)
+                .ok_or_else(|| AssemblerError::UnknownSymbol { span: self.span, name: name.clone() }),
+            ExpressionKind::Unary(operator, operand) => {
+                let value = operand.evaluate(lookup)?;
+                match operator {
+                    '-' => value.checked_neg().ok_or_else(overflow),
+                    _ => Ok(!value),
+                }
+            },
+            ExpressionKind::Binary(operator, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(lookup)?, rhs.evaluate(lookup)?);
+                match operator {
+                    '+' => lhs.checked_add(rhs).ok_or_else(overflow),
+                    '-' => lhs.checked_sub(rhs).ok_or_else(overflow),
+                    '*' => lhs.checked_mul(rhs).ok_or_else(overflow),
+                    '/' if rhs == 0 => Err(AssemblerError::InvalidExpression { span: self.span, reason: String::from("divides by zero") }),
+                    '/' => lhs.checked_div(rhs).ok_or_else(overflow),
+                    '&' => Ok(lhs & rhs),
+                    '|' => Ok(lhs | rhs),
+                    _ => Ok(lhs ^ rhs),
+                }
+            },
+            ExpressionKind::Call(function, argument) => {
+                let value = argument.evaluate(lookup)?;
+                match function {
+                    Function::Lo => Ok(value & 0xFF),
+                    Function::Hi => Ok((value >> 8) & 0xFF),
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ExpressionKind::Integer(value) => write!(f, "{}", value),
+            ExpressionKind::Symbol(name) => write!(f, "{}", name),
+            ExpressionKind::Unary(operator, operand) => write!(f, "{}{}", operator, operand),
+            ExpressionKind::Binary(operator, lhs, rhs) => write!(f, "({} {} {})", lhs, operator, rhs),
+            ExpressionKind::Call(Function::Lo, argument) => write!(f, "lo({})", argument),
+            ExpressionKind::Call(Function::Hi, argument) => write!(f, "hi({})", argument),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn integer(value: i64) -> Expression {
+        Expression::new(ExpressionKind::Integer(value), Span::default())
+    }
+
+    fn symbol(name: &str) -> Expression {
+        Expression::new(ExpressionKind::Symbol(String::from(name)), Span::default())
+    }
+
+    fn lookup(name: &str) -> Option<i64> {
+        match name {
+            "SIZE" => Some(64),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let expression = Expression::binary('+', Expression::binary('*', symbol("SIZE"), integer(4)), integer(1));
+        assert_eq!(expression.evaluate(&lookup), Ok(257));
+        assert_eq!(expression.to_string(), "((SIZE * 4) + 1)");
+        let call = Expression::new(ExpressionKind::Call(Function::Hi, Box::new(integer(0x1234))), Span::default());
+        assert_eq!(call.evaluate(&lookup), Ok(0x12));
+        let not = Expression::new(ExpressionKind::Unary('~', Box::new(integer(0))), Span::default());
+        assert_eq!(not.evaluate(&lookup), Ok(-1));
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        assert_eq!(
+            symbol("missing").evaluate(&lookup),
+            Err(AssemblerError::UnknownSymbol { span: Span::default(), name: String::from("missing") }),
+        );
+        let division = Expression::binary('/', integer(1), integer(0));
+        assert!(matches!(division.evaluate(&lookup), Err(AssemblerError::InvalidExpression { .. })));
+        let overflow = Expression::binary('*', integer(i64::MAX), integer(2));
+        assert!(matches!(overflow.evaluate(&lookup), Err(AssemblerError::InvalidExpression { .. })));
+    }
+}
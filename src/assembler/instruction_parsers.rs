@@ -1,10 +1,69 @@
-use lexer::Token;
+use crate::diagnostic::Span;
+use crate::instructions::{Opcode, OperandKind};
+use super::expression::Expression;
 
-#[derive(debug, PartialEq)]
+// Every instruction the VM decodes is exactly this many bytes long
+pub const INSTRUCTION_SIZE: usize = 4;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Operand {
+    Register(u8),       // $3
+    FloatRegister(u8),  // $f3
+    Integer(i64),       // #1000
+    Label(String),      // @loop
+    Expression(Expression),     // #SIZE*4+1, replaced by its value once every symbol is known
+}
+
+/* A parsed instruction:
+
+ 1) LOAD $1 #10    => 01 01 00 0A
+ 2) ADD $0 $1 $3   => 02 00 01 03
+ 3) JMP $0         => 06 00 00 00
+ 4) JMP @start     => 10 00 00 00   (when `start:` is at address 0)
+ 5) LOADM $1 $2 #4 => 1C 01 02 04
+ 6) ADDF $f0 $f1 $f2 => 31 00 01 02
+*/
+#[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
-    opcode: Token::Opcode,
-    operand1: Option<Token>,
-    operand2: Option<Token>,
-    operand3: Option<Token>,
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    pub span: Span,                 // the opcode through the last operand
+    pub operand_spans: Vec<Span>,   // where each operand is, when it came from source
 }
 
+impl AssemblerInstruction {
+    pub fn new(opcode: Opcode, operands: Vec<Operand>, span: Span) -> AssemblerInstruction {
+        AssemblerInstruction {
+            opcode,
+            operands,
+            span,
+            operand_spans: vec![],
+        }
+    }
+
+    // The span of an operand, or of the whole instruction if it is not known
+    pub fn operand_span(&self, position: usize) -> Span {
+        self.operand_spans.get(position).copied().unwrap_or(self.span)
+    }
+
+    // Encodes the instruction into the 4 byte layout the VM decodes. The operands must
+    // already have been checked against the opcode's operand kinds
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![u8::from(self.opcode)];
+        let kinds = self.opcode.operands().unwrap_or(&[]);
+        for (kind, operand) in kinds.iter().zip(&self.operands) {
+            match (kind, operand) {
+                (_, Operand::Register(register)) | (_, Operand::FloatRegister(register)) => bytes.push(*register),
+                (OperandKind::Integer8 | OperandKind::SignedInteger8, Operand::Integer(value)) => bytes.push(*value as u8),
+                (_, Operand::Integer(value)) => {
+                    bytes.push((*value >> 8) as u8);
+                    bytes.push(*value as u8);
+                },
+                (_, Operand::Label(name)) => panic!("Label @{} must be resolved before encoding", name),
+                (_, Operand::Expression(expression)) => panic!("Expression {} must be evaluated before encoding", expression),
+            }
+        }
+        bytes.resize(INSTRUCTION_SIZE, 0);
+        bytes
+    }
+}
@@ -1,67 +1,938 @@
-/* Examples:
+pub mod instruction_parsers;
+pub mod directives;
+pub mod expression;
+pub mod preprocessor;
+pub mod symbols;
 
- 1) LOAD $1 #10    => 01 01 00 0A
+use std::fmt;
+use std::path::Path;
+use crate::diagnostic::{Diagnose, Span, SourceMap};
+use crate::instructions::{Opcode, OperandKind};
+use crate::lexer::Lexer;
+use crate::lexer::token::LexerError;
+use crate::parser::ast::Statement;
+use crate::parser::parser::{Parser, ParserError};
+use crate::object::{ObjectFile, Symbol, Section, LineEntry};
+use crate::vm::REGISTER_COUNT;
+use instruction_parsers::{AssemblerInstruction, Operand, INSTRUCTION_SIZE};
+use directives::{Argument, Directive, DirectiveKind};
+use expression::{Expression, ExpressionKind};
+use preprocessor::{Preprocessor, PreprocessorError};
+use symbols::SymbolTable;
 
+/* Turns Teflon assembly into the bytecode the VM runs. Assembling happens in two passes:
 
+ 1) The source is lexed, its macros and includes are expanded and it is parsed into a program
+    whose instructions have the operands their opcodes expect. Every label declaration is added
+    to the symbol table with the address of the instruction or data that follows it, registers
+    and integers are checked against what their operands can hold, and data directives are laid
+    out in the read-only data section.
+ 2) Label usages are replaced with their addresses (or offsets for relative jumps), expressions
+    with their values and every instruction is encoded into its 4 byte form.
+
+ `.equ` constants and the expressions in data directives are evaluated in the first pass, so
+ they can only use symbols declared above them.
+
+ EX: LOAD $1 #1000 => 01 01 03 E8
 */
+pub struct Assembler {
+    instructions: Vec<AssemblerInstruction>,
+    pub symbols: SymbolTable,
+    errors: Vec<AssemblerError>,
+    rodata: Vec<u8>,            // the read-only data laid out by `.data` directives
+    section: Section,           // the section the next statement goes in
+    base: usize,                // where the code of the last assemble starts in the program
+    source_name: String,        // the name diagnostics give the source
+    pub sources: SourceMap,     // the source of the last assemble, which error spans point into
+}
 
+// What assembling a source adds to, so the REPL can undo a line
+#[derive(Debug, PartialEq, Clone)]
+pub struct Checkpoint {
+    symbols: SymbolTable,
+    rodata_len: usize,
+    section: Section,
+}
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssemblerError {
+    Lexer(LexerError),
+    Preprocessor(PreprocessorError),
+    Parser(ParserError),
+    NoEncoding { span: Span, opcode: Opcode },
+    InvalidRegister { span: Span, register: u8 },
+    InvalidFloatRegister { span: Span, register: u8 },
+    OperandOutOfRange { span: Span, value: i64, min: i64, max: i64 },
+    DuplicateLabel { span: Span, name: String },
+    UnknownLabel { span: Span, name: String },
+    WrongSection { span: Span, section: Section },     // the section the statement has to be in
+    InvalidDirective { span: Span, directive: DirectiveKind, expected: &'static str },
+    DataLabel { span: Span, name: String },            // a jump to a label in the data section
+    UnknownSymbol { span: Span, name: String },        // a name in an expression that is not a constant or label
+    InvalidExpression { span: Span, reason: String },
+}
 
-/* A Program is composed of:
- -  a list of instructions 
-*/
-// struct Program {
-//     instructions: Vec<Instruction>,
-// }
-
-
-/* A Instruction is composed of:
- - An Opcode 
- - A Register
- - A Integer Operand
- - A Newline
-*/ 
-// struct Instruction {
-//     opcode: Opcode,
-//     register: Register,
-//     operand: IntegerOperand,
-//     newline: char,
-// }
-
-/* A Register is composed of
- - The symbol $
- - A Number
- - A Space
-*/
-// struct Register {
-//     number: Number,
-// }
+impl Diagnose for AssemblerError {
+    fn span(&self) -> Span {
+        match self {
+            AssemblerError::Lexer(e) => e.span(),
+            AssemblerError::Preprocessor(e) => e.span(),
+            AssemblerError::Parser(e) => e.span(),
+            AssemblerError::NoEncoding { span, .. }
+            | AssemblerError::InvalidRegister { span, .. }
+            | AssemblerError::InvalidFloatRegister { span, .. }
+            | AssemblerError::OperandOutOfRange { span, .. }
+            | AssemblerError::DuplicateLabel { span, .. }
+            | AssemblerError::UnknownLabel { span, .. }
+            | AssemblerError::WrongSection { span, .. }
+            | AssemblerError::InvalidDirective { span, .. }
+            | AssemblerError::DataLabel { span, .. }
+            | AssemblerError::UnknownSymbol { span, .. }
+            | AssemblerError::InvalidExpression { span, .. } => *span,
+        }
+    }
 
-/* A IntegerOperand is composed og:
- - The symbol #
- - A Number
-*/
-// struct IntegerOperand {
-//     number: Number,
-// }
+    fn message(&self) -> String {
+        match self {
+            AssemblerError::Lexer(e) => e.message(),
+            AssemblerError::Preprocessor(e) => e.message(),
+            AssemblerError::Parser(e) => e.message(),
+            AssemblerError::NoEncoding { opcode, .. } => format!("{} can not be assembled", opcode.mnemonic()),
+            AssemblerError::InvalidRegister { register, .. } => format!("Register ${} does not exist", register),
+            AssemblerError::InvalidFloatRegister { register, .. } => format!("Float register $f{} does not exist", register),
+            AssemblerError::OperandOutOfRange { value, min, max, .. } if value < min =>
+                format!("Operand {} is below the smallest value {} of the {}", value, min, describe_range(*min, *max)),
+            AssemblerError::OperandOutOfRange { value, min, max, .. } =>
+                format!("Operand {} is above the largest value {} of the {}", value, max, describe_range(*min, *max)),
+            AssemblerError::DuplicateLabel { name, .. } => format!("Label `{}` was already declared", name),
+            AssemblerError::UnknownLabel { name, .. } => format!("Label `@{}` is never declared", name),
+            AssemblerError::WrongSection { section: Section::Code, .. } => String::from("Only allowed in the .code section"),
+            AssemblerError::WrongSection { section: Section::ReadOnlyData, .. } => String::from("Only allowed in the .data section"),
+            AssemblerError::InvalidDirective { directive, expected, .. } => format!("{} expects {}", directive, expected),
+            AssemblerError::DataLabel { name, .. } => format!("Label `@{}` is in the .data section and can not be jumped to", name),
+            AssemblerError::UnknownSymbol { name, .. } => format!("`{}` is not a constant or label declared before it is used", name),
+            AssemblerError::InvalidExpression { reason, .. } => format!("Expression {}", reason),
+        }
+    }
+}
 
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {}", self.message(), self.span())
+    }
+}
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-/* A number is composed of:
- - the symbols 0-9
-    <digit> -> 0 | 1 | 2 | 3 | 4 | 5 | 6 | 7 | 8 | 9 
-    <number> -> <digit> | <digit> <number>
-*/
-// struct Number {
-//     number: u8,
-// }
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler {
+            instructions: vec![],
+            symbols: SymbolTable::new(),
+            errors: vec![],
+            rodata: vec![],
+            section: Section::Code,
+            base: 0,
+            source_name: String::from("<input>"),
+            sources: SourceMap::new(),
+        }
+    }
 
+    // Names the source in diagnostics, usually after the file it was read from. Included
+    // files are found relative to it
+    pub fn set_source_name(&mut self, name: &str) {
+        self.source_name = name.to_string();
+    }
 
-/* An opcode is composed of:
- - one or more letters in a row
- - A space
-*/
-// struct Opcode {
-//     letters: String
-// }
+    // Renders an error from the last assemble with the source line it points at
+    pub fn render(&self, error: &AssemblerError) -> String {
+        error.diagnostic().render(&self.sources)
+    }
+
+    // Assembles a complete source string into VM bytecode, or every error that was found
+    pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        self.symbols = SymbolTable::new();
+        self.rodata.clear();
+        self.section = Section::Code;
+        self.assemble_at(source, 0)
+    }
+
+    // Assembles code that goes `base` bytes into a program which earlier calls assembled the
+    // start of, so it can use their labels, constants and data and carries on in the section they
+    // ended in. The REPL assembles a line at a time this way. Nothing is kept from a source that
+    // does not assemble
+    pub fn assemble_at(&mut self, source: &str, base: usize) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let checkpoint = self.checkpoint();
+        self.instructions.clear();
+        self.errors.clear();
+        self.base = base;
+        self.sources = SourceMap::new();
+        let file = self.sources.add(&self.source_name, source);
+
+        self.first_pass(file, source);
+        let program = if self.errors.is_empty() { self.second_pass() } else { vec![] };
+        if !self.errors.is_empty() {
+            self.rewind(checkpoint);
+            return Err(std::mem::take(&mut self.errors));
+        }
+        Ok(program)
+    }
+
+    // The symbols, read-only data and section as they are now, for `rewind` to go back to
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint { symbols: self.symbols.clone(), rodata_len: self.rodata.len(), section: self.section }
+    }
+
+    // Forgets everything assembled since the checkpoint was taken
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.symbols = checkpoint.symbols;
+        self.rodata.truncate(checkpoint.rodata_len);
+        self.section = checkpoint.section;
+    }
+
+    // The read-only data laid out so far
+    pub fn rodata(&self) -> &[u8] {
+        &self.rodata
+    }
+
+    // The labels declared so far, as the symbols of a program file
+    pub fn object_symbols(&self) -> Vec<Symbol> {
+        self.symbols.iter().into_iter()
+            .map(|(name, section, address)| Symbol { name: name.to_string(), section, address: address as u32 })
+            .collect()
+    }
+
+    // Assembles a source string into a program file with read-only data, symbol and debug
+    // sections. Execution starts at the `main` label when one is declared in the code section,
+    // otherwise at the first instruction
+    pub fn assemble_object(&mut self, source: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        let code = self.assemble(source)?;
+        let symbols = self.object_symbols();
+        let entry_point = match self.symbols.symbol_section("main") {
+            Some(Section::Code) => self.symbols.symbol_value("main").unwrap_or(0),
+            _ => 0,
+        };
+        let debug = self.instructions.iter().enumerate()
+            .map(|(index, instruction)| LineEntry {
+                address: (index * INSTRUCTION_SIZE) as u32,
+                // Instructions from a macro belong to the line the macro is used on
+                line: self.sources.origin(instruction.span).line as u32,
+            })
+            .collect();
+
+        Ok(ObjectFile {
+            entry_point: entry_point as u32,
+            code,
+            rodata: self.rodata.clone(),
+            symbols: Some(symbols),
+            debug: Some(debug),
+        })
+    }
+
+    // Lexes, parses and validates the source and builds the symbol table
+    fn first_pass(&mut self, file: usize, source: &str) {
+        let mut lexer = Lexer::for_file(file);
+        lexer.lex_source(source);
+        if !lexer.errors.is_empty() {
+            self.errors.extend(lexer.errors.into_iter().map(AssemblerError::Lexer));
+            return;
+        }
+
+        let tokens = match Preprocessor::new(&mut self.sources).process(lexer.tokens, file, Path::new(&self.source_name)) {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                self.errors.extend(errors);
+                return;
+            },
+        };
+
+        let program = match Parser::new(tokens).parse() {
+            Ok(program) => program,
+            Err(errors) => {
+                self.errors.extend(errors.into_iter().map(AssemblerError::Parser));
+                return;
+            },
+        };
+
+        // Statements go in the code section until a `.data` directive. A source assembled after
+        // another one carries on in the section that one ended in
+        for statement in program.statements {
+            match statement {
+                Statement::Label(name, span) => {
+                    let address = match self.section {
+                        Section::Code => self.base + self.instructions.len() * INSTRUCTION_SIZE,
+                        Section::ReadOnlyData => self.rodata.len(),
+                    };
+                    if !self.symbols.add_symbol(&name, self.section, address) {
+                        self.errors.push(AssemblerError::DuplicateLabel { span, name });
+                    }
+                },
+                Statement::Instruction(instruction) => {
+                    if self.section != Section::Code {
+                        self.errors.push(AssemblerError::WrongSection { span: instruction.span, section: Section::Code });
+                        continue;
+                    }
+                    if let Err(e) = validate(&instruction) {
+                        self.errors.push(e);
+                    }
+                    self.instructions.push(instruction);
+                },
+                Statement::Directive(directive) => match directive.kind {
+                    DirectiveKind::Equ => {
+                        if let Err(e) = self.define_constant(&directive) {
+                            self.errors.push(e);
+                        }
+                    },
+                    DirectiveKind::Data | DirectiveKind::Code => {
+                        if !directive.arguments.is_empty() {
+                            self.errors.push(invalid_directive(&directive, "no arguments"));
+                        }
+                        self.section = if directive.kind == DirectiveKind::Data { Section::ReadOnlyData } else { Section::Code };
+                    },
+                    _ if self.section != Section::ReadOnlyData => {
+                        self.errors.push(AssemblerError::WrongSection { span: directive.span, section: Section::ReadOnlyData });
+                    },
+                    _ => match self.evaluate_arguments(directive).and_then(|directive| layout(&directive, self.rodata.len())) {
+                        Ok(bytes) => self.rodata.extend(bytes),
+                        Err(e) => self.errors.push(e),
+                    },
+                },
+            }
+        }
+    }
+
+    // `.equ NAME, value` adds a constant with the value of an expression
+    fn define_constant(&mut self, directive: &Directive) -> Result<(), AssemblerError> {
+        let (name, value) = match directive.arguments.as_slice() {
+            [Argument::Expression(Expression { kind: ExpressionKind::Symbol(name), .. }), value] => (name, value),
+            _ => return Err(invalid_directive(directive, "a name and a value")),
+        };
+        let value = match value {
+            Argument::Integer(value) => *value,
+            Argument::Expression(expression) => expression.evaluate(&|name| self.symbols.value(name))?,
+            Argument::String(_) | Argument::Float(_) => return Err(invalid_directive(directive, "a name and a value")),
+        };
+        if !self.symbols.add_constant(name, value) {
+            return Err(AssemblerError::DuplicateLabel { span: directive.argument_span(0), name: name.clone() });
+        }
+        Ok(())
+    }
+
+    // Replaces the expressions in a data directive with their values
+    fn evaluate_arguments(&self, mut directive: Directive) -> Result<Directive, AssemblerError> {
+        for argument in directive.arguments.iter_mut() {
+            if let Argument::Expression(expression) = argument {
+                *argument = Argument::Integer(expression.evaluate(&|name| self.symbols.value(name))?);
+            }
+        }
+        Ok(directive)
+    }
+
+    // Resolves label usages and encodes every instruction
+    fn second_pass(&mut self) -> Vec<u8> {
+        let mut program = Vec::with_capacity(self.instructions.len() * INSTRUCTION_SIZE);
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            let address = self.base + index * INSTRUCTION_SIZE;
+            match resolve(instruction, address, &self.symbols) {
+                Ok(resolved) => program.extend(resolved.to_bytes()),
+                Err(e) => self.errors.push(e),
+            }
+        }
+        program
+    }
+}
+
+// Checks that the registers of an instruction exist and that its integers fit. The parser has
+// already checked the kind of every operand
+fn validate(instruction: &AssemblerInstruction) -> Result<(), AssemblerError> {
+    let opcode = select_form(instruction.opcode, &instruction.operands);
+    let kinds = match opcode.operands() {
+        Some(kinds) => kinds,
+        None => return Err(AssemblerError::NoEncoding { span: instruction.span, opcode }),
+    };
+    for (position, (kind, operand)) in kinds.iter().zip(&instruction.operands).enumerate() {
+        let span = instruction.operand_span(position);
+        match (kind, operand) {
+            (_, Operand::Register(register)) if *register as usize >= REGISTER_COUNT => {
+                return Err(AssemblerError::InvalidRegister { span, register: *register });
+            },
+            (_, Operand::FloatRegister(register)) if *register as usize >= REGISTER_COUNT => {
+                return Err(AssemblerError::InvalidFloatRegister { span, register: *register });
+            },
+            (_, Operand::Integer(value)) => check_range(span, *kind, *value)?,
+            // Labels and expressions are range checked once their value is known
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Replaces every label usage with the label's address and every expression with its value.
+// Relative jumps to a label get the distance from the instruction after the jump instead. The
+// values are range checked once the form of the instruction is picked from them
+fn resolve(instruction: &AssemblerInstruction, address: usize, symbols: &SymbolTable) -> Result<AssemblerInstruction, AssemblerError> {
+    let mut resolved = instruction.clone();
+    let kinds = instruction.opcode.operands().unwrap_or(&[]);
+    for (position, (kind, operand)) in kinds.iter().zip(resolved.operands.iter_mut()).enumerate() {
+        if let Operand::Expression(expression) = operand {
+            *operand = Operand::Integer(expression.evaluate(&|name| symbols.value(name))?);
+        }
+        if let Operand::Label(name) = operand {
+            let span = instruction.operand_span(position);
+            let target = match symbols.symbol_value(name) {
+                Some(target) => target as i64,
+                None => return Err(AssemblerError::UnknownLabel { span, name: name.clone() }),
+            };
+            let next = (address + INSTRUCTION_SIZE) as i64;
+            if matches!(kind, OperandKind::Address | OperandKind::Offset) && symbols.symbol_section(name) != Some(Section::Code) {
+                return Err(AssemblerError::DataLabel { span, name: name.clone() });
+            }
+            *operand = Operand::Integer(match instruction.opcode {
+                Opcode::JMPFI => target - next,
+                Opcode::JMPBI => next - target,
+                _ => target,
+            });
+        }
+    }
+
+    resolved.opcode = select_form(instruction.opcode, &resolved.operands);
+    let kinds = resolved.opcode.operands().unwrap_or(&[]);
+    for (position, (kind, operand)) in kinds.iter().zip(&resolved.operands).enumerate() {
+        if let Operand::Integer(value) = operand {
+            check_range(instruction.operand_span(position), *kind, *value)?;
+        }
+    }
+    Ok(resolved)
+}
+
+// The form of an instruction that depends on the value of its immediate, which for a constant
+// or expression is only known once it is evaluated. LOAD only takes unsigned numbers, so
+// loading a negative number assembles to LOADS
+fn select_form(opcode: Opcode, operands: &[Operand]) -> Opcode {
+    match (opcode, operands) {
+        (Opcode::LOAD, [_, Operand::Integer(value)]) if *value < 0 => Opcode::LOADS,
+        (opcode, _) => opcode,
+    }
+}
+
+// The bytes a data directive adds to the read-only data, which is `offset` bytes long so far
+fn layout(directive: &Directive, offset: usize) -> Result<Vec<u8>, AssemblerError> {
+    let integers: Option<Vec<i64>> = directive.arguments.iter()
+        .map(|argument| match argument {
+            Argument::Integer(value) => Some(*value),
+            Argument::String(_) | Argument::Float(_) | Argument::Expression(_) => None,
+        })
+        .collect();
+    let strings: Option<Vec<&str>> = directive.arguments.iter()
+        .map(|argument| match argument {
+            Argument::String(text) => Some(text.as_str()),
+            Argument::Integer(_) | Argument::Float(_) | Argument::Expression(_) => None,
+        })
+        .collect();
+    let floats: Option<Vec<f64>> = directive.arguments.iter()
+        .map(|argument| match argument {
+            Argument::Float(value) => Some(*value),
+            Argument::Integer(value) => Some(*value as f64),
+            Argument::String(_) | Argument::Expression(_) => None,
+        })
+        .collect();
+
+    let mut bytes = vec![];
+    match (directive.kind, integers.as_deref(), strings.as_deref()) {
+        (DirectiveKind::Asciiz, _, Some(strings)) if !strings.is_empty() => {
+            for text in strings {
+                bytes.extend(text.as_bytes());
+                bytes.push(0);
+            }
+        },
+        (DirectiveKind::Asciiz, _, _) => return Err(invalid_directive(directive, "one or more strings")),
+        (DirectiveKind::Word, Some(values), _) | (DirectiveKind::Byte, Some(values), _) if !values.is_empty() => {
+            for (position, value) in values.iter().enumerate() {
+                let span = directive.argument_span(position);
+                if directive.kind == DirectiveKind::Byte {
+                    check_bounds(span, *value, i8::MIN as i64, u8::MAX as i64)?;
+                    bytes.push(*value as u8);
+                } else {
+                    check_bounds(span, *value, i32::MIN as i64, u32::MAX as i64)?;
+                    bytes.extend((*value as u32).to_be_bytes());
+                }
+            }
+        },
+        (DirectiveKind::Word, _, _) | (DirectiveKind::Byte, _, _) => return Err(invalid_directive(directive, "one or more integers")),
+        (DirectiveKind::Double, _, _) => match floats.as_deref() {
+            Some(values) if !values.is_empty() => values.iter().for_each(|value| bytes.extend(value.to_be_bytes())),
+            _ => return Err(invalid_directive(directive, "one or more numbers")),
+        },
+        (DirectiveKind::Space, Some([count]), _) => {
+            check_bounds(directive.argument_span(0), *count, 0, u16::MAX as i64)?;
+            bytes.resize(*count as usize, 0);
+        },
+        (DirectiveKind::Space, _, _) => return Err(invalid_directive(directive, "a byte count")),
+        (DirectiveKind::Align, Some([alignment]), _) if (1..=4096).contains(alignment) && (*alignment as u64).is_power_of_two() => {
+            let alignment = *alignment as usize;
+            bytes.resize((alignment - offset % alignment) % alignment, 0);
+        },
+        (DirectiveKind::Align, _, _) => return Err(invalid_directive(directive, "a power of two up to 4096")),
+        (DirectiveKind::Data, _, _) | (DirectiveKind::Code, _, _) | (DirectiveKind::Equ, _, _) => (),
+    }
+    Ok(bytes)
+}
+
+fn invalid_directive(directive: &Directive, expected: &'static str) -> AssemblerError {
+    AssemblerError::InvalidDirective { span: directive.span, directive: directive.kind, expected }
+}
+
+// Checks that a value fits in the bytes its operand is encoded in
+fn check_range(span: Span, kind: OperandKind, value: i64) -> Result<(), AssemblerError> {
+    let (min, max) = kind.range();
+    check_bounds(span, value, min, max)
+}
+
+// Names an operand by its width, e.g. "8 bit signed operand" for -128 to 127
+fn describe_range(min: i64, max: i64) -> String {
+    let bits = |value: i64| 64 - (value as u64).leading_zeros();
+    let width = if min < 0 { bits(max).max(bits(-min - 1) + 1) } else { bits(max) };
+    let signedness = match (min, max) {
+        (0, _) => "unsigned ",
+        (min, max) if max == -min - 1 => "signed ",
+        _ => "",
+    };
+    format!("{} bit {}operand", width, signedness)
+}
+
+fn check_bounds(span: Span, value: i64, min: i64, max: i64) -> Result<(), AssemblerError> {
+    if value < min || value > max {
+        return Err(AssemblerError::OperandOutOfRange { span, value, min, max });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::{VM, ExitReason};
+
+    fn assemble(source: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        Assembler::new().assemble(source)
+    }
+
+    fn span(line: usize, start: usize, end: usize) -> Span {
+        Span::new(0, line, start, end)
+    }
+
+    #[test]
+    fn test_assemble_load() {
+        assert_eq!(assemble("LOAD $1 #1000"), Ok(vec![0x01, 0x01, 0x03, 0xE8]));
+    }
+
+    #[test]
+    fn test_assemble_every_layout() {
+        let program = assemble("HLT\nADD $1 $2 $3\nJMP $4\nJEQ $5 $6").unwrap();
+        assert_eq!(program, vec![
+            0, 0, 0, 0,
+            2, 1, 2, 3,
+            6, 4, 0, 0,
+            14, 5, 6, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_round_trip_with_vm() {
+        let program = assemble("LOAD $1 #15\nLOAD $2 #5\nSUB $1 $2 $3\nMUL $3 $2 $4\nHLT").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.set_program(program);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[3], 10);
+        assert_eq!(test_vm.registers[4], 50);
+    }
+
+    #[test]
+    fn test_assemble_reports_every_invalid_instruction() {
+        let errors = assemble("LOAD $1 #70000\nJMP $40\nLOADB $1 $2 #-1").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::OperandOutOfRange { span: span(1, 8, 14), value: 70000, min: 0, max: 65535 },
+            AssemblerError::InvalidRegister { span: span(2, 4, 7), register: 40 },
+            AssemblerError::OperandOutOfRange { span: span(3, 12, 15), value: -1, min: 0, max: 255 },
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_reports_every_parser_error() {
+        let errors = assemble("LOAD $1 #1\nADD $1 $2 #3\nJEQ #4 $1\nHLT").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::Parser(ParserError::WrongOperandKind { span: span(2, 10, 12), opcode: Opcode::ADD, position: 2 }),
+            AssemblerError::Parser(ParserError::WrongOperandKind { span: span(3, 4, 6), opcode: Opcode::JEQ, position: 0 }),
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_wrong_operand_count() {
+        let errors = assemble("ADD $1 $2").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::Parser(
+            ParserError::WrongOperandCount { span: span(1, 0, 9), opcode: Opcode::ADD, expected: 3, found: 2 },
+        )]);
+    }
+
+    #[test]
+    fn test_assemble_invalid_register() {
+        assert_eq!(assemble("JMP $32"), Err(vec![AssemblerError::InvalidRegister { span: span(1, 4, 7), register: 32 }]));
+    }
+
+    #[test]
+    fn test_assemble_neq() {
+        assert_eq!(assemble("NEQ $1 $2 $3"), Ok(vec![32, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_assemble_lexer_error() {
+        assert!(matches!(assemble("LOAD $1 #1%").unwrap_err()[0], AssemblerError::Lexer(_)));
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let mut assembler = Assembler::new();
+        let program = assembler.assemble("start: LOAD $1 #1\nloop:\nJEQ $1 @start\nJMP @loop").unwrap();
+        assert_eq!(assembler.symbols.symbol_value("start"), Some(0));
+        assert_eq!(assembler.symbols.symbol_value("loop"), Some(4));
+        assert_eq!(program, vec![
+            1, 1, 0, 1,
+            19, 1, 0, 0,
+            16, 0, 4, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_relative_jumps_to_labels() {
+        let program = assemble("back: JMPF @forward\nHLT\nforward: JMPB @back").unwrap();
+        assert_eq!(program, vec![
+            17, 0, 4, 0,
+            0, 0, 0, 0,
+            18, 0, 12, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_backwards_jmpf_is_out_of_range() {
+        let errors = assemble("back: HLT\nJMPF @back").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::OperandOutOfRange { span: span(2, 5, 10), value: -8, min: 0, max: 65535 }]);
+    }
+
+    #[test]
+    fn test_assemble_label_errors() {
+        let errors = assemble("a: HLT\na: HLT\nJMP @b").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::DuplicateLabel { span: span(2, 0, 2), name: String::from("a") }]);
+        let errors = assemble("JMP @b").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::UnknownLabel { span: span(1, 4, 6), name: String::from("b") }]);
+    }
+
+    #[test]
+    fn test_assemble_loop_runs_in_vm() {
+        // Counts register 1 up to 5
+        let source = "LOAD $2 #1
+LOAD $3 #5
+loop: ADD $1 $2 $1
+EQ $1 $3 $4
+JNEQ $4 @loop
+HLT";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 5);
+    }
+
+    #[test]
+    fn test_assemble_subroutine() {
+        let source = "LOAD $1 #6
+CALL @double
+HLT
+double: PUSH $2
+ADD $1 $1 $1
+POP $2
+RET";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(&test_vm.program()[4..8], &[23, 0, 12, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 12);
+    }
+
+    #[test]
+    fn test_assemble_heap_instructions() {
+        let source = "LOAD $1 #8
+ALOC $1
+LOAD $2 #513
+STOREH $2 $3 #2
+LOADM $4 $3 #1
+LOADB $5 $3 #3";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(&test_vm.program()[12..16], &[30, 2, 3, 2]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.heap, vec![0, 0, 2, 1, 0, 0, 0, 0]);
+        assert_eq!(test_vm.registers[4], 0x00020100);
+        assert_eq!(test_vm.registers[5], 1);
+    }
+
+    #[test]
+    fn test_assemble_heap_offset_out_of_range() {
+        let errors = assemble("LOADB $1 $2 #256").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::OperandOutOfRange { span: span(1, 12, 16), value: 256, min: 0, max: 255 }]);
+    }
+
+    #[test]
+    fn test_assemble_object() {
+        let object = Assembler::new().assemble_object("double: ADD $1 $1 $1\nRET\nmain: LOAD $1 #4\nCALL @double\nHLT").unwrap();
+        assert_eq!(object.entry_point, 8);
+        assert_eq!(object.symbol_at(Section::Code, 0), Some("double"));
+        assert_eq!(object.debug.as_ref().unwrap()[3], LineEntry { address: 12, line: 4 });
+
+        let mut test_vm = VM::new();
+        test_vm.load(&object.to_bytes()).unwrap();
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 8);
+    }
+
+    #[test]
+    fn test_assemble_at() {
+        let mut assembler = Assembler::new();
+        assert_eq!(assembler.assemble_at("LOAD $1 #3", 0), Ok(vec![1, 1, 0, 3]));
+        assert_eq!(assembler.assemble_at("loop: DEC $1", 4), Ok(vec![66, 1, 0, 0]));
+        assert_eq!(assembler.assemble_at("JNZ @loop", 8), Ok(vec![44, 0, 4, 0]));
+        // A line that fails leaves no labels behind
+        assert!(assembler.assemble_at("end: JMP @nowhere", 12).is_err());
+        assert!(assembler.assemble_at("end: HLT", 12).is_ok());
+        assert_eq!(assembler.object_symbols().iter().map(|symbol| (symbol.name.as_str(), symbol.address)).collect::<Vec<_>>(), vec![("loop", 4), ("end", 12)]);
+    }
+
+    #[test]
+    fn test_render_error() {
+        let mut assembler = Assembler::new();
+        assembler.set_source_name("loop.tf");
+        let errors = assembler.assemble("LOAD $1 #10\nJMP @nowhere").unwrap_err();
+        assert_eq!(assembler.render(&errors[0]), "\
+error: Label `@nowhere` is never declared
+ --> loop.tf:2:5
+  |
+2 | JMP @nowhere
+  |     ^^^^^^^^
+");
+        assert_eq!(errors[0].to_string(), "Label `@nowhere` is never declared on line 2, column 5");
+    }
+
+    #[test]
+    fn test_assemble_literals() {
+        let program = assemble("LOAD $0 #-5\nLOAD $1 #0xFFFF\nLOAD $2 #'A'\nLOADB $3 $0 #0b11").unwrap();
+        // LOAD is unsigned, so a negative number is loaded with LOADS
+        assert_eq!(program[..8], [0x45, 0x00, 0xFF, 0xFB, 0x01, 0x01, 0xFF, 0xFF]);
+        let mut test_vm = VM::new();
+        test_vm.set_program(program[..12].to_vec());
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[..3], [-5, 65535, 65]);
+    }
+
+    #[test]
+    fn test_assemble_negative_constant() {
+        // The form is picked from the value, so a negated constant loads like a literal
+        let program = assemble(".equ N, 5\nLOAD $1 #-N\nLOAD $2 #N - 6").unwrap();
+        assert_eq!(program, vec![0x45, 0x01, 0xFF, 0xFB, 0x45, 0x02, 0xFF, 0xFF]);
+        let errors = assemble(".equ N, 40000\nLOAD $1 #-N\nLOAD $2 #N * 2").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::OperandOutOfRange { span: span(2, 8, 11), value: -40000, min: -32768, max: 32767 },
+            AssemblerError::OperandOutOfRange { span: span(3, 8, 14), value: 80000, min: 0, max: 65535 },
+        ]);
+        assert_eq!(errors[0].message(), "Operand -40000 is below the smallest value -32768 of the 16 bit signed operand");
+        assert_eq!(errors[1].message(), "Operand 80000 is above the largest value 65535 of the 16 bit unsigned operand");
+    }
+
+    #[test]
+    fn test_assemble_literal_out_of_range() {
+        let errors = assemble("LOAD $1 #70000\nLOADB $1 $2 #-1\nLOAD $3 #-32769\nLOADS $4 #40000").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::OperandOutOfRange { span: span(1, 8, 14), value: 70000, min: 0, max: 65535 },
+            AssemblerError::OperandOutOfRange { span: span(2, 12, 15), value: -1, min: 0, max: 255 },
+            AssemblerError::OperandOutOfRange { span: span(3, 8, 15), value: -32769, min: -32768, max: 32767 },
+            AssemblerError::OperandOutOfRange { span: span(4, 9, 15), value: 40000, min: -32768, max: 32767 },
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_data_section() {
+        let source = ".data\nmsg: .asciiz \"hi\"\ncount: .byte 3, 'a'\n.align 4\nbig: .word -2\n.space 2\n.code\nmain: LOAD $0 @big\nHLT";
+        let mut assembler = Assembler::new();
+        let object = assembler.assemble_object(source).unwrap();
+        assert_eq!(object.rodata, vec![b'h', b'i', 0, 3, b'a', 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFE, 0, 0]);
+        assert_eq!(object.code, vec![0x01, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(object.entry_point, 0);
+        assert_eq!(object.symbol_at(Section::ReadOnlyData, 3), Some("count"));
+        assert_eq!(assembler.symbols.symbol_section("main"), Some(Section::Code));
+    }
+
+    #[test]
+    fn test_print_string_from_data_section() {
+        let source = "LOAD $0 @msg\nLOAD $1 #2\nSYSCALL $1\nHLT\n.data\nmsg: .asciiz \"Hello\\n\"";
+        let object = Assembler::new().assemble_object(source).unwrap();
+        let handler = crate::syscall::CaptureHandler::new();
+        let mut test_vm = VM::new();
+        test_vm.set_syscall_handler(Box::new(handler.clone()));
+        test_vm.load_object(object);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(handler.output(), "Hello\n");
+    }
+
+    #[test]
+    fn test_assemble_directive_errors() {
+        let errors = assemble(".asciiz \"a\"\n.data\nHLT\n.word\n.byte 300\n.align 3\n.space \"x\"").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::WrongSection { span: span(1, 0, 11), section: Section::ReadOnlyData },
+            AssemblerError::WrongSection { span: span(3, 0, 3), section: Section::Code },
+            AssemblerError::InvalidDirective { span: span(4, 0, 5), directive: DirectiveKind::Word, expected: "one or more integers" },
+            AssemblerError::OperandOutOfRange { span: span(5, 6, 9), value: 300, min: -128, max: 255 },
+            AssemblerError::InvalidDirective { span: span(6, 0, 8), directive: DirectiveKind::Align, expected: "a power of two up to 4096" },
+            AssemblerError::InvalidDirective { span: span(7, 0, 10), directive: DirectiveKind::Space, expected: "a byte count" },
+        ]);
+    }
+
+    #[test]
+    fn test_jump_to_data_label() {
+        let errors = assemble("JMP @msg\n.data\nmsg: .byte 1").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::DataLabel { span: span(1, 4, 8), name: String::from("msg") }]);
+    }
+
+    #[test]
+    fn test_assemble_macros() {
+        let source = ".macro count_down reg\nloop: SUB reg $1 reg\nNEQ reg $0 $5\nJEQ $5 @loop\n.endm\nLOAD $1 #1\nLOAD $2 #3\nLOAD $3 #2\ncount_down $2\ncount_down $3\nHLT";
+        let mut assembler = Assembler::new();
+        let object = assembler.assemble_object(source).unwrap();
+        // Both expansions stand for the line the macro is used on
+        let lines: Vec<u32> = object.debug.as_ref().unwrap().iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, vec![6, 7, 8, 9, 9, 9, 10, 10, 10, 11]);
+        let mut test_vm = VM::new();
+        test_vm.load_object(object);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1..4], [1, 0, 0]);
+    }
+
+    #[test]
+    fn test_render_error_in_macro() {
+        let mut assembler = Assembler::new();
+        let errors = assembler.assemble(".macro clear reg\nLOAD reg #0\n.endm\nclear $40").unwrap_err();
+        assert_eq!(assembler.render(&errors[0]), "\
+error: Register $40 does not exist
+ --> <input>:2:6
+  |
+2 | LOAD reg #0
+  |      ^^^
+note: in expansion of macro `clear`
+ --> <input>:4:1
+  |
+4 | clear $40
+  | ^^^^^^^^^
+");
+    }
+
+    #[test]
+    fn test_assemble_constant_expressions() {
+        let source = ".equ SIZE, 64\n.equ MASK SIZE - 1\nstart: LOAD $1 #SIZE*4+1\nLOAD $2, #(end - start) / 4\nLOAD $3 #hi(0x1234) | lo(~MASK)\nend: HLT";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1..4], [257, 3, 0x12 | 0xC0]);
+    }
+
+    #[test]
+    fn test_assemble_expressions_in_data() {
+        let source = ".equ COUNT, 3\n.data\ntable: .byte COUNT, COUNT * 2\n.word 'a' + 1\n.code\nLOAD $0 #table + 1\nJMP #done\ndone: HLT";
+        let object = Assembler::new().assemble_object(source).unwrap();
+        assert_eq!(object.rodata, vec![3, 6, 0, 0, 0, 98]);
+        assert_eq!(object.code[..8], [0x01, 0x00, 0x00, 0x01, 16, 0x00, 0x08, 0x00]);
+    }
+
+    #[test]
+    fn test_assemble_expression_errors() {
+        let errors = assemble(".equ BIG, 0x4000\nLOAD $1 #BIG * 2\nLOAD $2 #1 / (BIG - BIG)\nLOAD $3 #later\n.equ BIG, 1").unwrap_err();
+        assert_eq!(errors, vec![AssemblerError::DuplicateLabel { span: span(5, 5, 8), name: String::from("BIG") }]);
+        let errors = assemble(".equ BIG, 0x8000\nLOAD $1 #BIG * 2\nLOAD $2 #1 / (BIG - BIG)\nLOAD $3 #later").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::OperandOutOfRange { span: span(2, 8, 16), value: 0x10000, min: 0, max: 65535 },
+            AssemblerError::InvalidExpression { span: span(3, 9, 24), reason: String::from("divides by zero") },
+            AssemblerError::UnknownSymbol { span: span(4, 9, 14), name: String::from("later") },
+        ]);
+        let errors = assemble(".equ X\n.equ 1, 2\n.data\n.byte later\nlater: .byte 1").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::InvalidDirective { span: span(1, 0, 6), directive: DirectiveKind::Equ, expected: "a name and a value" },
+            AssemblerError::InvalidDirective { span: span(2, 0, 9), directive: DirectiveKind::Equ, expected: "a name and a value" },
+            AssemblerError::UnknownSymbol { span: span(4, 6, 11), name: String::from("later") },
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_flag_jumps() {
+        // Counts register 1 down to 0
+        let source = "LOAD $1 #5\nLOAD $2 #1\nloop: SUB $1 $2 $1\nJNZ @loop\nHLT";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(&test_vm.program()[12..16], &[44, 0, 8, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_assemble_compact_forms() {
+        // Sums 10 + 9 + ... + 1 into register 2
+        let source = "LOAD $1 #10\nloop: ADD $2 $1 $2\nDEC $1\nCMP $1 #0\nJNZ @loop\nMUL $2 #2 $3\nHLT";
+        let mut test_vm = VM::new();
+        test_vm.set_program(assemble(source).unwrap());
+        assert_eq!(&test_vm.program()[8..24], &[66, 1, 0, 0, 68, 1, 0, 0, 44, 0, 4, 0, 64, 2, 2, 3]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[2..4], [55, 110]);
+        assert_eq!(assemble("ADD $1 #127 $2\nSUB $1 #-128 $2\nCMP $1 #-32768"), Ok(vec![62, 1, 127, 2, 63, 1, 0x80, 2, 68, 1, 0x80, 0]));
+        // Immediates too large for the compact form keep the general form, which needs a register
+        assert_eq!(assemble("ADD $1 #128 $2\nMUL $1 #-129 $2\nCMP $1 #32768").unwrap_err(), vec![
+            AssemblerError::Parser(ParserError::WrongOperandKind { span: span(1, 7, 11), opcode: Opcode::ADD, position: 1 }),
+            AssemblerError::Parser(ParserError::WrongOperandKind { span: span(2, 7, 12), opcode: Opcode::MUL, position: 1 }),
+            AssemblerError::Parser(ParserError::WrongOperandKind { span: span(3, 7, 13), opcode: Opcode::CMP, position: 1 }),
+        ]);
+    }
+
+    #[test]
+    fn test_assemble_compact_forms_of_expressions() {
+        assert_eq!(assemble(".equ N, 100\nADD $1 #N + 27 $2\nCMP $1 #-N * 300"), Ok(vec![62, 1, 127, 2, 68, 1, 0x8A, 0xD0]));
+        // Only the compact form holds an immediate, so a value that does not fit it is rejected
+        let errors = assemble(".equ N, 100\nADD $1 #N + 28 $2\nSUB $1 #-N * 2 $2\nCMP $1 #N * 400").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::OperandOutOfRange { span: span(2, 7, 14), value: 128, min: -128, max: 127 },
+            AssemblerError::OperandOutOfRange { span: span(3, 7, 14), value: -200, min: -128, max: 127 },
+            AssemblerError::OperandOutOfRange { span: span(4, 7, 15), value: 40000, min: -32768, max: 32767 },
+        ]);
+        assert_eq!(errors[0].message(), "Operand 128 is above the largest value 127 of the 8 bit signed operand");
+        assert_eq!(errors[1].message(), "Operand -200 is below the smallest value -128 of the 8 bit signed operand");
+        assert_eq!(describe_range(i32::MIN as i64, u32::MAX as i64), "32 bit operand");
+    }
+
+    #[test]
+    fn test_assemble_floats() {
+        let source = ".data\npi: .double 3.5\ntwo: .double 2\n.code\nLOADF $f0 @pi\nLOADF $f1, @two\nMULF $f0 $f1 $f2\nFTOI $f2 $1\nLTF $f1 $f0 $2\nJEQ $2 @done\nLOAD $1 #0\ndone: HLT";
+        let object = Assembler::new().assemble_object(source).unwrap();
+        assert_eq!(object.rodata[..8], 3.5f64.to_be_bytes());
+        assert_eq!(object.code[..8], [48, 0, 0, 0, 48, 1, 0, 8]);
+        let mut test_vm = VM::new();
+        test_vm.load_object(object);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.float_registers[2], 7.0);
+        assert_eq!(test_vm.registers[1], 7);
+    }
 
+    #[test]
+    fn test_assemble_float_errors() {
+        let errors = assemble("ADDF $f0 $f1 $f32\n.data\n.double \"x\"").unwrap_err();
+        assert_eq!(errors, vec![
+            AssemblerError::InvalidFloatRegister { span: span(1, 13, 17), register: 32 },
+            AssemblerError::InvalidDirective { span: span(3, 0, 11), directive: DirectiveKind::Double, expected: "one or more numbers" },
+        ]);
+    }
+}
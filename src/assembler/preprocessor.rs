@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::diagnostic::{Diagnose, Span, SourceMap};
+use crate::lexer::Lexer;
+use crate::lexer::token::{Token, TokenType};
+use super::AssemblerError;
+
+// Macros may expand other macros, but a macro that keeps expanding itself is an error
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PreprocessorError {
+    IncludeNotFound { span: Span, path: String, reason: String },
+    IncludeCycle { span: Span, path: String },
+    InvalidInclude(Span),               // an .include without a path
+    InvalidMacro(Span),                 // a .macro without a name, or with parameters that are not names
+    DuplicateMacro { span: Span, name: String },
+    UnterminatedMacro { span: Span, name: String },
+    UnexpectedEndm(Span),
+    WrongArgumentCount { span: Span, name: String, expected: usize, found: usize },
+    RecursiveMacro { span: Span, name: String },
+}
+
+impl Diagnose for PreprocessorError {
+    fn span(&self) -> Span {
+        match self {
+            PreprocessorError::IncludeNotFound { span, .. }
+            | PreprocessorError::IncludeCycle { span, .. }
+            | PreprocessorError::InvalidInclude(span)
+            | PreprocessorError::InvalidMacro(span)
+            | PreprocessorError::DuplicateMacro { span, .. }
+            | PreprocessorError::UnterminatedMacro { span, .. }
+            | PreprocessorError::UnexpectedEndm(span)
+            | PreprocessorError::WrongArgumentCount { span, .. }
+            | PreprocessorError::RecursiveMacro { span, .. } => *span,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            PreprocessorError::IncludeNotFound { path, reason, .. } => format!("Unable to include `{}`: {}", path, reason),
+            PreprocessorError::IncludeCycle { path, .. } => format!("`{}` includes itself", path),
+            PreprocessorError::InvalidInclude(_) => String::from(".include expects the path of a file as a string"),
+            PreprocessorError::InvalidMacro(_) => String::from(".macro expects a name followed by parameter names"),
+            PreprocessorError::DuplicateMacro { name, .. } => format!("Macro `{}` was already defined", name),
+            PreprocessorError::UnterminatedMacro { name, .. } => format!("Macro `{}` has no .endm", name),
+            PreprocessorError::UnexpectedEndm(_) => String::from(".endm without a .macro"),
+            PreprocessorError::WrongArgumentCount { name, expected, found, .. } =>
+                format!("Macro `{}` expects {} arguments but {} were given", name, expected, found),
+            PreprocessorError::RecursiveMacro { name, .. } => format!("Macro `{}` expands itself without end", name),
+        }
+    }
+}
+
+impl fmt::Display for PreprocessorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {}", self.message(), self.span())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Vec<Token>>,      // the lines between .macro and .endm
+}
+
+/* Expands macros and include files in a token stream before it is parsed:
+
+    .macro inc reg, amount          ADD and LOAD lines from the body are put in place of
+        LOAD $31 amount             every `inc`, with `reg` and `amount` replaced by the
+        ADD reg $31 reg             arguments. Labels declared in the body are renamed for
+    .endm                           every expansion so a macro can be used more than once
+    inc $1, #2
+
+    .include "lib.tf"               the tokens of lib.tf, resolved relative to this file
+
+   Expanded tokens keep the span of the macro body they came from, tagged with the expansion,
+   so diagnostics can show both the body and where the macro was used.
+*/
+pub struct Preprocessor<'a> {
+    sources: &'a mut SourceMap,
+    paths: HashMap<usize, PathBuf>,     // the path of every file, to resolve its includes
+    including: Vec<PathBuf>,            // the files being included, to detect cycles
+    macros: HashMap<String, Macro>,
+    errors: Vec<AssemblerError>,
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(sources: &'a mut SourceMap) -> Preprocessor<'a> {
+        Preprocessor {
+            sources,
+            paths: HashMap::new(),
+            including: vec![],
+            macros: HashMap::new(),
+            errors: vec![],
+        }
+    }
+
+    // Expands the tokens of the file with id `file`, which was read from `path`
+    pub fn process(mut self, mut tokens: Vec<Token>, file: usize, path: &Path) -> Result<Vec<Token>, Vec<AssemblerError>> {
+        let eof = match tokens.last() {
+            Some(token) if token.token == TokenType::EOF => tokens.pop(),
+            _ => None,
+        };
+        self.paths.insert(file, path.to_path_buf());
+        self.including.push(canonical(path));
+
+        let mut output = self.process_lines(lines(tokens), 0);
+        output.extend(eof);
+        if self.errors.is_empty() {
+            Ok(output)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    fn process_lines(&mut self, lines: Vec<Vec<Token>>, depth: usize) -> Vec<Token> {
+        let mut output = vec![];
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            match directive_name(&line).as_deref() {
+                Some("macro") => self.define(&line, &mut lines),
+                Some("endm") => self.error(PreprocessorError::UnexpectedEndm(line[0].span)),
+                Some("include") => output.extend(self.include(&line, depth)),
+                _ => output.extend(self.expand_line(line, depth)),
+            }
+        }
+        output
+    }
+
+    // Reads a macro definition from its .macro line up to the matching .endm
+    fn define(&mut self, line: &[Token], lines: &mut impl Iterator<Item = Vec<Token>>) {
+        let span = line_span(line);
+        let names: Option<Vec<String>> = line[1..].iter()
+            .filter(|token| token.token != TokenType::COMMA)
+            .map(|token| match &token.token {
+                TokenType::OPCODE(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+
+        // The body is consumed even if the .macro line is invalid
+        let mut body = vec![];
+        let mut terminated = false;
+        for line in lines {
+            if directive_name(&line).as_deref() == Some("endm") {
+                terminated = true;
+                break;
+            }
+            body.push(line);
+        }
+
+        match names.as_deref() {
+            Some([name, ..]) if !terminated => self.error(PreprocessorError::UnterminatedMacro { span, name: name.clone() }),
+            Some([name, ..]) if self.macros.contains_key(name) => self.error(PreprocessorError::DuplicateMacro { span, name: name.clone() }),
+            Some([name, params @ ..]) => {
+                self.macros.insert(name.clone(), Macro { params: params.to_vec(), body });
+            },
+            _ => self.error(PreprocessorError::InvalidMacro(span)),
+        }
+    }
+
+    // Lexes and expands an included file, whose path is relative to the including file
+    fn include(&mut self, line: &[Token], depth: usize) -> Vec<Token> {
+        let (relative, span) = match line {
+            [_, Token { token: TokenType::STRING(path), span }] => (path.clone(), *span),
+            _ => {
+                self.error(PreprocessorError::InvalidInclude(line_span(line)));
+                return vec![];
+            },
+        };
+        let base = self.paths.get(&span.file).and_then(|path| path.parent()).unwrap_or_else(|| Path::new(""));
+        let path = base.join(&relative);
+        let canonical_path = canonical(&path);
+        if self.including.contains(&canonical_path) {
+            self.error(PreprocessorError::IncludeCycle { span, path: relative });
+            return vec![];
+        }
+
+        let file = self.sources.len();
+        let mut lexer = Lexer::for_file(file);
+        match lexer.lex_file(&path) {
+            Ok(text) => self.sources.add(&path.display().to_string(), &text),
+            Err(e) => {
+                self.error(PreprocessorError::IncludeNotFound { span, path: relative, reason: e.to_string() });
+                return vec![];
+            },
+        };
+        if !lexer.errors.is_empty() {
+            self.errors.extend(lexer.errors.into_iter().map(AssemblerError::Lexer));
+            return vec![];
+        }
+
+        self.paths.insert(file, path);
+        self.including.push(canonical_path);
+        let tokens = lexer.tokens.into_iter().filter(|token| token.token != TokenType::EOF).collect();
+        let output = self.process_lines(lines(tokens), depth);
+        self.including.pop();
+        output
+    }
+
+    // Expands the line if it uses a macro, after any labels declared on it
+    fn expand_line(&mut self, line: Vec<Token>, depth: usize) -> Vec<Token> {
+        let labels = line.iter().take_while(|token| matches!(token.token, TokenType::LabelDeclaration(_))).count();
+        let (name, definition) = match line.get(labels).map(|token| &token.token) {
+            Some(TokenType::OPCODE(name)) => match self.macros.get(name) {
+                Some(definition) => (name.clone(), definition.clone()),
+                None => return line,
+            },
+            _ => return line,
+        };
+        let site = line_span(&line[labels..]);
+        let mut output = line[..labels].to_vec();
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.error(PreprocessorError::RecursiveMacro { span: site, name });
+            return output;
+        }
+        let arguments = split_arguments(&line[labels + 1..]);
+        if arguments.len() != definition.params.len() {
+            let (expected, found) = (definition.params.len(), arguments.len());
+            self.error(PreprocessorError::WrongArgumentCount { span: site, name, expected, found });
+            return output;
+        }
+
+        let expansion = self.sources.add_expansion(&name, site);
+        let locals: Vec<&String> = definition.body.iter().flatten()
+            .filter_map(|token| match &token.token {
+                TokenType::LabelDeclaration(label) => Some(label),
+                _ => None,
+            })
+            .collect();
+        let local = |label: &String| match locals.contains(&label) {
+            true => format!("__{}_{}_{}", name, expansion, label),
+            false => label.clone(),
+        };
+
+        let mut body = vec![];
+        for body_line in &definition.body {
+            let mut expanded = vec![];
+            for token in body_line {
+                let span = Span { expansion: Some(expansion), ..token.span };
+                match &token.token {
+                    TokenType::OPCODE(param) if definition.params.contains(param) => {
+                        let position = definition.params.iter().position(|name| name == param).unwrap_or(0);
+                        // Arguments take the place, and so the span, of their parameter
+                        expanded.extend(arguments[position].iter().map(|argument| Token::new(argument.token.clone(), span)));
+                    },
+                    TokenType::LabelDeclaration(label) => expanded.push(Token::new(TokenType::LabelDeclaration(local(label)), span)),
+                    TokenType::LabelUsage(label) => expanded.push(Token::new(TokenType::LabelUsage(local(label)), span)),
+                    other => expanded.push(Token::new(other.clone(), span)),
+                }
+            }
+            body.push(expanded);
+        }
+        output.extend(self.process_lines(body, depth + 1));
+        output
+    }
+
+    fn error(&mut self, error: PreprocessorError) {
+        self.errors.push(AssemblerError::Preprocessor(error));
+    }
+}
+
+// Groups tokens into the lines they were written on
+fn lines(tokens: Vec<Token>) -> Vec<Vec<Token>> {
+    let mut lines: Vec<Vec<Token>> = vec![];
+    for token in tokens {
+        match lines.last_mut() {
+            Some(line) if line[0].span.same_line(token.span) => line.push(token),
+            _ => lines.push(vec![token]),
+        }
+    }
+    lines
+}
+
+// The lower case name of the directive that starts a line, if it starts with one
+fn directive_name(line: &[Token]) -> Option<String> {
+    match line.first().map(|token| &token.token) {
+        Some(TokenType::DIRECTIVE(name)) => Some(name.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn line_span(line: &[Token]) -> Span {
+    match (line.first(), line.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => Span::default(),
+    }
+}
+
+/* Splits the tokens after a macro name into its arguments. Arguments are separated by commas
+   or, without commas, are a single token or a `$` or `#` with the number or expression after it:
+
+    inc $1, #2      => [$ 1] [# 2]
+    inc $1 #2       => [$ 1] [# 2]
+    inc $1 #N*2     => [$ 1] [# N * 2]
+*/
+fn split_arguments(tokens: &[Token]) -> Vec<Vec<Token>> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    if tokens.iter().any(|token| token.token == TokenType::COMMA) {
+        return tokens.split(|token| token.token == TokenType::COMMA).map(|argument| argument.to_vec()).collect();
+    }
+    let mut arguments = vec![];
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        let mut argument = vec![token.clone()];
+        if matches!(token.token, TokenType::REGISTER | TokenType::IntOperand) {
+            argument.extend(tokens.next().cloned());
+        }
+        if token.token == TokenType::IntOperand {
+            // An expression goes on for as long as operators join its tokens
+            while let Some(next) = tokens.as_slice().first() {
+                let after_operator = matches!(argument.last().map(|last| &last.token), Some(TokenType::OPERATOR(c)) if *c != ')');
+                let before_operator = matches!(&next.token, TokenType::OPERATOR(c) if *c != '~');
+                if !after_operator && !before_operator {
+                    break;
+                }
+                argument.push(next.clone());
+                tokens.next();
+            }
+        }
+        arguments.push(argument);
+    }
+    arguments
+}
+
+// The same file is recognized however it is reached
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    fn preprocess(source: &str) -> Result<Vec<TokenType>, Vec<AssemblerError>> {
+        let mut sources = SourceMap::new();
+        let file = sources.add("<input>", source);
+        let mut lexer = Lexer::for_file(file);
+        lexer.lex_source(source);
+        let tokens = Preprocessor::new(&mut sources).process(lexer.tokens, file, Path::new("<input>"))?;
+        Ok(tokens.into_iter().map(|token| token.token).collect())
+    }
+
+    fn opcode(name: &str) -> TokenType {
+        TokenType::OPCODE(String::from(name))
+    }
+
+    // A directory of its own for the include files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("teflon-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_expand_macro() {
+        let tokens = preprocess(".macro twice reg\nADD reg reg reg\n.endm\ntwice $1\ntwice $2").unwrap();
+        assert_eq!(tokens, vec![
+            opcode("ADD"), TokenType::REGISTER, TokenType::NUMBER(1), TokenType::REGISTER, TokenType::NUMBER(1),
+            TokenType::REGISTER, TokenType::NUMBER(1),
+            opcode("ADD"), TokenType::REGISTER, TokenType::NUMBER(2), TokenType::REGISTER, TokenType::NUMBER(2),
+            TokenType::REGISTER, TokenType::NUMBER(2),
+            TokenType::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_expression_arguments() {
+        let tokens = preprocess(".macro set reg value\nLOAD reg value\n.endm\nset $1 #lo(N)*2 ").unwrap();
+        assert_eq!(tokens, vec![
+            opcode("LOAD"), TokenType::REGISTER, TokenType::NUMBER(1), TokenType::IntOperand, opcode("lo"),
+            TokenType::OPERATOR('('), opcode("N"), TokenType::OPERATOR(')'), TokenType::OPERATOR('*'), TokenType::NUMBER(2),
+            TokenType::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_local_labels_are_unique() {
+        let tokens = preprocess(".macro spin\nloop: JMP @loop\n.endm\nspin\nspin\nJMP @loop").unwrap();
+        assert_eq!(tokens, vec![
+            TokenType::LabelDeclaration(String::from("__spin_0_loop")), opcode("JMP"), TokenType::LabelUsage(String::from("__spin_0_loop")),
+            TokenType::LabelDeclaration(String::from("__spin_1_loop")), opcode("JMP"), TokenType::LabelUsage(String::from("__spin_1_loop")),
+            opcode("JMP"), TokenType::LabelUsage(String::from("loop")),
+            TokenType::EOF,
+        ]);
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let errors = preprocess(".macro a x\nHLT\n.endm\na\n.endm\n.macro a\n.endm\n.macro b\nb\n.endm\nb\n.macro\n.endm\n.macro c").unwrap_err();
+        let errors: Vec<String> = errors.iter().map(|e| e.message()).collect();
+        assert_eq!(errors, vec![
+            "Macro `a` expects 1 arguments but 0 were given",
+            ".endm without a .macro",
+            "Macro `a` was already defined",
+            "Macro `b` expands itself without end",
+            ".macro expects a name followed by parameter names",
+            "Macro `c` has no .endm",
+        ]);
+    }
+
+    #[test]
+    fn test_include_relative_to_including_file() {
+        let dir = test_dir("include");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/inc.tf"), ".include \"more.tf\"\n.macro one reg\nLOAD reg #1\n.endm").unwrap();
+        fs::write(dir.join("lib/more.tf"), "HLT").unwrap();
+        let main = dir.join("main.tf");
+        let source = ".include \"lib/inc.tf\"\none $4";
+
+        let mut sources = SourceMap::new();
+        let file = sources.add(&main.display().to_string(), source);
+        let mut lexer = Lexer::for_file(file);
+        lexer.lex_source(source);
+        let tokens = Preprocessor::new(&mut sources).process(lexer.tokens, file, &main).unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|token| token.token.clone()).collect();
+        assert_eq!(types, vec![
+            opcode("HLT"),
+            opcode("LOAD"), TokenType::REGISTER, TokenType::NUMBER(4), TokenType::IntOperand, TokenType::NUMBER(1),
+            TokenType::EOF,
+        ]);
+        assert_eq!(sources.len(), 3);
+        assert_eq!(tokens[0].span.file, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let dir = test_dir("cycle");
+        fs::write(dir.join("a.tf"), ".include \"b.tf\"").unwrap();
+        fs::write(dir.join("b.tf"), ".include \"a.tf\"").unwrap();
+        let main = dir.join("main.tf");
+        let source = ".include \"a.tf\"\n.include \"missing.tf\"\n.include";
+
+        let mut sources = SourceMap::new();
+        let file = sources.add("main.tf", source);
+        let mut lexer = Lexer::for_file(file);
+        lexer.lex_source(source);
+        let errors = Preprocessor::new(&mut sources).process(lexer.tokens, file, &main).unwrap_err();
+        assert!(matches!(&errors[0], AssemblerError::Preprocessor(PreprocessorError::IncludeCycle { path, .. }) if path == "a.tf"));
+        assert!(matches!(&errors[1], AssemblerError::Preprocessor(PreprocessorError::IncludeNotFound { path, .. }) if path == "missing.tf"));
+        assert_eq!(errors[2], AssemblerError::Preprocessor(PreprocessorError::InvalidInclude(Span::new(0, 3, 0, 8))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
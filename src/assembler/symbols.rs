@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use crate::object::Section;
+
+// Maps every label declared in a program to the section and address it was declared at, and
+// every `.equ` constant to its value. Labels and constants share one namespace
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct SymbolTable {
+    symbols: HashMap<String, (Section, usize)>,
+    constants: HashMap<String, i64>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable {
+            symbols: HashMap::new(),
+            constants: HashMap::new(),
+        }
+    }
+
+    // Adds a symbol, returning false if a symbol with the same name already exists
+    pub fn add_symbol(&mut self, name: &str, section: Section, address: usize) -> bool {
+        if self.contains(name) {
+            return false;
+        }
+        self.symbols.insert(name.to_string(), (section, address));
+        true
+    }
+
+    // Adds a constant, returning false if a symbol with the same name already exists
+    pub fn add_constant(&mut self, name: &str, value: i64) -> bool {
+        if self.contains(name) {
+            return false;
+        }
+        self.constants.insert(name.to_string(), value);
+        true
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.symbols.contains_key(name) || self.constants.contains_key(name)
+    }
+
+    // The value a name has in an expression: a constant's value or a label's address
+    pub fn value(&self, name: &str) -> Option<i64> {
+        self.constants.get(name).copied().or_else(|| self.symbol_value(name).map(|address| address as i64))
+    }
+
+    pub fn symbol_value(&self, name: &str) -> Option<usize> {
+        self.symbols.get(name).map(|(_, address)| *address)
+    }
+
+    pub fn symbol_section(&self, name: &str) -> Option<Section> {
+        self.symbols.get(name).map(|(section, _)| *section)
+    }
+
+    // Every label ordered by section (code first), then address, then name
+    pub fn iter(&self) -> Vec<(&str, Section, usize)> {
+        let mut symbols: Vec<(&str, Section, usize)> = self.symbols.iter()
+            .map(|(name, (section, address))| (name.as_str(), *section, *address))
+            .collect();
+        symbols.sort_by_key(|(name, section, address)| (*section == Section::ReadOnlyData, *address, *name));
+        symbols
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_symbol_table() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.add_symbol("loop", Section::Code, 12));
+        assert!(!symbols.add_symbol("loop", Section::Code, 16));
+        assert_eq!(symbols.symbol_value("loop"), Some(12));
+        assert_eq!(symbols.symbol_value("end"), None);
+        assert_eq!(symbols.len(), 1);
+        assert!(symbols.add_symbol("start", Section::Code, 0));
+        assert!(symbols.add_symbol("msg", Section::ReadOnlyData, 0));
+        assert_eq!(symbols.symbol_section("msg"), Some(Section::ReadOnlyData));
+        assert_eq!(symbols.iter(), vec![
+            ("start", Section::Code, 0),
+            ("loop", Section::Code, 12),
+            ("msg", Section::ReadOnlyData, 0),
+        ]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut symbols = SymbolTable::new();
+        assert!(symbols.add_constant("SIZE", -64));
+        assert!(symbols.add_symbol("end", Section::Code, 8));
+        assert!(!symbols.add_symbol("SIZE", Section::Code, 0));
+        assert!(!symbols.add_constant("end", 1));
+        assert_eq!(symbols.value("SIZE"), Some(-64));
+        assert_eq!(symbols.value("end"), Some(8));
+        assert_eq!(symbols.symbol_value("SIZE"), None);
+        assert_eq!(symbols.len(), 1);
+    }
+}
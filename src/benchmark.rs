@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+use crate::assembler::Assembler;
+use crate::vm::{VM, VmError};
+
+// The most times the outer loop can run, since LOAD takes a 16 bit immediate
+pub const MAX_ROUNDS: u16 = u16::MAX;
+
+/* A loop-heavy program: the inner loop runs 30000 times for every round of the outer one and
+   is made of the compact instructions a typical loop uses.
+*/
+fn source(rounds: u16) -> String {
+    format!(".equ ROUNDS, {}
+        LOAD $1 #ROUNDS
+outer:  LOAD $2 #30000
+inner:  ADD $3 $2 $3
+        XOR $4 $3 $4
+        DEC $2
+        JNZ @inner
+        DEC $1
+        JNZ @outer
+        HLT", rounds)
+}
+
+// How long the byte-level loop and the pre-decoded loop took to run the same program
+#[derive(Debug)]
+pub struct Report {
+    pub byte_level: Duration,
+    pub predecoded: Duration,
+}
+
+impl Report {
+    pub fn speedup(&self) -> f64 {
+        self.byte_level.as_secs_f64() / self.predecoded.as_secs_f64()
+    }
+}
+
+// Runs the benchmark program with both interpreter loops and checks they agree
+pub fn run(rounds: u16) -> Result<Report, String> {
+    let program = Assembler::new().assemble(&source(rounds))
+        .map_err(|errors| format!("Benchmark program does not assemble: {:?}", errors))?;
+
+    let mut byte_level = VM::new();
+    byte_level.set_program(program.clone());
+    let (byte_level_time, byte_level_result) = time(|| byte_level.run());
+
+    let mut predecoded = VM::new();
+    predecoded.set_program(program);
+    // Decoding is part of the cost of the pre-decoded loop
+    let (predecoded_time, predecoded_result) = time(|| {
+        predecoded.predecode();
+        predecoded.run_predecoded()
+    });
+
+    if byte_level_result != predecoded_result || byte_level.registers != predecoded.registers {
+        return Err(String::from("The byte-level and pre-decoded loops gave different results"));
+    }
+    byte_level_result.map_err(|e| format!("Error: {}", e))?;
+    Ok(Report { byte_level: byte_level_time, predecoded: predecoded_time })
+}
+
+fn time<T>(mut f: impl FnMut() -> Result<T, VmError>) -> (Duration, Result<T, VmError>) {
+    let start = Instant::now();
+    let result = f();
+    (start.elapsed(), result)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_benchmark_loops_agree() {
+        let report = run(1).unwrap();
+        assert!(report.speedup() > 0.0);
+    }
+}
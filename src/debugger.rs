@@ -0,0 +1,294 @@
+use std::collections::BTreeSet;
+use crate::disassembler::{disassemble_instruction, DisassembledInstruction};
+use crate::instructions::Opcode;
+use crate::assembler::instruction_parsers::INSTRUCTION_SIZE;
+use crate::object::{Symbol, Section};
+use crate::vm::{VM, ExitReason, VmError, REGISTER_COUNT};
+
+// Why the debugger handed control back
+#[derive(Debug, PartialEq, Clone)]
+pub enum StopReason {
+    Stepped,                                                // a step finished normally
+    Breakpoint(usize),                                      // execution reached a breakpoint at this address
+    Watchpoint { register: usize, old: i32, new: i32 },    // a watched register changed
+    Exited(ExitReason),                                     // the program finished
+    Error(VmError),                                         // the program stopped with an error
+}
+
+// One entry of a backtrace: the address being executed in a frame
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub address: usize,
+    pub label: Option<String>,      // the closest label at or before the address
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Watchpoint {
+    register: usize,
+    value: i32,
+}
+
+/* Wraps a VM to run it an instruction at a time with breakpoints and watchpoints:
+
+ - step      executes one instruction
+ - step_over executes one instruction, running a whole subroutine when it is a CALL
+ - cont      runs until a breakpoint, a watchpoint or the end of the program
+*/
+pub struct Debugger {
+    pub vm: VM,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    symbols: Vec<Symbol>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new(VM::new())
+    }
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            watchpoints: vec![],
+            symbols: vec![],
+        }
+    }
+
+    // Symbols let breakpoints be set on labels and name addresses in the output
+    pub fn set_symbols(&mut self, symbols: Vec<Symbol>) {
+        self.symbols = symbols;
+    }
+
+    // Adds a breakpoint on a decimal or `0x` hex address, or on a label. Returns the address
+    pub fn add_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let address = self.locate(location)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    // Removes the breakpoint on an address or label, failing when there is none
+    pub fn remove_breakpoint(&mut self, location: &str) -> Result<usize, String> {
+        let address = self.locate(location)?;
+        if !self.breakpoints.remove(&address) {
+            return Err(format!("No breakpoint at {:04X}", address));
+        }
+        Ok(address)
+    }
+
+    fn locate(&self, location: &str) -> Result<usize, String> {
+        let location = location.trim_start_matches('@');
+        match parse_address(location) {
+            Some(address) => Ok(address),
+            None => self.symbols.iter()
+                .find(|symbol| symbol.section == Section::Code && symbol.name == location)
+                .map(|symbol| symbol.address as usize)
+                .ok_or_else(|| format!("Unknown address or label `{}`", location)),
+        }
+    }
+
+    pub fn breakpoints(&self) -> Vec<usize> {
+        self.breakpoints.iter().copied().collect()
+    }
+
+    // Stops execution whenever the value of a register changes
+    pub fn watch(&mut self, register: usize) -> Result<(), String> {
+        if register >= REGISTER_COUNT {
+            return Err(format!("Register ${} does not exist", register));
+        }
+        if !self.watchpoints.iter().any(|watch| watch.register == register) {
+            self.watchpoints.push(Watchpoint { register, value: self.vm.registers[register] });
+        }
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, register: usize) -> Result<(), String> {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watch| watch.register != register);
+        if self.watchpoints.len() == count {
+            return Err(format!("${} is not watched", register));
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        match self.vm.run_once() {
+            Ok(ExitReason::Stepped) => self.check_watchpoints().unwrap_or(StopReason::Stepped),
+            Ok(reason) => StopReason::Exited(reason),
+            Err(e) => StopReason::Error(e),
+        }
+    }
+
+    // Steps over CALL instructions by running until the subroutine returns
+    pub fn step_over(&mut self) -> StopReason {
+        if self.current_instruction().opcode != Opcode::CALL {
+            return self.step();
+        }
+        let return_address = self.vm.pc() + INSTRUCTION_SIZE;
+        let depth = self.vm.sp();
+        self.run_until(|vm| vm.pc() == return_address && vm.sp() == depth)
+    }
+
+    // Runs until a breakpoint, a watchpoint or the end of the program. A breakpoint at the
+    // current address does not stop execution again straight away
+    pub fn cont(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    fn run_until(&mut self, done: impl Fn(&VM) -> bool) -> StopReason {
+        loop {
+            match self.step() {
+                StopReason::Stepped => (),
+                reason => return reason,
+            }
+            if done(&self.vm) {
+                return StopReason::Stepped;
+            }
+            if self.breakpoints.contains(&self.vm.pc()) {
+                return StopReason::Breakpoint(self.vm.pc());
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<StopReason> {
+        let registers = self.vm.registers;
+        let mut stop = None;
+        for watch in self.watchpoints.iter_mut() {
+            let new = registers[watch.register];
+            if new != watch.value && stop.is_none() {
+                stop = Some(StopReason::Watchpoint { register: watch.register, old: watch.value, new });
+            }
+            watch.value = new;
+        }
+        stop
+    }
+
+    // The instruction at the program counter
+    pub fn current_instruction(&self) -> DisassembledInstruction {
+        disassemble_instruction(self.vm.program(), self.vm.pc(), &self.symbols)
+    }
+
+    // The current frame followed by every caller, innermost first
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let stack = self.vm.stack();
+        let mut frames = vec![self.frame(self.vm.pc())];
+        let mut fp = self.vm.fp();
+        // Each frame is the return address followed by the caller's frame pointer
+        while fp >= 2 && fp <= stack.len() {
+            frames.push(self.frame(stack[fp - 2] as usize));
+            let caller_fp = stack[fp - 1] as usize;
+            if caller_fp >= fp {
+                break;
+            }
+            fp = caller_fp;
+        }
+        frames
+    }
+
+    fn frame(&self, address: usize) -> Frame {
+        let label = self.symbols.iter()
+            .filter(|symbol| symbol.section == Section::Code && symbol.address as usize <= address)
+            .max_by_key(|symbol| symbol.address)
+            .map(|symbol| symbol.name.clone());
+        Frame { address, label }
+    }
+}
+
+fn parse_address(location: &str) -> Option<usize> {
+    if let Some(hex) = location.strip_prefix("0x") {
+        return usize::from_str_radix(hex, 16).ok();
+    }
+    location.parse().ok()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    const PROGRAM: &str = "main: LOAD $1 #3
+CALL @double
+ADD $1 $1 $2
+HLT
+double: ADD $1 $1 $1
+CALL @inner
+RET
+inner: RET";
+
+    fn debugger() -> Debugger {
+        let object = Assembler::new().assemble_object(PROGRAM).unwrap();
+        let symbols = object.symbols.clone().unwrap();
+        let mut vm = VM::new();
+        vm.load_object(object);
+        let mut debugger = Debugger::new(vm);
+        debugger.set_symbols(symbols);
+        debugger
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.current_instruction().to_string(), "0000: main: LOAD $1 #3");
+        assert_eq!(debugger.step(), StopReason::Stepped);
+        assert_eq!(debugger.vm.pc(), 4);
+        assert_eq!(debugger.step(), StopReason::Stepped);
+        assert_eq!(debugger.vm.pc(), 16);
+    }
+
+    #[test]
+    fn test_breakpoint_on_label_and_address() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.add_breakpoint("@inner"), Ok(28));
+        assert_eq!(debugger.add_breakpoint("0x8"), Ok(8));
+        assert!(debugger.add_breakpoint("nowhere").is_err());
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(28));
+        // Continuing from a breakpoint moves past it
+        assert_eq!(debugger.cont(), StopReason::Breakpoint(8));
+        assert_eq!(debugger.cont(), StopReason::Exited(ExitReason::Halted));
+        assert_eq!(debugger.vm.registers[2], 12);
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut debugger = debugger();
+        debugger.watch(1).unwrap();
+        assert_eq!(debugger.cont(), StopReason::Watchpoint { register: 1, old: 0, new: 3 });
+        assert_eq!(debugger.cont(), StopReason::Watchpoint { register: 1, old: 3, new: 6 });
+        assert!(debugger.watch(32).is_err());
+    }
+
+    #[test]
+    fn test_step_over_calls() {
+        let mut debugger = debugger();
+        debugger.step();
+        assert_eq!(debugger.step_over(), StopReason::Stepped);
+        assert_eq!(debugger.vm.pc(), 8);
+        assert_eq!(debugger.vm.registers[1], 6);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint("inner").unwrap();
+        debugger.cont();
+        let frames: Vec<(usize, Option<String>)> = debugger.backtrace().into_iter()
+            .map(|frame| (frame.address, frame.label))
+            .collect();
+        assert_eq!(frames, vec![
+            (28, Some(String::from("inner"))),
+            (24, Some(String::from("double"))),
+            (8, Some(String::from("main"))),
+        ]);
+    }
+
+    #[test]
+    fn test_error_stops_execution() {
+        let mut vm = VM::new();
+        vm.set_program(vec![200, 0, 0, 0]);
+        let mut debugger = Debugger::new(vm);
+        assert_eq!(debugger.cont(), StopReason::Error(VmError::IllegalOpcode { opcode: 200, pc: 0 }));
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use crate::instructions::{Opcode, OperandKind};
+use crate::assembler::instruction_parsers::INSTRUCTION_SIZE;
+use crate::vm::{VmError, REGISTER_COUNT};
+
+/* An instruction read out of the bytecode once, with its operands pulled apart and its registers
+   checked, so executing it does not have to touch the program bytes again. Operands are in the
+   order of the instruction table: registers are indexes, immediates and addresses are the raw
+   16 bit values.
+
+ EX: 02 00 01 03 => DecodedInstruction { opcode: ADD, operands: [0, 1, 3], length: 4 }
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DecodedInstruction {
+    pub opcode: Opcode,
+    pub operands: [u16; 3],
+    pub length: usize,      // How far the program counter moves when the instruction does not jump
+}
+
+// Shows the instruction as assembly, with addresses and offsets as plain numbers
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode.mnemonic())?;
+        for (kind, value) in self.opcode.operands().unwrap_or(&[]).iter().zip(&self.operands) {
+            match kind {
+                OperandKind::Register => write!(f, " ${}", value)?,
+                OperandKind::FloatRegister => write!(f, " $f{}", value)?,
+                OperandKind::SignedInteger8 => write!(f, " #{}", *value as u8 as i8)?,
+                OperandKind::SignedInteger16 => write!(f, " #{}", *value as i16)?,
+                _ => write!(f, " #{}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Decodes the instruction that starts at `address`. Errors are the ones executing the bytes
+// would give, so decoding can be done ahead of time without changing what a program does
+pub fn decode_instruction(program: &[u8], address: usize) -> Result<DecodedInstruction, VmError> {
+    let byte = program[address];
+    let opcode = Opcode::from(byte);
+    let kinds = opcode.operands().ok_or(VmError::IllegalOpcode { opcode: byte, pc: address })?;
+    // HLT and RET never read past their opcode, so they need no padding
+    let length = if kinds.is_empty() { 1 } else { INSTRUCTION_SIZE };
+    let mut operands = [0; 3];
+    let mut offset = address + 1;
+    for (position, kind) in kinds.iter().enumerate() {
+        let bytes = program.get(offset..offset + kind.width())
+            .ok_or(VmError::TruncatedInstruction { pc: address })?;
+        let value = bytes.iter().fold(0, |value, byte| (value << 8) | *byte as u16);
+        if matches!(kind, OperandKind::Register | OperandKind::FloatRegister) && value as usize >= REGISTER_COUNT {
+            return Err(VmError::InvalidRegister { register: value as u8, pc: address });
+        }
+        operands[position] = value;
+        offset += kind.width();
+    }
+    if address + length > program.len() {
+        return Err(VmError::TruncatedInstruction { pc: address });
+    }
+    Ok(DecodedInstruction { opcode, operands, length })
+}
+
+// Decodes every instruction of a program, indexed by address / INSTRUCTION_SIZE. Instructions
+// that do not decode are left as None so the error is only raised if they are ever executed
+pub fn decode_program(program: &[u8]) -> Vec<Option<DecodedInstruction>> {
+    (0..program.len())
+        .step_by(INSTRUCTION_SIZE)
+        .map(|address| decode_instruction(program, address).ok())
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_instruction() {
+        let program = vec![1, 2, 0xFF, 0xFE, 0, 0, 0, 0];
+        assert_eq!(
+            decode_instruction(&program, 0),
+            Ok(DecodedInstruction { opcode: Opcode::LOAD, operands: [2, 0xFFFE, 0], length: 4 }),
+        );
+        assert_eq!(
+            decode_instruction(&program, 4),
+            Ok(DecodedInstruction { opcode: Opcode::HLT, operands: [0, 0, 0], length: 1 }),
+        );
+        assert_eq!(decode_instruction(&program, 0).unwrap().to_string(), "LOAD $2 #65534");
+        assert_eq!(decode_instruction(&[69, 2, 0xFF, 0xFE], 0).unwrap().to_string(), "LOADS $2 #-2");
+        assert_eq!(decode_instruction(&[62, 1, 0xFF, 31], 0).unwrap().to_string(), "ADDI $1 #-1 $31");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode_instruction(&[200, 0, 0, 0], 0), Err(VmError::IllegalOpcode { opcode: 200, pc: 0 }));
+        assert_eq!(decode_instruction(&[2, 0, 32, 1], 0), Err(VmError::InvalidRegister { register: 32, pc: 0 }));
+        assert_eq!(decode_instruction(&[1, 0, 0, 15, 1, 0], 4), Err(VmError::TruncatedInstruction { pc: 4 }));
+        assert_eq!(decode_instruction(&[21, 0], 0), Err(VmError::TruncatedInstruction { pc: 0 }));
+    }
+
+    #[test]
+    fn test_decode_program() {
+        let program = vec![2, 0, 1, 3, 200, 0, 0, 0, 0];
+        let decoded = decode_program(&program);
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0].map(|instruction| instruction.opcode), Some(Opcode::ADD));
+        assert_eq!(decoded[1], None);
+        assert_eq!(decoded[2].map(|instruction| instruction.opcode), Some(Opcode::HLT));
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+
+/* Where a token or an error is in the source: the file it came from, its line and the byte
+   columns it starts and ends at. Lines count from 1, columns from 0 and the end is exclusive.
+   Tokens that came from a macro expansion point into the macro's body and name the expansion.
+
+ EX: `ADD` in "LOAD $1 #2\nADD $1 $2 $3" => file 0, line 2, columns 0..3
+*/
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub file: usize,
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+    pub expansion: Option<usize>,   // the index of the macro expansion in the `SourceMap`
+}
+
+impl Span {
+    pub fn new(file: usize, line: usize, start: usize, end: usize) -> Span {
+        Span { file, line, start, end, expansion: None }
+    }
+
+    // Whether both spans are on the same line of the same file and expansion. A statement
+    // never continues past the end of its line
+    pub fn same_line(self, other: Span) -> bool {
+        self.file == other.file && self.line == other.line && self.expansion == other.expansion
+    }
+
+    // The span from the start of this one to the end of `other`, which must be on the same line
+    pub fn to(self, other: Span) -> Span {
+        if !self.same_line(other) {
+            return self;
+        }
+        Span { end: other.end.max(self.end), ..self }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.start + 1)
+    }
+}
+
+// The source files spans point into. A span's `file` is the index of its file here and its
+// `expansion` the index of the macro expansion it came from
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    expansions: Vec<Expansion>,
+}
+
+// Where a macro was expanded
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expansion {
+    pub name: String,
+    pub site: Span,
+}
+
+#[derive(Debug, Clone)]
+struct SourceFile {
+    name: String,
+    text: String,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    // Adds a file and returns its id
+    pub fn add(&mut self, name: &str, text: &str) -> usize {
+        self.files.push(SourceFile { name: name.to_string(), text: text.to_string() });
+        self.files.len() - 1
+    }
+
+    // The number of files added so far, which is also the id the next file gets
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // Records the expansion of a macro at `site` and returns its id
+    pub fn add_expansion(&mut self, name: &str, site: Span) -> usize {
+        self.expansions.push(Expansion { name: name.to_string(), site });
+        self.expansions.len() - 1
+    }
+
+    pub fn expansion(&self, id: usize) -> Option<&Expansion> {
+        self.expansions.get(id)
+    }
+
+    // The span in the source that was written, outside of every macro expansion
+    pub fn origin(&self, mut span: Span) -> Span {
+        while let Some(expansion) = span.expansion.and_then(|id| self.expansion(id)) {
+            span = expansion.site;
+        }
+        span
+    }
+
+    pub fn name(&self, file: usize) -> Option<&str> {
+        self.files.get(file).map(|file| file.name.as_str())
+    }
+
+    // The text of a line, without its line ending
+    pub fn line(&self, file: usize, line: usize) -> Option<&str> {
+        let file = self.files.get(file)?;
+        file.text.lines().nth(line.checked_sub(1)?)
+    }
+}
+
+// Errors that point at the source they were found in
+pub trait Diagnose {
+    fn span(&self) -> Span;
+
+    // What went wrong, without the location
+    fn message(&self) -> String;
+
+    fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.span(), self.message())
+    }
+}
+
+/* An error message together with the source it is about. Rendered it looks like:
+
+    error: Unknown opcode `LAOD`
+     --> prog.tf:2:1
+      |
+    2 | LAOD $1 #10
+      | ^^^^
+
+   Errors inside a macro expansion are followed by a note for every expansion they are in.
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: String) -> Diagnostic {
+        Diagnostic { span, message }
+    }
+
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut rendered = format!("error: {}\n", self.message);
+        rendered.push_str(&snippet(self.span, sources));
+        let mut expansion = self.span.expansion;
+        while let Some(Expansion { name, site }) = expansion.and_then(|id| sources.expansion(id)) {
+            rendered.push_str(&format!("note: in expansion of macro `{}`\n", name));
+            rendered.push_str(&snippet(*site, sources));
+            expansion = site.expansion;
+        }
+        rendered
+    }
+}
+
+// The location of a span followed by its source line with the span underlined
+fn snippet(span: Span, sources: &SourceMap) -> String {
+    let name = sources.name(span.file).unwrap_or("<input>");
+    let mut rendered = String::new();
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+    rendered.push_str(&format!("{}--> {}:{}:{}\n", gutter, name, span.line, span.start + 1));
+
+    if let Some(text) = sources.line(span.file, span.line) {
+        // Columns are bytes, but the caret has to line up with characters
+        let start = text.get(..span.start.min(text.len())).map_or(0, |prefix| prefix.chars().count());
+        let width = text.get(span.start.min(text.len())..span.end.min(text.len()))
+            .map_or(0, |underlined| underlined.chars().count())
+            .max(1);
+        let padding: String = text.chars().take(start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        rendered.push_str(&format!("{} |\n", gutter));
+        rendered.push_str(&format!("{} | {}\n", line_number, text));
+        rendered.push_str(&format!("{} | {}{}\n", gutter, padding, "^".repeat(width)));
+    }
+    rendered
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_diagnostic() {
+        let mut sources = SourceMap::new();
+        let file = sources.add("prog.tf", "LOAD $1 #10\nLAOD $1 #10");
+        let diagnostic = Diagnostic::new(Span::new(file, 2, 0, 4), String::from("Unknown opcode `LAOD`"));
+        assert_eq!(diagnostic.render(&sources), "\
+error: Unknown opcode `LAOD`
+ --> prog.tf:2:1
+  |
+2 | LAOD $1 #10
+  | ^^^^
+");
+    }
+
+    #[test]
+    fn test_render_without_source() {
+        let diagnostic = Diagnostic::new(Span::new(3, 12, 4, 5), String::from("Oops"));
+        assert_eq!(diagnostic.render(&SourceMap::new()), "error: Oops\n  --> <input>:12:5\n");
+    }
+
+    #[test]
+    fn test_caret_counts_characters() {
+        let mut sources = SourceMap::new();
+        sources.add("a", "\u{e9}\u{e9} %");
+        let diagnostic = Diagnostic::new(Span::new(0, 1, 5, 6), String::from("Bad"));
+        assert!(diagnostic.render(&sources).ends_with("1 | \u{e9}\u{e9} %\n  |    ^\n"));
+    }
+
+    #[test]
+    fn test_span_to() {
+        let start = Span::new(0, 1, 0, 3);
+        assert_eq!(start.to(Span::new(0, 1, 8, 11)), Span::new(0, 1, 0, 11));
+        assert_eq!(start.to(Span::new(0, 2, 8, 11)), start);
+    }
+}
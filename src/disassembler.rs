@@ -0,0 +1,186 @@
+use std::fmt;
+use crate::instructions::{Opcode, OperandKind};
+use crate::assembler::instruction_parsers::{Operand, INSTRUCTION_SIZE};
+use crate::object::{ObjectFile, Symbol, Section};
+
+/* Turns VM bytecode back into Teflon assembly. Every instruction is decoded with the operand
+   layout from the instruction table, the same one the assembler encodes with, so the output
+   can be assembled again.
+
+ EX: 02 00 01 03 at address 4 => 0004: ADD $0 $1 $3
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct DisassembledInstruction {
+    pub address: usize,
+    pub label: Option<String>,      // the label declared at this address, if symbols are known
+    pub opcode: Opcode,
+    pub operands: Vec<Operand>,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for DisassembledInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}: ", self.address)?;
+        if let Some(label) = &self.label {
+            write!(f, "{}: ", label)?;
+        }
+        write!(f, "{}", self.opcode.mnemonic())?;
+        for operand in &self.operands {
+            match operand {
+                Operand::Register(register) => write!(f, " ${}", register)?,
+                Operand::FloatRegister(register) => write!(f, " $f{}", register)?,
+                Operand::Integer(value) => write!(f, " #{}", value)?,
+                Operand::Label(name) => write!(f, " @{}", name)?,
+                Operand::Expression(expression) => write!(f, " #{}", expression)?,
+            }
+        }
+        if self.opcode == Opcode::IGL {
+            // Illegal bytes are shown as a comment so the listing still assembles
+            let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            write!(f, " <{}>", bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// Disassembles every instruction in a program
+pub fn disassemble(program: &[u8]) -> Vec<DisassembledInstruction> {
+    disassemble_with_symbols(program, &[])
+}
+
+// Disassembles the code section of a program file, naming addresses with its symbols
+pub fn disassemble_object(object: &ObjectFile) -> Vec<DisassembledInstruction> {
+    let symbols = object.symbols.as_deref().unwrap_or(&[]);
+    disassemble_with_symbols(&object.code, symbols)
+}
+
+pub fn disassemble_with_symbols(program: &[u8], symbols: &[Symbol]) -> Vec<DisassembledInstruction> {
+    (0..program.len())
+        .step_by(INSTRUCTION_SIZE)
+        .map(|address| disassemble_instruction(program, address, symbols))
+        .collect()
+}
+
+// Disassembles the single instruction that starts at `address`
+pub fn disassemble_instruction(program: &[u8], address: usize, symbols: &[Symbol]) -> DisassembledInstruction {
+    let end = program.len().min(address + INSTRUCTION_SIZE);
+    let bytes = program[address.min(end)..end].to_vec();
+    let label = code_label(symbols, address);
+    let opcode = bytes.first().map(|byte| Opcode::from(*byte)).unwrap_or(Opcode::IGL);
+
+    let operands = match opcode.operands() {
+        Some(kinds) if bytes.len() == INSTRUCTION_SIZE => decode_operands(opcode, kinds, &bytes[1..], address, symbols),
+        _ => None,
+    };
+    match operands {
+        Some(operands) => DisassembledInstruction { address, label, opcode, operands, bytes },
+        None => DisassembledInstruction { address, label, opcode: Opcode::IGL, operands: vec![], bytes },
+    }
+}
+
+fn decode_operands(opcode: Opcode, kinds: &[OperandKind], mut bytes: &[u8], address: usize, symbols: &[Symbol]) -> Option<Vec<Operand>> {
+    let mut operands = vec![];
+    for kind in kinds {
+        if bytes.len() < kind.width() {
+            return None;
+        }
+        let value = bytes[..kind.width()].iter().fold(0, |value, byte| (value << 8) | *byte as i64);
+        bytes = &bytes[kind.width()..];
+
+        let operand = match kind {
+            OperandKind::Register => Operand::Register(value as u8),
+            OperandKind::FloatRegister => Operand::FloatRegister(value as u8),
+            OperandKind::Integer8 => Operand::Integer(value),
+            OperandKind::SignedInteger8 => Operand::Integer(value as u8 as i8 as i64),
+            OperandKind::Integer16 => Operand::Integer(value),
+            OperandKind::SignedInteger16 => Operand::Integer(value as u16 as i16 as i64),
+            OperandKind::Address | OperandKind::Offset => {
+                let next = (address + INSTRUCTION_SIZE) as i64;
+                let target = match (kind, opcode) {
+                    (OperandKind::Address, _) => Some(value),
+                    (OperandKind::Offset, Opcode::JMPFI) => Some(next + value),
+                    (OperandKind::Offset, Opcode::JMPBI) => Some(next - value),
+                    _ => None,
+                };
+                match target.and_then(|target| code_label(symbols, target as usize)) {
+                    Some(label) => Operand::Label(label),
+                    None => Operand::Integer(value),
+                }
+            },
+            OperandKind::DataAddress => match label(symbols, Section::ReadOnlyData, value as usize) {
+                Some(label) => Operand::Label(label),
+                None => Operand::Integer(value),
+            },
+        };
+        operands.push(operand);
+    }
+    Some(operands)
+}
+
+fn code_label(symbols: &[Symbol], address: usize) -> Option<String> {
+    label(symbols, Section::Code, address)
+}
+
+fn label(symbols: &[Symbol], section: Section, address: usize) -> Option<String> {
+    symbols.iter()
+        .find(|symbol| symbol.section == section && symbol.address as usize == address)
+        .map(|symbol| symbol.name.clone())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn listing(instructions: &[DisassembledInstruction]) -> Vec<String> {
+        instructions.iter().map(|instruction| instruction.to_string()).collect()
+    }
+
+    #[test]
+    fn test_disassemble_instructions() {
+        let program = vec![1, 1, 3, 232, 2, 0, 1, 3, 6, 4, 0, 0, 0, 0, 0, 0];
+        assert_eq!(listing(&disassemble(&program)), vec![
+            "0000: LOAD $1 #1000",
+            "0004: ADD $0 $1 $3",
+            "0008: JMP $4",
+            "000C: HLT",
+        ]);
+    }
+
+    #[test]
+    fn test_disassemble_illegal_bytes() {
+        let program = vec![200, 0, 0, 0, 1, 1];
+        let instructions = disassemble(&program);
+        assert_eq!(instructions[0].opcode, Opcode::IGL);
+        assert_eq!(listing(&instructions), vec![
+            "0000: IGL <C8 00 00 00>",
+            "0004: IGL <01 01>",
+        ]);
+    }
+
+    #[test]
+    fn test_disassemble_with_symbols() {
+        let source = "main: LOAD $1 #2\nloop: JMPF @end\nJMPB @loop\nend: JNEQ $1 @loop";
+        let object = Assembler::new().assemble_object(source).unwrap();
+        assert_eq!(listing(&disassemble_object(&object)), vec![
+            "0000: main: LOAD $1 #2",
+            "0004: loop: JMPFI @end",
+            "0008: JMPBI @loop",
+            "000C: end: JNEQI $1 @loop",
+        ]);
+        let object = Assembler::new().assemble_object("LOADF $f1 @half\nHLT\n.data\n.byte 1\nhalf: .double 0.5").unwrap();
+        assert_eq!(listing(&disassemble_object(&object))[0], "0000: LOADF $f1 @half");
+    }
+
+    #[test]
+    fn test_disassembly_reassembles() {
+        let source = "LOAD $1 #-10\nloop: STOREM $1 $2 #4\nCALL @loop\nAND $1 $2 $3\nNOT $3 $4\nSAR $4 $1 $5\nMOD $5 $2 $6\nGETREM $7\nITOF $1 $f2\nDIVF $f2 $f0 $f31\nADDI $1 #-128 $2\nMOV $2 $3\nCMPI $3 #-300\nRET";
+        let mut assembler = Assembler::new();
+        let program = assembler.assemble(source).unwrap();
+        let text: Vec<String> = disassemble(&program).iter()
+            .map(|instruction| instruction.to_string()[6..].to_string())
+            .collect();
+        assert_eq!(assembler.assemble(&text.join("\n")), Ok(program));
+    }
+}
@@ -1,27 +1,263 @@
-/**
- * An opcode is the first byte of an instruction in machine language which tells
- *  the hardware what operation needs to be performed with this instruction
- */
+use std::str::FromStr;
+
+// The kinds of operands an instruction takes, in the order they are encoded after the opcode
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    Register,       // one byte holding the register number
+    FloatRegister,  // one byte holding the number of a floating point register
+    Integer8,       // one unsigned byte
+    SignedInteger8, // one signed byte
+    Integer16,      // two unsigned bytes, high byte first. A label is replaced by its address
+    SignedInteger16, // two bytes holding a signed number, high byte first
+    Address,        // an absolute program address, encoded like Integer16
+    Offset,         // a distance from the next instruction, encoded like Integer16
+    DataAddress,    // an offset into the read-only data, encoded like Integer16
+}
+
+impl OperandKind {
+    // The number of bytes the operand takes up in an instruction
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::Integer8 | OperandKind::SignedInteger8 => 1,
+            OperandKind::Integer16 | OperandKind::SignedInteger16 | OperandKind::Address | OperandKind::Offset | OperandKind::DataAddress => 2,
+        }
+    }
+
+    // The smallest and largest integer the operand can hold
+    pub fn range(self) -> (i64, i64) {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::Integer8 => (0, u8::MAX as i64),
+            OperandKind::SignedInteger8 => (i8::MIN as i64, i8::MAX as i64),
+            OperandKind::SignedInteger16 => (i16::MIN as i64, i16::MAX as i64),
+            OperandKind::Integer16 | OperandKind::Address | OperandKind::Offset | OperandKind::DataAddress => (0, u16::MAX as i64),
+        }
+    }
+
+    // How the operand is written in assembly
+    pub fn syntax(self) -> &'static str {
+        match self {
+            OperandKind::Register => "$reg",
+            OperandKind::FloatRegister => "$freg",
+            OperandKind::Integer8 => "#imm8",
+            OperandKind::SignedInteger8 => "#simm8",
+            OperandKind::Integer16 => "#imm16",
+            OperandKind::SignedInteger16 => "#simm16",
+            OperandKind::Address => "@label",
+            OperandKind::Offset => "@label",
+            OperandKind::DataAddress => "@data",
+        }
+    }
+}
+
+// What an instruction does with a register operand. Operands that are not registers are only
+// ever read
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn reads(self) -> bool {
+        self != Access::Write
+    }
+
+    pub fn writes(self) -> bool {
+        self != Access::Read
+    }
+}
+
+// Everything there is to know about one instruction
 #[derive(Debug, PartialEq)]
-pub enum Opcode {
-    HLT,        // HALT
-    LOAD,       // Load variable into register
-    ADD,        // Add
-    SUB,        // Subtract
-    MUL,        // Multiply
-    DIV,        // Divide
-    JMP,        // Absolute Jump (changes the program counter relative to the VM)
-    JMPF,       // Relative Jump Forward (changes the program counter relative to the current instruction)
-    JMPB,       // Relative Jump backward
-    EQ,         // Equal
-    NEQ,        // Not Equal
-    GT,         // Greater Then
-    LT,         // Less Then
-    GQT,        // Greater or equal to
-    LQT,        // Less then or equal to
-    JEQ,        // Jump if equal to
-    JNEQ,       // Jump if not equal to
-    IGL,        // Illegal opcode
+pub struct InstructionInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub byte: u8,
+    pub operands: &'static [OperandKind],
+    pub access: &'static [Access],     // what the instruction does with each operand
+    pub description: &'static str,
+}
+
+// An operand is read unless its row says otherwise
+macro_rules! access {
+    () => { Access::Read };
+    ($access:ident) => { Access::$access };
+}
+
+/* Declares the instruction set. Every row becomes a variant of `Opcode` and an entry in
+   `INSTRUCTIONS`, and `From<u8>`, `FromStr` and the operand layout are all derived from it.
+   Operands the instruction writes to are marked `: Write`, or `: ReadWrite` when it also
+   reads them:
+
+    NAME = byte, [operand kinds], "description";
+    EX: MOV = 61, [Register, Register: Write], "...";
+*/
+macro_rules! instruction_set {
+    ($( $name:ident = $byte:expr, [$($kind:ident $(: $access:ident)?),*], $description:expr; )*) => {
+        /**
+         * An opcode is the first byte of an instruction in machine language which tells
+         *  the hardware what operation needs to be performed with this instruction
+         */
+        #[derive(Debug, PartialEq, Clone, Copy)]
+        pub enum Opcode {
+            $( $name, )*
+            IGL,        // Illegal opcode
+        }
+
+        pub const INSTRUCTIONS: &[InstructionInfo] = &[
+            $( InstructionInfo {
+                opcode: Opcode::$name,
+                mnemonic: stringify!($name),
+                byte: $byte,
+                operands: &[$(OperandKind::$kind),*],
+                access: &[$(access!($($access)?)),*],
+                description: $description,
+            }, )*
+        ];
+
+        impl From<u8> for Opcode {
+            fn from(v: u8) -> Self {
+                match v {
+                    $( $byte => Opcode::$name, )*
+                    _ => Opcode::IGL,
+                }
+            }
+        }
+
+        // Parses an assembly mnemonic (case insensitive) into its opcode
+        impl FromStr for Opcode {
+            type Err = ();
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s.to_uppercase().as_str() {
+                    $( stringify!($name) => Ok(Opcode::$name), )*
+                    _ => Err(()),
+                }
+            }
+        }
+    };
+}
+
+instruction_set! {
+    HLT = 0, [], "Halts the VM";
+    LOAD = 1, [Register: Write, Integer16], "Loads an unsigned 16 bit number into a register";
+    ADD = 2, [Register, Register, Register: Write], "Adds two registers and stores the result in the third. Sets the flags";
+    SUB = 3, [Register, Register, Register: Write], "Subtracts the second register from the first and stores the result in the third. Sets the flags";
+    MUL = 4, [Register, Register, Register: Write], "Multiplies two registers and stores the result in the third. Sets the flags";
+    DIV = 5, [Register, Register, Register: Write], "Divides the first register by the second, stores the quotient in the third and keeps the remainder. Sets the flags";
+    JMP = 6, [Register], "Absolute jump to the address in a register";
+    JMPF = 7, [Register], "Relative jump forward by the value in a register";
+    JMPB = 8, [Register], "Relative jump backward by the value in a register";
+    EQ = 9, [Register, Register, Register: Write], "Stores 1 in the third register if the first two are equal, otherwise 0";
+    GT = 10, [Register, Register, Register: Write], "Stores 1 in the third register if the first is greater than the second, otherwise 0";
+    LT = 11, [Register, Register, Register: Write], "Stores 1 in the third register if the first is less than the second, otherwise 0";
+    GQT = 12, [Register, Register, Register: Write], "Stores 1 in the third register if the first is greater than or equal to the second, otherwise 0";
+    LQT = 13, [Register, Register, Register: Write], "Stores 1 in the third register if the first is less than or equal to the second, otherwise 0";
+    JEQ = 14, [Register, Register], "Jumps to the address in the second register if the first register is 1";
+    JNEQ = 15, [Register, Register], "Jumps to the address in the second register if the first register is 0";
+    JMPI = 16, [Address], "Absolute jump to an immediate address";
+    JMPFI = 17, [Offset], "Relative jump forward by an immediate offset from the next instruction";
+    JMPBI = 18, [Offset], "Relative jump backward by an immediate offset from the next instruction";
+    JEQI = 19, [Register, Address], "Jumps to an immediate address if the register is 1";
+    JNEQI = 20, [Register, Address], "Jumps to an immediate address if the register is 0";
+    PUSH = 21, [Register], "Pushes a register onto the stack";
+    POP = 22, [Register: Write], "Pops the top of the stack into a register";
+    CALL = 23, [Address], "Pushes a new frame and calls the subroutine at an immediate address";
+    RET = 24, [], "Discards the current frame and returns to the caller";
+    ALOC = 25, [Register], "Grows the heap by the number of bytes in a register";
+    LOADB = 26, [Register: Write, Register, Integer8], "Loads the 8 bits at base register + offset from the heap into a register";
+    LOADH = 27, [Register: Write, Register, Integer8], "Loads the 16 bits at base register + offset from the heap into a register";
+    LOADM = 28, [Register: Write, Register, Integer8], "Loads the 32 bits at base register + offset from the heap into a register";
+    STOREB = 29, [Register, Register, Integer8], "Stores the low 8 bits of a register at base register + offset in the heap";
+    STOREH = 30, [Register, Register, Integer8], "Stores the low 16 bits of a register at base register + offset in the heap";
+    STOREM = 31, [Register, Register, Integer8], "Stores a register at base register + offset in the heap";
+    NEQ = 32, [Register, Register, Register: Write], "Stores 1 in the third register if the first two are not equal, otherwise 0";
+    SYSCALL = 33, [Register], "Makes the system call numbered by a register; the argument and result are in $0";
+    AND = 34, [Register, Register, Register: Write], "Stores the bitwise AND of two registers in the third";
+    OR = 35, [Register, Register, Register: Write], "Stores the bitwise OR of two registers in the third";
+    XOR = 36, [Register, Register, Register: Write], "Stores the bitwise exclusive OR of two registers in the third";
+    NOT = 37, [Register, Register: Write], "Stores the bitwise complement of the first register in the second";
+    SHL = 38, [Register, Register, Register: Write], "Shifts the first register left by the low 5 bits of the second and stores the result in the third";
+    SHR = 39, [Register, Register, Register: Write], "Shifts the first register right by the low 5 bits of the second, filling with zeros, and stores the result in the third";
+    SAR = 40, [Register, Register, Register: Write], "Shifts the first register right by the low 5 bits of the second, filling with its sign, and stores the result in the third";
+    MOD = 41, [Register, Register, Register: Write], "Stores the remainder of dividing the first register by the second in the third";
+    GETREM = 42, [Register: Write], "Copies the remainder of the last DIV into a register";
+    JZ = 43, [Address], "Jumps to an immediate address if the last arithmetic result was zero";
+    JNZ = 44, [Address], "Jumps to an immediate address if the last arithmetic result was not zero";
+    JN = 45, [Address], "Jumps to an immediate address if the last arithmetic result was negative";
+    JO = 46, [Address], "Jumps to an immediate address if the last arithmetic instruction overflowed";
+    JC = 47, [Address], "Jumps to an immediate address if the last arithmetic instruction carried or borrowed";
+    LOADF = 48, [FloatRegister: Write, DataAddress], "Loads the 64 bit float at an offset into the read-only data into a float register";
+    ADDF = 49, [FloatRegister, FloatRegister, FloatRegister: Write], "Adds two float registers and stores the result in the third";
+    SUBF = 50, [FloatRegister, FloatRegister, FloatRegister: Write], "Subtracts the second float register from the first and stores the result in the third";
+    MULF = 51, [FloatRegister, FloatRegister, FloatRegister: Write], "Multiplies two float registers and stores the result in the third";
+    DIVF = 52, [FloatRegister, FloatRegister, FloatRegister: Write], "Divides the first float register by the second and stores the result in the third";
+    EQF = 53, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the two float registers are equal, otherwise 0";
+    NEQF = 54, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the two float registers are not equal, otherwise 0";
+    GTF = 55, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the first float register is greater than the second, otherwise 0";
+    LTF = 56, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the first float register is less than the second, otherwise 0";
+    GQTF = 57, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the first float register is greater than or equal to the second, otherwise 0";
+    LQTF = 58, [FloatRegister, FloatRegister, Register: Write], "Stores 1 in the register if the first float register is less than or equal to the second, otherwise 0";
+    ITOF = 59, [Register, FloatRegister: Write], "Converts a register to a float and stores it in a float register";
+    FTOI = 60, [FloatRegister, Register: Write], "Converts a float register to an integer, rounding toward zero and clamping, and stores it in a register";
+    MOV = 61, [Register, Register: Write], "Copies the first register into the second";
+    ADDI = 62, [Register, SignedInteger8, Register: Write], "Adds a signed 8 bit immediate to a register and stores the result in the second register. Sets the flags";
+    SUBI = 63, [Register, SignedInteger8, Register: Write], "Subtracts a signed 8 bit immediate from a register and stores the result in the second register. Sets the flags";
+    MULI = 64, [Register, SignedInteger8, Register: Write], "Multiplies a register by a signed 8 bit immediate and stores the result in the second register. Sets the flags";
+    INC = 65, [Register: ReadWrite], "Adds 1 to a register. Sets the flags";
+    DEC = 66, [Register: ReadWrite], "Subtracts 1 from a register. Sets the flags";
+    CMP = 67, [Register, Register], "Sets the flags from subtracting the second register from the first, without storing the result";
+    CMPI = 68, [Register, SignedInteger16], "Sets the flags from subtracting a signed 16 bit immediate from a register, without storing the result";
+    LOADS = 69, [Register: Write, SignedInteger16], "Loads a signed 16 bit number into a register";
+}
+
+impl Opcode {
+    // The table entry for an opcode. IGL is the only opcode without one
+    pub fn info(self) -> Option<&'static InstructionInfo> {
+        INSTRUCTIONS.iter().find(|info| info.opcode == self)
+    }
+
+    // The operands the opcode takes, or None if it can not be encoded
+    pub fn operands(self) -> Option<&'static [OperandKind]> {
+        self.info().map(|info| info.operands)
+    }
+
+    // Integer registers the instruction uses without naming them as operands. System calls
+    // take their argument in $0 and return their result there
+    pub fn implicit_registers(self) -> &'static [(usize, Access)] {
+        match self {
+            Opcode::SYSCALL => &[(0, Access::ReadWrite)],
+            _ => &[],
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        self.info().map(|info| info.mnemonic).unwrap_or("IGL")
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> Self {
+        opcode.info().map(|info| info.byte).unwrap_or(u8::MAX)
+    }
+}
+
+// Renders the instruction set reference in docs/ISA.md
+pub fn isa_reference() -> String {
+    let mut reference = String::from("# Teflon Instruction Set\n\n");
+    reference.push_str("Generated from the instruction table in `src/instructions.rs` (`teflon isa`).\n");
+    reference.push_str("Every instruction is 4 bytes: the opcode byte, its operands and zero padding.\n\n");
+    reference.push_str("| Byte | Mnemonic | Operands | Description |\n");
+    reference.push_str("| ---- | -------- | -------- | ----------- |\n");
+    for info in INSTRUCTIONS {
+        let operands: Vec<&str> = info.operands.iter().map(|kind| kind.syntax()).collect();
+        reference.push_str(&format!(
+            "| 0x{:02X} | {} | {} | {} |\n",
+            info.byte, info.mnemonic, operands.join(" "), info.description
+        ));
+    }
+    reference
 }
 
 
@@ -32,6 +268,7 @@ pub struct Instruction {
 }
 
 impl Instruction {
+    #[allow(dead_code)]
     fn new(opcode: Opcode) -> Instruction {
         Instruction {
             opcode
@@ -39,31 +276,6 @@ impl Instruction {
     }
 }
 
-impl From<u8> for Opcode {
-    fn from(v: u8) -> Self {
-        match v {
-            0 => Opcode::HLT,
-            1 => Opcode::LOAD,
-            2 => Opcode::ADD,
-            3 => Opcode::SUB,
-            4 => Opcode::MUL,
-            5 => Opcode::DIV,
-            6 => Opcode::JMP,
-            7 => Opcode::JMPF,
-            8 => Opcode::JMPB,
-            9 => Opcode::EQ,
-            10 => Opcode::GT,
-            11 => Opcode::LT,
-            12 => Opcode::GQT,
-            13 => Opcode::LQT,
-            14 => Opcode::JEQ,
-            15 => Opcode::JNEQ,
-            _ => Opcode::IGL,
-        }
-    }
-}
-
-
 
 #[cfg(test)]
 mod test {
@@ -81,4 +293,43 @@ mod test {
 
         assert_eq!(instruction.opcode, Opcode::HLT);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_mnemonic() {
+        assert_eq!("load".parse::<Opcode>(), Ok(Opcode::LOAD));
+        assert_eq!("JNEQ".parse::<Opcode>(), Ok(Opcode::JNEQ));
+        assert_eq!("FOO".parse::<Opcode>(), Err(()));
+        assert_eq!("IGL".parse::<Opcode>(), Err(()));
+    }
+
+    #[test]
+    fn opcode_byte_round_trip() {
+        for info in INSTRUCTIONS {
+            assert_eq!(Opcode::from(info.byte), info.opcode);
+            assert_eq!(u8::from(info.opcode), info.byte);
+            assert_eq!(info.mnemonic.parse::<Opcode>(), Ok(info.opcode));
+        }
+        assert_eq!(Opcode::from(255), Opcode::IGL);
+    }
+
+    #[test]
+    fn every_instruction_fits_in_four_bytes() {
+        for info in INSTRUCTIONS {
+            let width: usize = info.operands.iter().map(|kind| kind.width()).sum();
+            // The opcode itself takes the first of the 4 bytes
+            assert!(width < 4, "{} is too wide", info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn bytes_are_unique() {
+        for (i, info) in INSTRUCTIONS.iter().enumerate() {
+            assert!(INSTRUCTIONS[i + 1..].iter().all(|other| other.byte != info.byte), "{} reuses a byte", info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn isa_reference_is_up_to_date() {
+        assert_eq!(include_str!("../docs/ISA.md"), isa_reference());
+    }
+}
@@ -1,55 +1,91 @@
 pub mod token;
 use token::{ Token, TokenType, Error, LexerError };
-use std::fs::File;
-use std::io::BufReader;
-use std::io::prelude::*;
+use crate::diagnostic::Span;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 #[derive(Debug, PartialEq)]
 pub struct Lexer {
     state: State,
     val: String,
+    file: usize,        // the id of the source file every span points into
+    column: usize,      // the byte column of the character being lexed
+    start: usize,       // the byte column the current token started at
+    end: usize,         // the byte column just after the last character of the current token
     pub tokens: Vec<Token>,
-    errors: Vec<LexerError>
+    pub errors: Vec<LexerError>
 }    
     
 #[derive(Debug, PartialEq)]  
 pub enum State {
     S,      // S => No pattern has been detected
-    D,      // D => part of a number has been detected
+    D,      // D => part of a decimal number has been detected
+    N,      // N => a `-` sign has been detected, which negates what follows if it is not a digit
+    Z,      // Z => a leading zero has been detected, which may start a `0x` or `0b` prefix
+    X,      // X => part of a hex number has been detected
+    B,      // B => part of a binary number has been detected
+    F,      // F => the fraction of a decimal number has been detected
+    I,      // I => a number with an invalid digit has been detected
     O,      // O => part of a opcode has been detected
     C,      // C => part of a comment has been detected
+    L,      // L => part of a label usage has been detected
+    P,      // P => part of a directive (`.name`) has been detected
+    Q,      // Q => part of a character literal has been detected
+    QE,     // QE => a `\` escape inside a character literal has been detected
+    T,      // T => part of a string literal has been detected
+    TE,     // TE => a `\` escape inside a string literal has been detected
 }
 
 
+impl Default for Lexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Lexer {
     pub fn new() -> Lexer {
+        Lexer::for_file(0)
+    }
+
+    // A lexer whose spans point into the source file with the given id
+    pub fn for_file(file: usize) -> Lexer {
         Lexer {
             state: State::S,
             val: String::from(""),
+            file,
+            column: 0,
+            start: 0,
+            end: 0,
             tokens: Vec::new(),
             errors: Vec::new(),
         }
     }
 
-    pub fn lex_file(&mut self, path: String) {
-        let f = File::open(path).expect("File does not exist");
-        let buf_reader = BufReader::new(f);        
-        let mut line_number = 1;
+    // Lex's every line of a file and returns its text, so diagnostics can show it
+    pub fn lex_file(&mut self, path: &Path) -> io::Result<String> {
+        let text = fs::read_to_string(path)?;
+        self.lex_source(&text);
+        Ok(text)
+    }
 
-        for line in buf_reader.lines() {
-            match line {
-                Ok(line) => self.lex_line(&line, line_number),
-                Err(e) => panic!("Error reading line: {}", e),
-            }
+    // Lex's every line of an in memory source string and terminates the tokens with an EOF
+    pub fn lex_source(&mut self, source: &str) {
+        let mut line_number = 1;
+        for line in source.lines() {
+            self.lex_line(line, line_number);
             line_number += 1;
         }
-        self.tokens.push(Token::new(TokenType::EOF, line_number));
+        self.add_eof(line_number);
     }
 
     pub fn lex_line(&mut self, line: &str, line_number: usize) {
-        let mut it = line.chars().peekable();
-        while let Some(val) = it.next() {
-            if it.peek() == None {
+        let mut it = line.char_indices().peekable();
+        while let Some((column, val)) = it.next() {
+            self.column = column;
+            if it.peek().is_none() {
                 self.final_iteration(val, line_number);
             } else {
                 self.next_state(val, line_number);
@@ -68,19 +104,24 @@ impl Lexer {
     // Lex's a single line. This function is just used for testing
     fn lex_single_line(&mut self, line: &str) {
         self.lex_line(line, 1);
-        self.tokens.push(Token::new(TokenType::EOF, 1));
+        self.add_eof(1);
     }
 
     fn next_state(&mut self, c: char, line_number: usize) {
         match self.state {
             State::S => self.s_state_transition(c, line_number),
-            State::D => self.d_state_transition(c, line_number),
+            State::D | State::N | State::Z | State::X | State::B | State::F | State::I => self.number_state_transition(c, line_number),
             State::O => self.o_state_transition(c, line_number),
             State::C => self.c_state_transition(c),
+            State::L => self.l_state_transition(c, line_number),
+            State::P => self.p_state_transition(c, line_number),
+            State::Q | State::QE | State::T | State::TE => self.literal_state_transition(c, line_number),
         }
     }
 
     fn s_state_transition(&mut self, c: char, line: usize) {
+        self.start = self.column;
+        self.end = self.column + c.len_utf8();
         match c {
             'a'..='z' | 'A'..='Z' => {
                 self.val.push(c);
@@ -90,35 +131,133 @@ impl Lexer {
                 self.val.push(c);
                 self.state = State::D;
             },
+            '0' => {
+                self.val.push(c);
+                self.state = State::Z;
+            },
+            // After an operand a `-` subtracts, anywhere else it is the sign of a number
+            '-' if self.follows_operand(line) => self.add_token(TokenType::OPERATOR(c), line),
+            '-' => {
+                self.val.push(c);
+                self.state = State::N;
+            },
+            '+' | '*' | '/' | '&' | '|' | '^' | '~' | '(' | ')' => self.add_token(TokenType::OPERATOR(c), line),
+            '\'' => self.state = State::Q,
+            '"' => self.state = State::T,
             '$' => self.add_token(TokenType::REGISTER, line),
             '#' => self.add_token(TokenType::IntOperand, line),
+            '@' => self.state = State::L,
+            '.' => self.state = State::P,
+            ',' => self.add_token(TokenType::COMMA, line),
             '<' => self.state = State::C,
             '\n' | '\r' | ' ' => (),
-            '>' => self.errors.push(LexerError::new(Error::CommentError(line))),
-            _ => self.errors.push(LexerError::new(Error::TokenError(line, c))),
+            '>' => self.errors.push(LexerError::new(Error::CommentError(self.span(line)))),
+            _ => self.errors.push(LexerError::new(Error::TokenError(self.span(line), c))),
         }
     }
 
-    // A opcode has been detected
+    // A opcode has been detected. A trailing `:` turns it into a label declaration
     fn o_state_transition(&mut self, c: char, line: usize) {
         match c {
-            'a'..='z' | 'A'..='Z' => self.val.push(c),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => self.push(c),
+            ':' => {
+                self.end = self.column + 1;
+                self.add_token(TokenType::LabelDeclaration(self.val.clone()), line);
+                self.reset_values();
+            },
             _ => self.reset_and_add_token(TokenType::OPCODE(self.val.clone()), line, c),
         }
     }
 
-    // An integer has been detected
-    fn d_state_transition(&mut self, c: char, line: usize) {
+    // A label usage (`@name`) has been detected
+    fn l_state_transition(&mut self, c: char, line: usize) {
         match c {
-            '0'..='9' => self.val.push(c),
-            _ => self.reset_and_add_token(TokenType::NUMBER(self.val.clone()), line, c),
+            'a'..='z' | 'A'..='Z' | '_' => self.push(c),
+            '0'..='9' if !self.val.is_empty() => self.push(c),
+            _ => {
+                self.add_label_usage(line);
+                self.reset_values();
+                self.next_state(c, line);
+            },
         }
     }
 
-    fn c_state_transition(&mut self, c: char) {
+    // A directive (`.name`) has been detected
+    fn p_state_transition(&mut self, c: char, line: usize) {
         match c {
-            '>' => self.state = State::S,
-            _ => (),
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => self.push(c),
+            _ => {
+                self.add_directive(line);
+                self.reset_values();
+                self.next_state(c, line);
+            },
+        }
+    }
+
+    /* An integer has been detected:
+
+        -?[1-9][0-9]*       decimal
+        -?0[0-9]*           decimal with leading zeros
+        -?0[xX][0-9a-fA-F]+ hex
+        -?0[bB][01]+        binary
+        -?[0-9]+\.[0-9]*    float
+    */
+    fn number_state_transition(&mut self, c: char, line: usize) {
+        let next = match (&self.state, c) {
+            (State::N, '0') => State::Z,
+            (State::N, '1'..='9') => State::D,
+            (State::N, _) => {
+                // A `-` that is not followed by a digit negates what comes after it
+                self.add_token(TokenType::OPERATOR('-'), line);
+                self.reset_values();
+                self.next_state(c, line);
+                return;
+            },
+            (State::Z, 'x') | (State::Z, 'X') => State::X,
+            (State::Z, 'b') | (State::Z, 'B') => State::B,
+            (State::Z, '0'..='9') | (State::D, '0'..='9') => State::D,
+            (State::Z, '.') | (State::D, '.') | (State::F, '0'..='9') => State::F,
+            (State::X, '0'..='9' | 'a'..='f' | 'A'..='F') => State::X,
+            (State::B, '0' | '1') => State::B,
+            (_, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_') => State::I,
+            _ => {
+                self.add_number(line);
+                self.reset_values();
+                self.next_state(c, line);
+                return;
+            },
+        };
+        self.push(c);
+        self.state = next;
+    }
+
+    // A character (`'a'`) or string (`"abc"`) literal has been detected. The raw text between
+    // the quotes is collected and its escapes decoded once the closing quote is found
+    fn literal_state_transition(&mut self, c: char, line: usize) {
+        self.push(c);
+        self.state = match (&self.state, c) {
+            (State::Q, '\\') => State::QE,
+            (State::T, '\\') => State::TE,
+            (State::QE, _) => State::Q,
+            (State::TE, _) => State::T,
+            (State::Q, '\'') => {
+                self.add_char(line);
+                State::S
+            },
+            (State::T, '"') => {
+                self.add_string(line);
+                State::S
+            },
+            (state, _) => if *state == State::Q { State::Q } else { State::T },
+        };
+        if self.state == State::S {
+            self.reset_values();
+        }
+    }
+
+    fn c_state_transition(&mut self, c: char) {
+        if c == '>' {
+            self.state = State::S;
         }
     }
 
@@ -126,23 +265,115 @@ impl Lexer {
         self.next_state(c, line);
         match self.state {
             State::S => (),
-            State::D => self.add_token(TokenType::NUMBER(self.val.clone()), line),
+            State::D | State::Z | State::X | State::B | State::F | State::I => self.add_number(line),
+            State::N => self.add_token(TokenType::OPERATOR('-'), line),
+            State::Q | State::QE | State::T | State::TE => {
+                let reason = String::from("Literal is never closed");
+                self.errors.push(LexerError::new(Error::LiteralError(self.span(line), reason)));
+            },
             State::O => self.add_token(TokenType::OPCODE(self.val.clone()), line),
+            State::L => self.add_label_usage(line),
+            State::P => self.add_directive(line),
             State::C => {
                 if c != '>' {
-                    self.errors.push(LexerError::new(Error::CommentError(line)))
+                    // The comment runs from its `<` to the end of the line
+                    self.end = self.column + c.len_utf8();
+                    self.errors.push(LexerError::new(Error::CommentError(self.span(line))))
                 }
-                ()
             },
         }
+        // Tokens never span lines, so the next line starts from a clean state
+        self.reset_values();
+    }
+
+    // Whether the last token on this line ends an operand of an expression
+    fn follows_operand(&self, line: usize) -> bool {
+        match self.tokens.last() {
+            Some(token) if token.span.same_line(self.span(line)) => matches!(
+                token.token,
+                TokenType::NUMBER(_) | TokenType::CHAR(_) | TokenType::OPCODE(_) | TokenType::LabelUsage(_) | TokenType::OPERATOR(')')
+            ),
+            _ => false,
+        }
+    }
+
+    // A `@` must be followed by the name of a label
+    fn add_label_usage(&mut self, line: usize) {
+        if self.val.is_empty() {
+            self.errors.push(LexerError::new(Error::TokenError(self.span(line), '@')));
+        } else {
+            self.add_token(TokenType::LabelUsage(self.val.clone()), line);
+        }
+    }
+
+    // A `.` must be followed by the name of a directive
+    fn add_directive(&mut self, line: usize) {
+        if self.val.is_empty() {
+            self.errors.push(LexerError::new(Error::TokenError(self.span(line), '.')));
+        } else {
+            self.add_token(TokenType::DIRECTIVE(self.val.clone()), line);
+        }
+    }
+
+    fn add_number(&mut self, line: usize) {
+        if self.state == State::F {
+            match self.val.parse::<f64>() {
+                Ok(value) => self.add_token(TokenType::FLOAT(value), line),
+                Err(_) => self.errors.push(LexerError::new(Error::NumberError(self.span(line), self.val.clone()))),
+            }
+            return;
+        }
+        match decode_integer(&self.val) {
+            Some(value) => self.add_token(TokenType::NUMBER(value), line),
+            None => self.errors.push(LexerError::new(Error::NumberError(self.span(line), self.val.clone()))),
+        }
+    }
+
+    // The collected text ends with the closing quote
+    fn add_char(&mut self, line: usize) {
+        let raw = &self.val[..self.val.len() - 1];
+        let decoded = unescape(raw).and_then(|text| {
+            let mut chars = text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(String::from("Character literal must hold exactly one character")),
+            }
+        });
+        match decoded {
+            Ok(c) => self.add_token(TokenType::CHAR(c), line),
+            Err(reason) => self.errors.push(LexerError::new(Error::LiteralError(self.span(line), reason))),
+        }
+    }
+
+    fn add_string(&mut self, line: usize) {
+        let raw = &self.val[..self.val.len() - 1];
+        match unescape(raw) {
+            Ok(text) => self.add_token(TokenType::STRING(text), line),
+            Err(reason) => self.errors.push(LexerError::new(Error::LiteralError(self.span(line), reason))),
+        }
+    }
+
+    // Adds a character to the current token
+    fn push(&mut self, c: char) {
+        self.val.push(c);
+        self.end = self.column + c.len_utf8();
+    }
+
+    // The span of the current token
+    fn span(&self, line: usize) -> Span {
+        Span::new(self.file, line, self.start, self.end)
     }
 
     fn add_token(&mut self, token_type: TokenType, line: usize) {
-        self.tokens.push(Token::new(token_type, line));
+        self.tokens.push(Token::new(token_type, self.span(line)));
+    }
+
+    fn add_eof(&mut self, line: usize) {
+        self.tokens.push(Token::new(TokenType::EOF, Span::new(self.file, line, 0, 0)));
     }
 
     fn reset_and_add_token(&mut self, token_type: TokenType, line: usize, c: char) {
-        self.tokens.push(Token::new(token_type, line));
+        self.add_token(token_type, line);
         self.reset_values();
         self.next_state(c, line);
     }
@@ -153,16 +384,48 @@ impl Lexer {
     }
 }
 
-pub fn makeString(expr:&str) -> String{
-    return expr.into()
+// Decodes a signed decimal, `0x` hex or `0b` binary integer, or None if it is malformed or
+// does not fit in 64 bits
+fn decode_integer(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") | Some("0X") => (16, &digits[2..]),
+        Some("0b") | Some("0B") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+    let magnitude = i128::from_str_radix(digits, radix).ok()?;
+    i64::try_from(if negative { -magnitude } else { magnitude }).ok()
 }
 
+// Replaces the escapes in the text of a literal: \n \t \r \0 \\ \' and \"
+fn unescape(raw: &str) -> Result<String, String> {
+    let mut text = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        text.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '\'' | '"')) => c,
+            Some(c) => return Err(format!("Unknown escape `\\{}`", c)),
+            None => return Err(String::from("Literal ends with a `\\`")),
+        });
+    }
+    Ok(text)
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[macro_use]
     macro_rules! to_String {
         ($e:expr) => {
             String::from($e)
@@ -170,19 +433,23 @@ mod test {
     }
 
 
+    fn types(lexer: &Lexer) -> Vec<TokenType> {
+        lexer.tokens.iter().map(|token| token.token.clone()).collect()
+    }
+
     #[test]
     fn test_get_lexemes_for_load_instruction() {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("LOAD $1 #1000");
         let tokens = vec![
-            Token::new(TokenType::OPCODE(to_String!("LOAD")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("1")), 1),
-            Token::new(TokenType::IntOperand, 1),
-            Token::new(TokenType::NUMBER(to_String!("1000")), 1),
-            Token::new(TokenType::EOF, 1),
+            TokenType::OPCODE(to_String!("LOAD")),
+            TokenType::REGISTER,
+            TokenType::NUMBER(1),
+            TokenType::IntOperand,
+            TokenType::NUMBER(1000),
+            TokenType::EOF,
         ];
-        assert_eq!(test_lexer.tokens, tokens);
+        assert_eq!(types(&test_lexer), tokens);
     }
 
     #[test]
@@ -190,16 +457,16 @@ mod test {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("ADD $11 $2 $3");
         let tokens = vec![
-            Token::new(TokenType::OPCODE(to_String!("ADD")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("11")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("2")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("3")), 1),
-            Token::new(TokenType::EOF, 1),
+            TokenType::OPCODE(to_String!("ADD")),
+            TokenType::REGISTER,
+            TokenType::NUMBER(11),
+            TokenType::REGISTER,
+            TokenType::NUMBER(2),
+            TokenType::REGISTER,
+            TokenType::NUMBER(3),
+            TokenType::EOF,
         ];
-        assert_eq!(test_lexer.tokens, tokens);
+        assert_eq!(types(&test_lexer), tokens);
     }
 
     #[test]
@@ -207,9 +474,9 @@ mod test {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("< Hello for a comment >");
         let tokens = vec![
-            Token::new(TokenType::EOF, 1),
+            TokenType::EOF,
         ];
-        assert_eq!(test_lexer.tokens, tokens);
+        assert_eq!(types(&test_lexer), tokens);
     }
 
     #[test]
@@ -217,16 +484,16 @@ mod test {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("ADD $11 $2 $3 <this code should work>");
         let tokens = vec![
-            Token::new(TokenType::OPCODE(to_String!("ADD")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("11")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("2")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("3")), 1),
-            Token::new(TokenType::EOF, 1),
+            TokenType::OPCODE(to_String!("ADD")),
+            TokenType::REGISTER,
+            TokenType::NUMBER(11),
+            TokenType::REGISTER,
+            TokenType::NUMBER(2),
+            TokenType::REGISTER,
+            TokenType::NUMBER(3),
+            TokenType::EOF,
         ];
-        assert_eq!(test_lexer.tokens, tokens);
+        assert_eq!(types(&test_lexer), tokens);
     }
 
     #[test]
@@ -234,17 +501,17 @@ mod test {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("ADD $11 $2% $3 <this code should work>");
         let tokens = vec![
-            Token::new(TokenType::OPCODE(to_String!("ADD")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("11")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("2")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("3")), 1),
-            Token::new(TokenType::EOF, 1),
+            TokenType::OPCODE(to_String!("ADD")),
+            TokenType::REGISTER,
+            TokenType::NUMBER(11),
+            TokenType::REGISTER,
+            TokenType::NUMBER(2),
+            TokenType::REGISTER,
+            TokenType::NUMBER(3),
+            TokenType::EOF,
         ];
-        let errors = vec![LexerError::new(Error::TokenError(1, '%'))];
-        assert_eq!(test_lexer.tokens, tokens);
+        let errors = vec![LexerError::new(Error::TokenError(Span::new(0, 1, 10, 11), '%'))];
+        assert_eq!(types(&test_lexer), tokens);
         assert_eq!(test_lexer.errors, errors);
     }
 
@@ -253,18 +520,195 @@ mod test {
         let mut test_lexer = Lexer::new();
         test_lexer.lex_single_line("ADD $11 $2 $3 <this code should work");
         let tokens = vec![
-            Token::new(TokenType::OPCODE(to_String!("ADD")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("11")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("2")), 1),
-            Token::new(TokenType::REGISTER, 1),
-            Token::new(TokenType::NUMBER(to_String!("3")), 1),
-            Token::new(TokenType::EOF, 1),
+            TokenType::OPCODE(to_String!("ADD")),
+            TokenType::REGISTER,
+            TokenType::NUMBER(11),
+            TokenType::REGISTER,
+            TokenType::NUMBER(2),
+            TokenType::REGISTER,
+            TokenType::NUMBER(3),
+            TokenType::EOF,
+        ];
+        let errors = vec![LexerError::new(Error::CommentError(Span::new(0, 1, 14, 36)))];
+
+        assert_eq!(types(&test_lexer), tokens);
+        assert_eq!(test_lexer.errors, errors);
+    }
+
+    #[test]
+    fn test_label_declaration_and_usage() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("loop_2: JMP @loop_2");
+        let tokens = vec![
+            TokenType::LabelDeclaration(to_String!("loop_2")),
+            TokenType::OPCODE(to_String!("JMP")),
+            TokenType::LabelUsage(to_String!("loop_2")),
+            TokenType::EOF,
+        ];
+        assert_eq!(types(&test_lexer), tokens);
+    }
+
+    #[test]
+    fn test_label_usage_without_name() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("JMP @ $1");
+        let errors = vec![LexerError::new(Error::TokenError(Span::new(0, 1, 4, 5), '@'))];
+        assert_eq!(test_lexer.errors, errors);
+    }
+
+    #[test]
+    fn test_token_spans() {
+        let mut test_lexer = Lexer::for_file(2);
+        test_lexer.lex_source("HLT\nend: LOAD $12 #1000 <x> @end");
+        let spans: Vec<(usize, usize, usize)> = test_lexer.tokens.iter()
+            .map(|token| (token.span.line, token.span.start, token.span.end))
+            .collect();
+        assert_eq!(spans, vec![
+            (1, 0, 3),      // HLT
+            (2, 0, 4),      // end:
+            (2, 5, 9),      // LOAD
+            (2, 10, 11),    // $
+            (2, 11, 13),    // 12
+            (2, 14, 15),    // #
+            (2, 15, 19),    // 1000
+            (2, 24, 28),    // @end
+            (3, 0, 0),      // EOF
+        ]);
+        assert!(test_lexer.tokens.iter().all(|token| token.span.file == 2));
+    }
+
+    #[test]
+    fn test_integer_literals() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("$0 #-5 #0xFF #0B1010 #007 #-0x10");
+        let tokens = vec![
+            TokenType::REGISTER,
+            TokenType::NUMBER(0),
+            TokenType::IntOperand,
+            TokenType::NUMBER(-5),
+            TokenType::IntOperand,
+            TokenType::NUMBER(255),
+            TokenType::IntOperand,
+            TokenType::NUMBER(10),
+            TokenType::IntOperand,
+            TokenType::NUMBER(7),
+            TokenType::IntOperand,
+            TokenType::NUMBER(-16),
+            TokenType::EOF,
+        ];
+        assert_eq!(types(&test_lexer), tokens);
+        assert!(test_lexer.errors.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_integer_literals() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("#0b102 #0x #12ab #99999999999999999999");
+        let errors = vec![
+            LexerError::new(Error::NumberError(Span::new(0, 1, 1, 6), to_String!("0b102"))),
+            LexerError::new(Error::NumberError(Span::new(0, 1, 8, 10), to_String!("0x"))),
+            LexerError::new(Error::NumberError(Span::new(0, 1, 12, 16), to_String!("12ab"))),
+            LexerError::new(Error::NumberError(Span::new(0, 1, 18, 38), to_String!("99999999999999999999"))),
+        ];
+        assert_eq!(test_lexer.errors, errors);
+    }
+
+    #[test]
+    fn test_char_and_string_literals() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line(r#"#'a' #'\n' #'\'' "say \"hi\" <not a comment>\0""#);
+        let tokens = vec![
+            TokenType::IntOperand,
+            TokenType::CHAR('a'),
+            TokenType::IntOperand,
+            TokenType::CHAR('\n'),
+            TokenType::IntOperand,
+            TokenType::CHAR('\''),
+            TokenType::STRING(to_String!("say \"hi\" <not a comment>\0")),
+            TokenType::EOF,
         ];
-        let errors = vec![LexerError::new(Error::CommentError(1))];
+        assert_eq!(types(&test_lexer), tokens);
+        assert_eq!(test_lexer.tokens[1].span, Span::new(0, 1, 1, 4));
+    }
 
-        assert_eq!(test_lexer.tokens, tokens);
+    #[test]
+    fn test_invalid_literals() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line(r#"'ab' '\q' "open"#);
+        let errors = vec![
+            LexerError::new(Error::LiteralError(Span::new(0, 1, 0, 4), to_String!("Character literal must hold exactly one character"))),
+            LexerError::new(Error::LiteralError(Span::new(0, 1, 5, 9), to_String!("Unknown escape `\\q`"))),
+            LexerError::new(Error::LiteralError(Span::new(0, 1, 10, 15), to_String!("Literal is never closed"))),
+        ];
         assert_eq!(test_lexer.errors, errors);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_directives_and_commas() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("msg: .asciiz \"hi\", \"there\" .word 1,2");
+        let tokens = vec![
+            TokenType::LabelDeclaration(to_String!("msg")),
+            TokenType::DIRECTIVE(to_String!("asciiz")),
+            TokenType::STRING(to_String!("hi")),
+            TokenType::COMMA,
+            TokenType::STRING(to_String!("there")),
+            TokenType::DIRECTIVE(to_String!("word")),
+            TokenType::NUMBER(1),
+            TokenType::COMMA,
+            TokenType::NUMBER(2),
+            TokenType::EOF,
+        ];
+        assert_eq!(types(&test_lexer), tokens);
+        assert_eq!(test_lexer.tokens[1].span, Span::new(0, 1, 5, 12));
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line(".double 3.25, -0.5, 2. 1.5x");
+        let tokens = vec![
+            TokenType::DIRECTIVE(to_String!("double")),
+            TokenType::FLOAT(3.25),
+            TokenType::COMMA,
+            TokenType::FLOAT(-0.5),
+            TokenType::COMMA,
+            TokenType::FLOAT(2.0),
+            TokenType::EOF,
+        ];
+        assert_eq!(types(&test_lexer), tokens);
+        assert_eq!(test_lexer.errors, vec![LexerError::new(Error::NumberError(Span::new(0, 1, 23, 27), to_String!("1.5x")))]);
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut test_lexer = Lexer::new();
+        test_lexer.lex_single_line("#(end - start)*-2 #~lo(SIZE-1) #-(1)");
+        let tokens = vec![
+            TokenType::IntOperand,
+            TokenType::OPERATOR('('),
+            TokenType::OPCODE(to_String!("end")),
+            TokenType::OPERATOR('-'),
+            TokenType::OPCODE(to_String!("start")),
+            TokenType::OPERATOR(')'),
+            TokenType::OPERATOR('*'),
+            TokenType::NUMBER(-2),
+            TokenType::IntOperand,
+            TokenType::OPERATOR('~'),
+            TokenType::OPCODE(to_String!("lo")),
+            TokenType::OPERATOR('('),
+            TokenType::OPCODE(to_String!("SIZE")),
+            TokenType::OPERATOR('-'),
+            TokenType::NUMBER(1),
+            TokenType::OPERATOR(')'),
+            TokenType::IntOperand,
+            TokenType::OPERATOR('-'),
+            TokenType::OPERATOR('('),
+            TokenType::NUMBER(1),
+            TokenType::OPERATOR(')'),
+            TokenType::EOF,
+        ];
+        assert_eq!(types(&test_lexer), tokens);
+        assert!(test_lexer.errors.is_empty());
+    }
+}
@@ -1,38 +1,48 @@
-use std::fmt::{Display, Formatter, Debug, Result};
-use std::error;
+use std::fmt::{Display, Formatter, Result};
+use crate::diagnostic::{Diagnose, Span};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {    // EX:
     OPCODE(String),     // Load
-    NUMBER(String),     // 23
+    NUMBER(i64),        // 23, -5, 0xFF, 0b1010
+    FLOAT(f64),         // 3.25, -0.5
+    CHAR(char),         // 'a', '\n'
+    STRING(String),     // "hello\n"
     REGISTER,           // $
     IntOperand,         // #
+    LabelDeclaration(String),   // loop:
+    LabelUsage(String),         // @loop
+    DIRECTIVE(String),  // .asciiz
+    COMMA,              // ,
+    OPERATOR(char),     // + - * / & | ^ ~ ( )
     EOF,                // End of file
 }
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub token: TokenType,
-    pub line: usize,
+    pub span: Span,     // where the token is in the source
 }
 
 impl Token {
-    pub fn new(token: TokenType, line: usize) -> Token {
+    pub fn new(token: TokenType, span: Span) -> Token {
         Token {
             token,
-            line,
+            span,
         }
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Error {
-    TokenError(usize, char),
-    CommentError(usize),
+    TokenError(Span, char),     // a character that can not start a token
+    CommentError(Span),         // a comment that is never closed, or a `>` outside of one
+    NumberError(Span, String),  // a number with invalid digits or that does not fit in 64 bits
+    LiteralError(Span, String), // a character or string literal that can not be decoded, and why
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct LexerError {
     err: Error
 }
@@ -45,17 +55,28 @@ impl LexerError {
     }
 }
 
-impl Display for LexerError {
-    fn fmt(&self, f: &mut Formatter) -> Result {
-        match self.err {
-            Error::TokenError(line, c) => write!(f, "An error occurred lexing token {} on line {}", line, c),
-            Error::CommentError(line) => write!(f, "Invalid comment block on line {}", line),
+impl Diagnose for LexerError {
+    fn span(&self) -> Span {
+        match &self.err {
+            Error::TokenError(span, _)
+            | Error::CommentError(span)
+            | Error::NumberError(span, _)
+            | Error::LiteralError(span, _) => *span,
+        }
+    }
+
+    fn message(&self) -> String {
+        match &self.err {
+            Error::TokenError(_, c) => format!("Unexpected character `{}`", c),
+            Error::CommentError(_) => String::from("Invalid comment block"),
+            Error::NumberError(_, number) => format!("Invalid number `{}`", number),
+            Error::LiteralError(_, reason) => reason.clone(),
         }
     }
 }
 
-impl Debug for LexerError {
+impl Display for LexerError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{{ file: {}, line: {} }}", file!(), line!())
+        write!(f, "{} on {}", self.message(), self.span())
     }
-}
\ No newline at end of file
+}
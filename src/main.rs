@@ -4,15 +4,213 @@ pub mod repl;
 pub mod assembler;
 pub mod lexer;
 pub mod parser;
+pub mod object;
+pub mod disassembler;
+pub mod syscall;
+pub mod debugger;
+pub mod diagnostic;
+pub mod decoder;
+pub mod benchmark;
+pub mod snapshot;
+pub mod trace;
+pub mod profiler;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::process;
+use std::time::{Duration, Instant};
+use assembler::Assembler;
+use object::ObjectFile;
+use vm::{VM, ExitReason};
+use snapshot::Snapshot;
+use trace::{TraceSink, TextSink, JsonLinesSink};
+use profiler::Profiler;
+
+const USAGE: &str = "Usage:
+  teflon                          Starts the REPL
+  teflon assemble <source> <out>  Assembles a source file into a program file
+  teflon run <program> [options]  Runs an assembled program file
+      --fuel <n>                  Stops after spending n fuel, one per instruction
+      --timeout <ms>              Stops after running for ms milliseconds
+      --save <snapshot>           Saves the vm when it stops early so it can be resumed
+      --trace <file>              Writes every executed instruction to file, or stderr for -
+      --trace-json <file>         Like --trace, but as JSON lines
+      --profile <file>            Writes a hot spot report to file, or stderr for -
+      --folded <file>             Writes the folded call stacks for flamegraph tools
+  teflon resume <snapshot> [options]  Resumes a saved vm, with the same options as run
+  teflon disasm <program>         Prints the assembly of a program file
+  teflon isa                      Prints the instruction set reference
+  teflon bench [rounds]           Times the byte-level and pre-decoded interpreter loops";
 
 fn main(){
-    let mut lex = lexer::Lexer::new();
-    lex.lex_line("ADD #4 $3", 1);
-    let mut ast = parser::parser::Parser::new(lex.tokens);
-    // ast.next();
-    // ast.next();
-    // println!("{:?}", ast.current);
-
-    // let mut repl = repl::REPL::new();
-    // repl.run();
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+        [] => {
+            let mut repl = repl::REPL::new();
+            repl.run();
+            Ok(())
+        },
+        ["assemble", source, out] => assemble(source, out),
+        ["run", program, options @ ..] => run(program, options),
+        ["resume", snapshot, options @ ..] => resume(snapshot, options),
+        ["disasm", program] => disasm(program),
+        ["isa"] => {
+            print!("{}", instructions::isa_reference());
+            Ok(())
+        },
+        ["bench"] => bench("100"),
+        ["bench", rounds] => bench(rounds),
+        _ => Err(String::from(USAGE)),
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn assemble(source: &str, out: &str) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("Unable to read {}: {}", source, e))?;
+    let mut assembler = Assembler::new();
+    assembler.set_source_name(source);
+    let object = assembler.assemble_object(&text).map_err(|errors| {
+        errors.iter().map(|e| assembler.render(e)).collect::<Vec<String>>().join("\n").trim_end().to_string()
+    })?;
+    fs::write(out, object.to_bytes()).map_err(|e| format!("Unable to write {}: {}", out, e))
+}
+
+// The options shared by `run` and `resume`
+#[derive(Default)]
+struct RunOptions {
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    save: Option<String>,
+    sinks: Vec<Box<dyn TraceSink>>,
+    profile: Option<String>,
+    folded: Option<String>,
+}
+
+impl RunOptions {
+    fn parse(options: &[&str]) -> Result<RunOptions, String> {
+        let mut parsed = RunOptions::default();
+        for option in options.chunks(2) {
+            let number = |name: &str| option.get(1)
+                .and_then(|value| value.parse::<u64>().ok())
+                .ok_or_else(|| format!("{} takes a number", name));
+            match option {
+                ["--fuel", ..] => parsed.fuel = Some(number("--fuel")?),
+                ["--timeout", ..] => parsed.timeout = Some(Duration::from_millis(number("--timeout")?)),
+                ["--save", path] => parsed.save = Some(path.to_string()),
+                ["--trace", path] => parsed.sinks.push(Box::new(TextSink::new(trace_writer(path)?))),
+                ["--trace-json", path] => parsed.sinks.push(Box::new(JsonLinesSink::new(trace_writer(path)?))),
+                ["--profile", path] => parsed.profile = Some(path.to_string()),
+                ["--folded", path] => parsed.folded = Some(path.to_string()),
+                _ => return Err(String::from(USAGE)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn trace_writer(path: &str) -> Result<Box<dyn Write + Send>, String> {
+    match path {
+        "-" => Ok(Box::new(io::stderr())),
+        _ => {
+            let file = fs::File::create(path).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+            Ok(Box::new(BufWriter::new(file)))
+        },
+    }
+}
+
+fn run(program: &str, options: &[&str]) -> Result<(), String> {
+    let options = RunOptions::parse(options)?;
+    let bytes = fs::read(program).map_err(|e| format!("Unable to read {}: {}", program, e))?;
+    let object = ObjectFile::from_bytes(&bytes).map_err(|e| e.to_string())?;
+    let symbols = object.symbols.clone().unwrap_or_default();
+    let mut vm = VM::new();
+    vm.load_object(object);
+    execute(vm, options, Profiler::new(symbols))
+}
+
+fn resume(snapshot: &str, options: &[&str]) -> Result<(), String> {
+    let options = RunOptions::parse(options)?;
+    let bytes = fs::read(snapshot).map_err(|e| format!("Unable to read {}: {}", snapshot, e))?;
+    let mut vm = VM::new();
+    vm.restore(Snapshot::from_bytes(&bytes).map_err(|e| e.to_string())?);
+    // Snapshots have no symbols, so the profile names addresses
+    execute(vm, options, Profiler::new(vec![]))
+}
+
+// Runs the vm with the options. `profiler` is only used with --profile or --folded
+fn execute(mut vm: VM, mut options: RunOptions, profiler: Profiler) -> Result<(), String> {
+    vm.predecode();
+    if options.profile.is_some() || options.folded.is_some() {
+        options.sinks.push(Box::new(profiler.clone()));
+    }
+    if !options.sinks.is_empty() {
+        vm.set_tracer(Some(Box::new(options.sinks)));
+    }
+    let result = match (options.fuel, options.timeout) {
+        (None, None) => vm.run_predecoded(),
+        (fuel, timeout) => {
+            vm.deadline = timeout.map(|timeout| Instant::now() + timeout);
+            vm.run_with_fuel(fuel.unwrap_or(u64::MAX))
+        },
+    };
+    write_profile(&profiler, options.profile.as_deref(), options.folded.as_deref())?;
+
+    let stopped = match result {
+        Ok(ExitReason::Halted) | Ok(ExitReason::EndOfProgram) => return Ok(()),
+        Ok(ExitReason::Exit(code)) => {
+            // Dropping the vm flushes the trace, which exiting would skip
+            drop(vm);
+            process::exit(code)
+        },
+        Ok(ExitReason::OutOfFuel) => "Program ran out of fuel",
+        Ok(ExitReason::DeadlineExceeded) => "Program ran past its timeout",
+        Ok(reason) => return Err(format!("Program stopped unexpectedly: {:?}", reason)),
+        Err(e) => return Err(format!("Error: {}", e)),
+    };
+    match options.save {
+        Some(path) => {
+            fs::write(&path, vm.snapshot().to_bytes()).map_err(|e| format!("Unable to write {}: {}", path, e))?;
+            Err(format!("{}. Saved the vm to {}", stopped, path))
+        },
+        None => Err(String::from(stopped)),
+    }
+}
+
+fn write_profile(profiler: &Profiler, report: Option<&str>, folded: Option<&str>) -> Result<(), String> {
+    let write = |path: &str, text: String| trace_writer(path)?.write_all(text.as_bytes())
+        .map_err(|e| format!("Unable to write {}: {}", path, e));
+    if let Some(path) = report {
+        write(path, profiler.report())?;
+    }
+    if let Some(path) = folded {
+        write(path, profiler.folded())?;
+    }
+    Ok(())
+}
+
+fn disasm(program: &str) -> Result<(), String> {
+    let bytes = fs::read(program).map_err(|e| format!("Unable to read {}: {}", program, e))?;
+    let object = ObjectFile::from_bytes(&bytes).map_err(|e| e.to_string())?;
+    for instruction in disassembler::disassemble_object(&object) {
+        println!("{}", instruction);
+    }
+    Ok(())
+}
+
+fn bench(rounds: &str) -> Result<(), String> {
+    let rounds = match rounds.parse::<u16>() {
+        Ok(rounds) if (1..=benchmark::MAX_ROUNDS).contains(&rounds) => rounds,
+        _ => return Err(format!("Rounds must be between 1 and {}", benchmark::MAX_ROUNDS)),
+    };
+    let report = benchmark::run(rounds)?;
+    println!("Byte-level: {:?}", report.byte_level);
+    println!("Pre-decoded: {:?}", report.predecoded);
+    println!("Speedup: {:.2}x", report.speedup());
+    Ok(())
+}
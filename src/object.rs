@@ -0,0 +1,402 @@
+use std::error;
+use std::fmt;
+
+/* The on disk format of an assembled Teflon program. Every number is stored high byte first.
+
+ Header (32 bytes):
+    0   magic           4 bytes, always `TFLN`
+    4   version         u16
+    6   flags           u16, bit 0 => a symbol section follows, bit 1 => a debug section follows
+    8   entry point     u32, offset into the code section where execution starts
+    12  code length     u32
+    16  rodata length   u32
+    20  symbols length  u32
+    24  debug length    u32
+    28  reserved        u32, always 0
+
+ The sections follow the header in the order code, read-only data, symbols, debug.
+
+ Symbol section:    u32 count, then per symbol: u8 section (0 code, 1 rodata), u32 address,
+                    u16 name length, name (utf-8)
+ Debug section:     u32 count, then per entry: u32 code address, u32 source line
+*/
+pub const MAGIC: [u8; 4] = *b"TFLN";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 32;
+
+const FLAG_SYMBOLS: u16 = 1;
+const FLAG_DEBUG: u16 = 1 << 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Section {
+    Code,
+    ReadOnlyData,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub section: Section,
+    pub address: u32,
+}
+
+// Maps the instruction at a code address back to the line of source it came from
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LineEntry {
+    pub address: u32,
+    pub line: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ObjectFile {
+    pub entry_point: u32,
+    pub code: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub symbols: Option<Vec<Symbol>>,
+    pub debug: Option<Vec<LineEntry>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated(&'static str),            // the part of the file that is cut short
+    EntryPointOutOfRange(u32),
+    InvalidHeader(String),              // why the header could not be read
+    InvalidSymbol(String),              // why the symbol section could not be read
+    InvalidDebug(String),               // why the debug section could not be read
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "Not a Teflon program (bad magic bytes)"),
+            LoadError::UnsupportedVersion(version) => write!(f, "Unsupported program format version {}", version),
+            LoadError::Truncated(part) => write!(f, "The {} of the program is truncated", part),
+            LoadError::EntryPointOutOfRange(entry) => write!(f, "Entry point {} is outside of the code section", entry),
+            LoadError::InvalidHeader(reason) => write!(f, "Invalid header: {}", reason),
+            LoadError::InvalidSymbol(reason) => write!(f, "Invalid symbol section: {}", reason),
+            LoadError::InvalidDebug(reason) => write!(f, "Invalid debug section: {}", reason),
+            LoadError::TrailingBytes(count) => write!(f, "{} unexpected bytes after the last section", count),
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl ObjectFile {
+    pub fn new(code: Vec<u8>) -> ObjectFile {
+        ObjectFile {
+            code,
+            ..Default::default()
+        }
+    }
+
+    // Looks up the name of the symbol declared at `address` in `section`
+    pub fn symbol_at(&self, section: Section, address: u32) -> Option<&str> {
+        self.symbols.as_ref()?.iter()
+            .find(|symbol| symbol.section == section && symbol.address == address)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let symbols = self.symbols.as_ref().map(|symbols| encode_symbols(symbols)).unwrap_or_default();
+        let debug = self.debug.as_ref().map(|entries| encode_debug(entries)).unwrap_or_default();
+
+        let mut flags = 0;
+        if self.symbols.is_some() {
+            flags |= FLAG_SYMBOLS;
+        }
+        if self.debug.is_some() {
+            flags |= FLAG_DEBUG;
+        }
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.code.len() + self.rodata.len() + symbols.len() + debug.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&flags.to_be_bytes());
+        bytes.extend_from_slice(&self.entry_point.to_be_bytes());
+        for length in &[self.code.len(), self.rodata.len(), symbols.len(), debug.len(), 0] {
+            bytes.extend_from_slice(&(*length as u32).to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.code);
+        bytes.extend_from_slice(&self.rodata);
+        bytes.extend(symbols);
+        bytes.extend(debug);
+        bytes
+    }
+
+    // Reads and validates a program written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, LoadError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(LoadError::BadMagic);
+        }
+        let mut reader = Reader::new(&bytes[MAGIC.len()..]);
+        let version = reader.u16("header")?;
+        if version != FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let flags = reader.u16("header")?;
+        let entry_point = reader.u32("header")?;
+        let code_length = reader.u32("header")? as usize;
+        let rodata_length = reader.u32("header")? as usize;
+        let symbols_length = reader.u32("header")? as usize;
+        let debug_length = reader.u32("header")? as usize;
+        let reserved = reader.u32("header")?;
+        if reserved != 0 {
+            return Err(LoadError::InvalidHeader(format!("reserved field is {:#X} instead of 0", reserved)));
+        }
+        if flags & !(FLAG_SYMBOLS | FLAG_DEBUG) != 0 {
+            return Err(LoadError::InvalidHeader(format!("unknown flags {:#06X}", flags & !(FLAG_SYMBOLS | FLAG_DEBUG))));
+        }
+        // A section is only read when its flag is set, so one without its flag would be lost
+        if flags & FLAG_SYMBOLS == 0 && symbols_length > 0 {
+            return Err(LoadError::InvalidHeader(String::from("symbol section without the symbols flag")));
+        }
+        if flags & FLAG_DEBUG == 0 && debug_length > 0 {
+            return Err(LoadError::InvalidHeader(String::from("debug section without the debug flag")));
+        }
+
+        let code = reader.take(code_length, "code section")?.to_vec();
+        let rodata = reader.take(rodata_length, "read-only data section")?.to_vec();
+        let symbols = reader.take(symbols_length, "symbol section")?;
+        let debug = reader.take(debug_length, "debug section")?;
+        if reader.remaining() > 0 {
+            return Err(LoadError::TrailingBytes(reader.remaining()));
+        }
+        // An empty program may only start at 0
+        if entry_point as usize > code.len() || (entry_point as usize == code.len() && !code.is_empty()) {
+            return Err(LoadError::EntryPointOutOfRange(entry_point));
+        }
+
+        Ok(ObjectFile {
+            entry_point,
+            code,
+            rodata,
+            symbols: if flags & FLAG_SYMBOLS != 0 { Some(decode_symbols(symbols)?) } else { None },
+            debug: if flags & FLAG_DEBUG != 0 { Some(decode_debug(debug)?) } else { None },
+        })
+    }
+}
+
+fn encode_symbols(symbols: &[Symbol]) -> Vec<u8> {
+    let mut bytes = (symbols.len() as u32).to_be_bytes().to_vec();
+    for symbol in symbols {
+        bytes.push(match symbol.section {
+            Section::Code => 0,
+            Section::ReadOnlyData => 1,
+        });
+        bytes.extend_from_slice(&symbol.address.to_be_bytes());
+        bytes.extend_from_slice(&(symbol.name.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(symbol.name.as_bytes());
+    }
+    bytes
+}
+
+fn decode_symbols(bytes: &[u8]) -> Result<Vec<Symbol>, LoadError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u32("symbol section")?;
+    let mut symbols = vec![];
+    for _ in 0..count {
+        let section = match reader.u8("symbol section")? {
+            0 => Section::Code,
+            1 => Section::ReadOnlyData,
+            other => return Err(LoadError::InvalidSymbol(format!("unknown section {}", other))),
+        };
+        let address = reader.u32("symbol section")?;
+        let length = reader.u16("symbol section")? as usize;
+        let name = String::from_utf8(reader.take(length, "symbol section")?.to_vec())
+            .map_err(|_| LoadError::InvalidSymbol(String::from("name is not utf-8")))?;
+        symbols.push(Symbol { name, section, address });
+    }
+    if reader.remaining() > 0 {
+        return Err(LoadError::InvalidSymbol(format!("{} bytes after the last symbol", reader.remaining())));
+    }
+    Ok(symbols)
+}
+
+fn encode_debug(entries: &[LineEntry]) -> Vec<u8> {
+    let mut bytes = (entries.len() as u32).to_be_bytes().to_vec();
+    for entry in entries {
+        bytes.extend_from_slice(&entry.address.to_be_bytes());
+        bytes.extend_from_slice(&entry.line.to_be_bytes());
+    }
+    bytes
+}
+
+fn decode_debug(bytes: &[u8]) -> Result<Vec<LineEntry>, LoadError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u32("debug section")?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let address = reader.u32("debug section")?;
+        let line = reader.u32("debug section")?;
+        entries.push(LineEntry { address, line });
+    }
+    if reader.remaining() > 0 {
+        return Err(LoadError::InvalidDebug(format!("{} bytes after the last entry", reader.remaining())));
+    }
+    Ok(entries)
+}
+
+// Reads big endian values out of a byte slice, reporting which part of the file ran out
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, position: 0 }
+    }
+
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+
+    pub(crate) fn take(&mut self, length: usize, part: &'static str) -> Result<&'a [u8], LoadError> {
+        if length > self.remaining() {
+            return Err(LoadError::Truncated(part));
+        }
+        let slice = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self, part: &'static str) -> Result<u8, LoadError> {
+        Ok(self.take(1, part)?[0])
+    }
+
+    pub(crate) fn u16(&mut self, part: &'static str) -> Result<u16, LoadError> {
+        let bytes = self.take(2, part)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub(crate) fn u32(&mut self, part: &'static str) -> Result<u32, LoadError> {
+        let bytes = self.take(4, part)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub(crate) fn u64(&mut self, part: &'static str) -> Result<u64, LoadError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8, part)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_object() -> ObjectFile {
+        ObjectFile {
+            entry_point: 4,
+            code: vec![0, 0, 0, 0, 1, 1, 3, 232],
+            rodata: b"hi\0".to_vec(),
+            symbols: Some(vec![
+                Symbol { name: String::from("main"), section: Section::Code, address: 4 },
+                Symbol { name: String::from("greeting"), section: Section::ReadOnlyData, address: 0 },
+            ]),
+            debug: Some(vec![LineEntry { address: 0, line: 1 }, LineEntry { address: 4, line: 2 }]),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let object = test_object();
+        assert_eq!(ObjectFile::from_bytes(&object.to_bytes()), Ok(object));
+    }
+
+    #[test]
+    fn test_round_trip_without_optional_sections() {
+        let object = ObjectFile::new(vec![0, 0, 0, 0]);
+        let bytes = object.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 4);
+        assert_eq!(ObjectFile::from_bytes(&bytes), Ok(object));
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let bytes = test_object().to_bytes();
+        assert_eq!(&bytes[0..4], b"TFLN");
+        assert_eq!(&bytes[4..6], &[0, 1]);
+        assert_eq!(&bytes[6..8], &[0, 3]);
+        assert_eq!(&bytes[8..12], &[0, 0, 0, 4]);
+        assert_eq!(&bytes[12..16], &[0, 0, 0, 8]);
+        assert_eq!(&bytes[16..20], &[0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        assert_eq!(ObjectFile::from_bytes(b"TFLX1234"), Err(LoadError::BadMagic));
+        assert_eq!(ObjectFile::from_bytes(b"TF"), Err(LoadError::BadMagic));
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let mut bytes = test_object().to_bytes();
+        bytes[5] = 9;
+        assert_eq!(ObjectFile::from_bytes(&bytes), Err(LoadError::UnsupportedVersion(9)));
+    }
+
+    #[test]
+    fn test_truncated_sections() {
+        let bytes = test_object().to_bytes();
+        assert_eq!(ObjectFile::from_bytes(&bytes[..20]), Err(LoadError::Truncated("header")));
+        assert_eq!(ObjectFile::from_bytes(&bytes[..HEADER_SIZE + 5]), Err(LoadError::Truncated("code section")));
+        assert_eq!(ObjectFile::from_bytes(&bytes[..bytes.len() - 1]), Err(LoadError::Truncated("debug section")));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut bytes = test_object().to_bytes();
+        bytes.push(0);
+        assert_eq!(ObjectFile::from_bytes(&bytes), Err(LoadError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let bytes = test_object().to_bytes();
+        let mut reserved = bytes.clone();
+        reserved[31] = 1;
+        assert_eq!(ObjectFile::from_bytes(&reserved), Err(LoadError::InvalidHeader(String::from("reserved field is 0x1 instead of 0"))));
+        let mut flags = bytes.clone();
+        flags[6] = 0x80;
+        assert_eq!(ObjectFile::from_bytes(&flags), Err(LoadError::InvalidHeader(String::from("unknown flags 0x8000"))));
+        let mut no_symbols = bytes.clone();
+        no_symbols[7] = 2;
+        assert_eq!(ObjectFile::from_bytes(&no_symbols), Err(LoadError::InvalidHeader(String::from("symbol section without the symbols flag"))));
+        let mut no_debug = bytes;
+        no_debug[7] = 1;
+        assert_eq!(ObjectFile::from_bytes(&no_debug), Err(LoadError::InvalidHeader(String::from("debug section without the debug flag"))));
+    }
+
+    #[test]
+    fn test_trailing_bytes_in_sections() {
+        assert_eq!(decode_symbols(&[0, 0, 0, 0, 7]), Err(LoadError::InvalidSymbol(String::from("1 bytes after the last symbol"))));
+        assert_eq!(decode_debug(&[0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 2, 9, 9]), Err(LoadError::InvalidDebug(String::from("2 bytes after the last entry"))));
+
+        // The section lengths in the header include the extra bytes
+        let object = ObjectFile { debug: None, ..test_object() };
+        let mut bytes = object.to_bytes();
+        bytes.push(0);
+        bytes[23] += 1;
+        assert_eq!(ObjectFile::from_bytes(&bytes), Err(LoadError::InvalidSymbol(String::from("1 bytes after the last symbol"))));
+    }
+
+    #[test]
+    fn test_entry_point_out_of_range() {
+        let mut object = test_object();
+        object.entry_point = 8;
+        assert_eq!(ObjectFile::from_bytes(&object.to_bytes()), Err(LoadError::EntryPointOutOfRange(8)));
+    }
+
+    #[test]
+    fn test_symbol_at() {
+        let object = test_object();
+        assert_eq!(object.symbol_at(Section::Code, 4), Some("main"));
+        assert_eq!(object.symbol_at(Section::Code, 0), None);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::diagnostic::Span;
+use crate::assembler::instruction_parsers::AssemblerInstruction;
+use crate::assembler::directives::Directive;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+    Label(String, Span),                // name, where it is declared
+    Instruction(AssemblerInstruction),
+    Directive(Directive),
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Label(_, span) => *span,
+            Statement::Instruction(instruction) => instruction.span,
+            Statement::Directive(directive) => directive.span,
+        }
+    }
+}
+
+/* A parsed program: its statements in source order. Instructions are in the form they are
+   encoded in and their operands match the operand kinds of their opcode.
+
+ EX: "loop: JMP @loop" => [Label("loop"), Instruction(JMPI [Label("loop")])]
+*/
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+}
+
+impl Program {
+    pub fn new(statements: Vec<Statement>) -> Program {
+        Program { statements }
+    }
+}
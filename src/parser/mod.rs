@@ -1 +1,3 @@
-pub mod parser;
\ No newline at end of file
+pub mod ast;
+#[allow(clippy::module_inception)]
+pub mod parser;
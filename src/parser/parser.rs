@@ -1,32 +1,593 @@
-use crate::lexer;
+use std::fmt;
+use crate::diagnostic::{Diagnose, Span};
+use crate::lexer::token::{Token, TokenType};
+use crate::instructions::{Opcode, OperandKind};
+use crate::assembler::instruction_parsers::{AssemblerInstruction, Operand};
+use crate::assembler::directives::{Argument, Directive, DirectiveKind};
+use crate::assembler::expression::{Expression, ExpressionKind, Function};
+use super::ast::{Program, Statement};
 
+// Statement ::= <label declaration> | <instruction> | <directive>
+// Instruction ::= <opcode> ‘$’ <register>  ‘$’<register>  ‘$’ <register>  | <opcode> ‘$’ <register> ‘#’ <int operand>  | <opcode> ‘#’ <int operand>
+// Directive ::= ‘.’ <name> <argument>*
+// Expression ::= <term> (<operator> <term>)* with C precedence: `* /` then `+ -` then `&` then `^` then `|`
+// Term ::= <number> | <symbol> | ‘@’ <label> | ‘-’ <term> | ‘~’ <term> | ‘(’ <expression> ‘)’ | <function> ‘(’ <expression> ‘)’
+//
+// An instruction or directive and all of its operands live on a single line, so the parser uses
+// the line number of each token to know where a statement ends. Operands may be separated by commas.
+// After an error the parser carries on at the next line, so every error in a source is reported.
 pub struct Parser {
-    tokens: Vec<lexer::token::Token>,
+    tokens: Vec<Token>,
     pub current: usize
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParserError {
+    UnknownOpcode(Span, String),        // the mnemonic
+    UnknownDirective(Span, String),     // the name without its `.`
+    UnexpectedToken(Span, String),      // what was found
+    ExpectedNumber(Span),               // the `$` or `#` without a number
+    InvalidNumber(Span, String),        // the number that could not be parsed
+    UnknownFunction(Span, String),      // a call to something other than `lo` or `hi`
+    UnclosedParen(Span),                // the `(` without a `)`
+    WrongOperandCount { span: Span, opcode: Opcode, expected: usize, found: usize },
+    WrongOperandKind { span: Span, opcode: Opcode, position: usize },
+}
+
+impl Diagnose for ParserError {
+    fn span(&self) -> Span {
+        match self {
+            ParserError::UnknownOpcode(span, _)
+            | ParserError::UnknownDirective(span, _)
+            | ParserError::UnexpectedToken(span, _)
+            | ParserError::ExpectedNumber(span)
+            | ParserError::InvalidNumber(span, _)
+            | ParserError::UnknownFunction(span, _)
+            | ParserError::UnclosedParen(span)
+            | ParserError::WrongOperandCount { span, .. }
+            | ParserError::WrongOperandKind { span, .. } => *span,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            ParserError::UnknownOpcode(_, name) => format!("Unknown opcode `{}`", name),
+            ParserError::UnknownDirective(_, name) => format!("Unknown directive `.{}`", name),
+            ParserError::UnexpectedToken(_, found) => format!("Unexpected {}", found),
+            ParserError::ExpectedNumber(_) => String::from("Expected a number"),
+            ParserError::InvalidNumber(_, number) => format!("Number {} is too large", number),
+            ParserError::UnknownFunction(_, name) => format!("Unknown function `{}`, expected `lo` or `hi`", name),
+            ParserError::UnclosedParen(_) => String::from("Unclosed `(`"),
+            ParserError::WrongOperandCount { opcode, expected, found, .. } =>
+                format!("{} expects {} operands but {} were given", opcode.mnemonic(), expected, found),
+            ParserError::WrongOperandKind { opcode, position, .. } =>
+                format!("Operand {} of {} has the wrong type", position + 1, opcode.mnemonic()),
+        }
+    }
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} on {}", self.message(), self.span())
+    }
+}
+
 impl Parser {
-    pub fn new (tokens: Vec<lexer::token::Token>) -> Parser{
-        return Parser {
-            tokens: tokens, 
+    pub fn new (tokens: Vec<Token>) -> Parser{
+        Parser {
+            tokens,
             current: 0
         }
     }
-    fn isAtEnd(self) -> bool {      
-        return match self.peek(){
-            lexer::token::TokenType::EOF => true,
-            _ => false,
+
+    // Parses every statement in the token stream, or returns every error that was found
+    pub fn parse(&mut self) -> Result<Program, Vec<ParserError>> {
+        let mut statements = vec![];
+        let mut errors = vec![];
+        while !self.is_at_end() {
+            let start = self.peek().span;
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(e) => {
+                    errors.push(e);
+                    self.skip_line(start);
+                },
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Program::new(statements))
+    }
+
+    fn statement(&mut self) -> Result<Statement, ParserError> {
+        match &self.peek().token {
+            TokenType::LabelDeclaration(name) => {
+                let statement = Statement::Label(name.clone(), self.peek().span);
+                self.next();
+                Ok(statement)
+            },
+            TokenType::DIRECTIVE(_) => Ok(Statement::Directive(self.directive()?)),
+            _ => Ok(Statement::Instruction(self.instruction()?)),
+        }
+    }
+
+    // Skips what is left of the line of `start`
+    fn skip_line(&mut self, start: Span) {
+        while !self.is_at_end() && self.peek().span.same_line(start) {
+            self.next();
+        }
+    }
+
+    fn instruction(&mut self) -> Result<AssemblerInstruction, ParserError> {
+        let span = self.peek().span;
+        let opcode = match &self.next().token {
+            TokenType::OPCODE(name) => match name.parse::<Opcode>() {
+                Ok(opcode) => opcode,
+                Err(_) => return Err(ParserError::UnknownOpcode(span, name.clone())),
+            },
+            other => return Err(ParserError::UnexpectedToken(span, describe(other))),
+        };
+
+        let mut operands = vec![];
+        let mut operand_spans = vec![];
+        while self.next_operand(span) {
+            let (operand, operand_span) = self.operand()?;
+            operands.push(operand);
+            operand_spans.push(operand_span);
+        }
+        let span = operand_spans.last().map_or(span, |last| span.to(*last));
+
+        // The instruction table knows how many operands every opcode takes
+        let expected = opcode.operands().map(|kinds| kinds.len()).unwrap_or(0);
+        if operands.len() != expected {
+            return Err(ParserError::WrongOperandCount { span, opcode, expected, found: operands.len() });
+        }
+        let mut instruction = AssemblerInstruction::new(select_form(opcode, &operands), operands, span);
+        instruction.operand_spans = operand_spans;
+        check_operand_kinds(&instruction)?;
+        Ok(instruction)
+    }
+
+    fn directive(&mut self) -> Result<Directive, ParserError> {
+        let span = self.peek().span;
+        let kind = match &self.next().token {
+            TokenType::DIRECTIVE(name) => name.parse::<DirectiveKind>()
+                .map_err(|_| ParserError::UnknownDirective(span, name.clone()))?,
+            other => return Err(ParserError::UnexpectedToken(span, describe(other))),
+        };
+
+        let mut arguments = vec![];
+        let mut argument_spans = vec![];
+        while self.next_operand(span) {
+            let (argument, argument_span) = match &self.peek().token {
+                TokenType::STRING(text) => (Argument::String(text.clone()), self.next().span),
+                TokenType::FLOAT(value) => (Argument::Float(*value), self.next().span),
+                _ => {
+                    let expression = self.expression(span)?;
+                    let span = expression.span;
+                    match expression.kind {
+                        ExpressionKind::Integer(value) => (Argument::Integer(value), span),
+                        _ => (Argument::Expression(expression), span),
+                    }
+                },
+            };
+            arguments.push(argument);
+            argument_spans.push(argument_span);
+        }
+        let span = argument_spans.last().map_or(span, |last| span.to(*last));
+
+        let mut directive = Directive::new(kind, arguments, span);
+        directive.argument_spans = argument_spans;
+        Ok(directive)
+    }
+
+    // Skips a comma and reports whether another operand of the statement at `start` follows
+    fn next_operand(&mut self, start: Span) -> bool {
+        if !self.is_at_end() && self.peek().token == TokenType::COMMA && self.peek().span.same_line(start) {
+            self.next();
+        }
+        !self.is_at_end() && self.peek().span.same_line(start)
+    }
+
+    // Parses an operand and returns it with the span it covers
+    fn operand(&mut self) -> Result<(Operand, Span), ParserError> {
+        let span = self.peek().span;
+        match &self.next().token {
+            TokenType::REGISTER => {
+                if let Some((number, number_span)) = self.float_register(span)? {
+                    return Ok((Operand::FloatRegister(number), span.to(number_span)));
+                }
+                let (number, number_span) = self.number(span)?;
+                if number < 0 || number > u8::MAX as i64 {
+                    return Err(ParserError::InvalidNumber(number_span, number.to_string()));
+                }
+                Ok((Operand::Register(number as u8), span.to(number_span)))
+            },
+            TokenType::IntOperand => {
+                if self.is_at_end() || !self.peek().span.same_line(span) {
+                    return Err(ParserError::ExpectedNumber(span));
+                }
+                let expression = self.expression(span)?;
+                let operand_span = span.to(expression.span);
+                match expression.kind {
+                    ExpressionKind::Integer(value) => Ok((Operand::Integer(value), operand_span)),
+                    _ => Ok((Operand::Expression(expression), operand_span)),
+                }
+            },
+            TokenType::LabelUsage(name) => Ok((Operand::Label(name.clone()), span)),
+            other => Err(ParserError::UnexpectedToken(span, describe(other))),
+        }
+    }
+
+    // Consumes the `f3` of a float register written right after the `$` at `prefix`, if there is one
+    fn float_register(&mut self, prefix: Span) -> Result<Option<(u8, Span)>, ParserError> {
+        if self.is_at_end() || !self.peek().span.same_line(prefix) || self.peek().span.start != prefix.end {
+            return Ok(None);
+        }
+        let span = self.peek().span;
+        let digits = match &self.peek().token {
+            TokenType::OPCODE(name) if name.len() > 1 && name.starts_with(['f', 'F']) && name[1..].bytes().all(|b| b.is_ascii_digit()) =>
+                name[1..].to_string(),
+            _ => return Ok(None),
+        };
+        self.next();
+        match digits.parse::<u8>() {
+            Ok(number) => Ok(Some((number, span))),
+            Err(_) => Err(ParserError::InvalidNumber(span, digits)),
+        }
+    }
+
+    // Consumes the NUMBER or CHAR token that must follow the `$` or `#` at `prefix`
+    fn number(&mut self, prefix: Span) -> Result<(i64, Span), ParserError> {
+        if self.is_at_end() || !self.peek().span.same_line(prefix) {
+            return Err(ParserError::ExpectedNumber(prefix));
+        }
+        let span = self.peek().span;
+        match &self.next().token {
+            TokenType::NUMBER(value) => Ok((*value, span)),
+            TokenType::CHAR(c) => Ok((*c as i64, span)),
+            _ => Err(ParserError::ExpectedNumber(prefix)),
+        }
+    }
+
+    // Parses an expression on the line of `start`, starting with the operators that bind loosest
+    fn expression(&mut self, start: Span) -> Result<Expression, ParserError> {
+        self.binary(start, 0)
+    }
+
+    fn binary(&mut self, start: Span, level: usize) -> Result<Expression, ParserError> {
+        const PRECEDENCE: [&[char]; 5] = [&['|'], &['^'], &['&'], &['+', '-'], &['*', '/']];
+        if level == PRECEDENCE.len() {
+            return self.term(start);
         }
+        let mut lhs = self.binary(start, level + 1)?;
+        while let Some(operator) = self.peek_operator(start).filter(|c| PRECEDENCE[level].contains(c)) {
+            self.next();
+            let rhs = self.binary(start, level + 1)?;
+            lhs = Expression::binary(operator, lhs, rhs);
+        }
+        Ok(lhs)
     }
-    fn peek(&self) -> &lexer::token::TokenType{
-        return &self.tokens.get(self.current).unwrap().token; 
+
+    fn term(&mut self, start: Span) -> Result<Expression, ParserError> {
+        if self.is_at_end() || !self.peek().span.same_line(start) {
+            return Err(ParserError::ExpectedNumber(start));
+        }
+        let span = self.peek().span;
+        let kind = match self.next().token.clone() {
+            TokenType::NUMBER(value) => ExpressionKind::Integer(value),
+            TokenType::CHAR(c) => ExpressionKind::Integer(c as i64),
+            TokenType::LabelUsage(name) => ExpressionKind::Symbol(name),
+            TokenType::OPERATOR(operator @ ('-' | '~')) => {
+                let operand = self.term(start)?;
+                return Ok(Expression::new(ExpressionKind::Unary(operator, Box::new(operand.clone())), span.to(operand.span)));
+            },
+            TokenType::OPERATOR('(') => {
+                let expression = self.expression(start)?;
+                let end = self.close_paren(span, start)?;
+                return Ok(Expression::new(expression.kind, span.to(end)));
+            },
+            TokenType::OPCODE(name) if self.peek_operator(start) == Some('(') => {
+                let function = Function::from_name(&name).ok_or(ParserError::UnknownFunction(span, name))?;
+                let open = self.next().span;
+                let argument = self.expression(start)?;
+                let end = self.close_paren(open, start)?;
+                return Ok(Expression::new(ExpressionKind::Call(function, Box::new(argument)), span.to(end)));
+            },
+            TokenType::OPCODE(name) => ExpressionKind::Symbol(name),
+            other => return Err(ParserError::UnexpectedToken(span, describe(&other))),
+        };
+        Ok(Expression::new(kind, span))
     }
-    fn previous(&self) -> &lexer::token::TokenType{
-        return &self.tokens.get(self.current - 1).unwrap().token; 
+
+    // Consumes the `)` that closes the `(` at `open` and returns its span
+    fn close_paren(&mut self, open: Span, start: Span) -> Result<Span, ParserError> {
+        match self.peek_operator(start) {
+            Some(')') => Ok(self.next().span),
+            _ => Err(ParserError::UnclosedParen(open)),
+        }
     }
-    fn next(&mut self){
-       self.current += 1;
+
+    // The operator that comes next on the line of `start`, if there is one
+    fn peek_operator(&self, start: Span) -> Option<char> {
+        if self.is_at_end() || !self.peek().span.same_line(start) {
+            return None;
+        }
+        match self.peek().token {
+            TokenType::OPERATOR(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        match self.tokens.get(self.current) {
+            Some(token) => token.token == TokenType::EOF,
+            None => true,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    fn next(&mut self) -> &Token {
+        self.current += 1;
+        &self.tokens[self.current - 1]
     }
 }
 
-// Instruction ::= <opcode> ‘$’ <register>  ‘$’<register>  ‘$’ <register>  | <opcode> ‘$’ <register> ‘#’ <int operand>  | <opcode> ‘#’ <int operand>
+// Instructions written with a label, integer or expression in place of a register use the
+// immediate form of the opcode, so `JMP @loop` never needs the address loaded into a register
+// first and `ADD $1 #4 $1` assembles to ADDI. The compact forms only hold small immediates, so
+// an integer too large for them keeps the general form, which then asks for a register. A label
+// or expression has no value yet and only the compact form can hold it, so the assembler checks
+// its value against the compact operand once it is evaluated and rejects it when it does not fit
+fn select_form(opcode: Opcode, operands: &[Operand]) -> Opcode {
+    let is_immediate = |operand: &Operand| matches!(operand, Operand::Label(_) | Operand::Integer(_) | Operand::Expression(_));
+    let fits = |operand: &Operand, kind: OperandKind| match operand {
+        Operand::Integer(value) => (kind.range().0..=kind.range().1).contains(value),
+        operand => is_immediate(operand),
+    };
+    match (opcode, operands) {
+        (Opcode::ADD, [_, value, _]) if fits(value, OperandKind::SignedInteger8) => Opcode::ADDI,
+        (Opcode::SUB, [_, value, _]) if fits(value, OperandKind::SignedInteger8) => Opcode::SUBI,
+        (Opcode::MUL, [_, value, _]) if fits(value, OperandKind::SignedInteger8) => Opcode::MULI,
+        (Opcode::CMP, [_, value]) if fits(value, OperandKind::SignedInteger16) => Opcode::CMPI,
+        (Opcode::JMP, [target]) if is_immediate(target) => Opcode::JMPI,
+        (Opcode::JMPF, [target]) if is_immediate(target) => Opcode::JMPFI,
+        (Opcode::JMPB, [target]) if is_immediate(target) => Opcode::JMPBI,
+        (Opcode::JEQ, [_, target]) if is_immediate(target) => Opcode::JEQI,
+        (Opcode::JNEQ, [_, target]) if is_immediate(target) => Opcode::JNEQI,
+        (opcode, _) => opcode,
+    }
+}
+
+// Checks that every operand is of the kind its opcode expects. Whether the value fits is only
+// known once labels and expressions are resolved
+fn check_operand_kinds(instruction: &AssemblerInstruction) -> Result<(), ParserError> {
+    let kinds = instruction.opcode.operands().unwrap_or(&[]);
+    for (position, (kind, operand)) in kinds.iter().zip(&instruction.operands).enumerate() {
+        let matches = match kind {
+            OperandKind::Register => matches!(operand, Operand::Register(_)),
+            OperandKind::FloatRegister => matches!(operand, Operand::FloatRegister(_)),
+            OperandKind::Integer8 | OperandKind::SignedInteger8 => matches!(operand, Operand::Integer(_) | Operand::Expression(_)),
+            OperandKind::Integer16 | OperandKind::SignedInteger16 | OperandKind::Address | OperandKind::Offset | OperandKind::DataAddress =>
+                matches!(operand, Operand::Integer(_) | Operand::Label(_) | Operand::Expression(_)),
+        };
+        if !matches {
+            let span = instruction.operand_span(position);
+            return Err(ParserError::WrongOperandKind { span, opcode: instruction.opcode, position });
+        }
+    }
+    Ok(())
+}
+
+// A human readable description of a token for error messages
+fn describe(token: &TokenType) -> String {
+    match token {
+        TokenType::OPCODE(name) => format!("opcode `{}`", name),
+        TokenType::NUMBER(number) => format!("number `{}`", number),
+        TokenType::FLOAT(number) => format!("number `{}`", number),
+        TokenType::CHAR(c) => format!("character {:?}", c),
+        TokenType::STRING(text) => format!("string {:?}", text),
+        TokenType::REGISTER => String::from("`$`"),
+        TokenType::IntOperand => String::from("`#`"),
+        TokenType::LabelDeclaration(name) => format!("label `{}:`", name),
+        TokenType::LabelUsage(name) => format!("label `@{}`", name),
+        TokenType::DIRECTIVE(name) => format!("directive `.{}`", name),
+        TokenType::COMMA => String::from("`,`"),
+        TokenType::OPERATOR(c) => format!("`{}`", c),
+        TokenType::EOF => String::from("end of file"),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn span(line: usize, start: usize, end: usize) -> Span {
+        Span::new(0, line, start, end)
+    }
+
+    fn parse(source: &str) -> Result<Vec<Statement>, Vec<ParserError>> {
+        let mut lexer = Lexer::new();
+        lexer.lex_source(source);
+        Parser::new(lexer.tokens).parse().map(|program| program.statements)
+    }
+
+    // The instructions of a parse, without their spans
+    fn instructions(statements: &[Statement]) -> Vec<(Opcode, Vec<Operand>, usize)> {
+        statements.iter()
+            .filter_map(|statement| match statement {
+                Statement::Instruction(instruction) =>
+                    Some((instruction.opcode, instruction.operands.clone(), instruction.span.line)),
+                Statement::Label(..) | Statement::Directive(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_load_instruction() {
+        let statements = parse("LOAD $1 #1000").unwrap();
+        assert_eq!(instructions(&statements), vec![
+            (Opcode::LOAD, vec![Operand::Register(1), Operand::Integer(1000)], 1),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_multiple_lines() {
+        let statements = parse("LOAD $1 #10\nADD $1 $2 $3\nHLT").unwrap();
+        assert_eq!(statements.len(), 3);
+        assert_eq!(instructions(&statements)[2], (Opcode::HLT, vec![], 3));
+    }
+
+    #[test]
+    fn test_parse_labels() {
+        let statements = parse("start: LOAD $1 #10\nend:\nJEQ $1 @start").unwrap();
+        assert_eq!(statements[0], Statement::Label(String::from("start"), span(1, 0, 6)));
+        assert_eq!(statements[2], Statement::Label(String::from("end"), span(2, 0, 4)));
+        assert_eq!(instructions(&statements), vec![
+            (Opcode::LOAD, vec![Operand::Register(1), Operand::Integer(10)], 1),
+            (Opcode::JEQI, vec![Operand::Register(1), Operand::Label(String::from("start"))], 3),
+        ]);
+    }
+
+    #[test]
+    fn test_instruction_and_operand_spans() {
+        let statements = parse("  LOAD $1 #1000").unwrap();
+        match &statements[0] {
+            Statement::Instruction(instruction) => {
+                assert_eq!(instruction.span, span(1, 2, 15));
+                assert_eq!(instruction.operand_spans, vec![span(1, 7, 9), span(1, 10, 15)]);
+            },
+            other => panic!("Expected an instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_wrong_operand_count() {
+        assert_eq!(
+            parse("ADD $1 $2"),
+            Err(vec![ParserError::WrongOperandCount { span: span(1, 0, 9), opcode: Opcode::ADD, expected: 3, found: 2 }]),
+        );
+        assert_eq!(
+            parse("HLT $1"),
+            Err(vec![ParserError::WrongOperandCount { span: span(1, 0, 6), opcode: Opcode::HLT, expected: 0, found: 1 }]),
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_opcode() {
+        assert_eq!(parse("LAOD $1 #10"), Err(vec![ParserError::UnknownOpcode(span(1, 0, 4), String::from("LAOD"))]));
+    }
+
+    #[test]
+    fn test_parse_register_without_number() {
+        assert_eq!(parse("JMP $\nHLT"), Err(vec![ParserError::ExpectedNumber(span(1, 4, 5))]));
+    }
+
+    #[test]
+    fn test_parse_operand_without_opcode() {
+        assert_eq!(parse("#10"), Err(vec![ParserError::UnexpectedToken(span(1, 0, 1), String::from("`#`"))]));
+    }
+
+    #[test]
+    fn test_parse_wrong_operand_kind() {
+        assert_eq!(parse("ADD $1 $2 #3\nJMP @end\nLOADB $1 $2 @end"), Err(vec![
+            ParserError::WrongOperandKind { span: span(1, 10, 12), opcode: Opcode::ADD, position: 2 },
+            ParserError::WrongOperandKind { span: span(3, 12, 16), opcode: Opcode::LOADB, position: 2 },
+        ]));
+    }
+
+    #[test]
+    fn test_parse_recovers_at_next_line() {
+        let mut lexer = Lexer::new();
+        lexer.lex_source("LAOD $1 #10\nstart: LOAD $1 #1\nADD $1 $2\n.dta 1\nJMP @start $1 $2 <x>\nHLT");
+        let mut parser = Parser::new(lexer.tokens);
+        let errors: Vec<String> = parser.parse().unwrap_err().iter().map(|e| e.message()).collect();
+        assert_eq!(errors, vec![
+            "Unknown opcode `LAOD`",
+            "ADD expects 3 operands but 2 were given",
+            "Unknown directive `.dta`",
+            "JMP expects 1 operands but 3 were given",
+        ]);
+        let statements = parse("start: JMP @start\n.data\nend:").unwrap();
+        assert_eq!(statements[0], Statement::Label(String::from("start"), span(1, 0, 6)));
+        assert!(matches!(&statements[1], Statement::Instruction(instruction) if instruction.opcode == Opcode::JMPI));
+        assert_eq!(statements[3], Statement::Label(String::from("end"), span(3, 0, 4)));
+    }
+
+    #[test]
+    fn test_parse_float_registers() {
+        let statements = parse("ADDF $f0, $F1, $f31\nITOF $1 $f2").unwrap();
+        assert_eq!(instructions(&statements), vec![
+            (Opcode::ADDF, vec![Operand::FloatRegister(0), Operand::FloatRegister(1), Operand::FloatRegister(31)], 1),
+            (Opcode::ITOF, vec![Operand::Register(1), Operand::FloatRegister(2)], 2),
+        ]);
+        assert_eq!(parse("ADDF $f0 $1 $f2"), Err(vec![
+            ParserError::WrongOperandKind { span: span(1, 9, 11), opcode: Opcode::ADDF, position: 1 },
+        ]));
+        assert_eq!(parse("ITOF $1 $f300"), Err(vec![ParserError::InvalidNumber(span(1, 9, 13), String::from("300"))]));
+    }
+
+    #[test]
+    fn test_parse_commas() {
+        let statements = parse("ADD $1, $2, $3").unwrap();
+        assert_eq!(instructions(&statements), vec![
+            (Opcode::ADD, vec![Operand::Register(1), Operand::Register(2), Operand::Register(3)], 1),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_directives() {
+        let statements = parse(".data\nmsg: .asciiz \"hi\", \"you\"\n.byte 1 'a'").unwrap();
+        assert_eq!(statements, vec![
+            Statement::Directive(Directive::new(DirectiveKind::Data, vec![], span(1, 0, 5))),
+            Statement::Label(String::from("msg"), span(2, 0, 4)),
+            Statement::Directive(Directive {
+                kind: DirectiveKind::Asciiz,
+                arguments: vec![Argument::String(String::from("hi")), Argument::String(String::from("you"))],
+                span: span(2, 5, 24),
+                argument_spans: vec![span(2, 13, 17), span(2, 19, 24)],
+            }),
+            Statement::Directive(Directive {
+                kind: DirectiveKind::Byte,
+                arguments: vec![Argument::Integer(1), Argument::Integer(97)],
+                span: span(3, 0, 11),
+                argument_spans: vec![span(3, 6, 7), span(3, 8, 11)],
+            }),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_directive_errors() {
+        assert_eq!(parse(".dta"), Err(vec![ParserError::UnknownDirective(span(1, 0, 4), String::from("dta"))]));
+        assert_eq!(parse(".word $1"), Err(vec![ParserError::UnexpectedToken(span(1, 6, 7), String::from("`$`"))]));
+    }
+
+    #[test]
+    fn test_parse_expressions() {
+        let statements = parse("LOAD $1 #SIZE*4+1 | 2\nLOAD $2, #-(end - @start)\nLOAD $3 #hi(0x1234)").unwrap();
+        let operands: Vec<String> = instructions(&statements).iter()
+            .map(|(_, operands, _)| match &operands[1] {
+                Operand::Expression(expression) => expression.to_string(),
+                other => panic!("Expected an expression, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(operands, vec!["(((SIZE * 4) + 1) | 2)", "-(end - start)", "hi(4660)"]);
+        match &statements[0] {
+            Statement::Instruction(instruction) => assert_eq!(instruction.operand_spans[1], span(1, 8, 21)),
+            other => panic!("Expected an instruction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_expression_errors() {
+        assert_eq!(parse("LOAD $1 #(2 + 3"), Err(vec![ParserError::UnclosedParen(span(1, 9, 10))]));
+        assert_eq!(parse("LOAD $1 #low(3)"), Err(vec![ParserError::UnknownFunction(span(1, 9, 12), String::from("low"))]));
+        assert_eq!(parse("LOAD $1 #2 *"), Err(vec![ParserError::ExpectedNumber(span(1, 8, 9))]));
+    }
+}
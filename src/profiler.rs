@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use crate::decoder::DecodedInstruction;
+use crate::instructions::Opcode;
+use crate::object::{Symbol, Section};
+use crate::trace::{TraceSink, TraceRecord};
+
+// The most addresses listed in the hot spot report
+const REPORT_LIMIT: usize = 20;
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: u64,
+    by_address: HashMap<usize, (u64, DecodedInstruction)>,
+    by_opcode: HashMap<&'static str, u64>,
+    stacks: Vec<String>,        // Every call stack seen, outermost frame first and split by `;`, by id
+    stack_ids: HashMap<String, usize>,
+    calls: HashMap<(usize, usize), usize>,  // The stack a CALL from a stack to an address leads to
+    by_stack: Vec<u64>,         // Instructions run in each stack, by id
+    frames: Vec<usize>,         // The id of the stack after every CALL that has not returned, the current one last
+}
+
+impl Counts {
+    // The id of a stack, adding it if it was not seen before
+    fn intern(&mut self, stack: String) -> usize {
+        if let Some(id) = self.stack_ids.get(&stack) {
+            return *id;
+        }
+        let id = self.stacks.len();
+        self.stack_ids.insert(stack.clone(), id);
+        self.stacks.push(stack);
+        self.by_stack.push(0);
+        id
+    }
+}
+
+/* Counts how often every instruction runs. Register it with `VM::set_tracer` and keep a clone
+   to read the results, since clones share the same counts:
+
+    let profiler = Profiler::new(symbols);
+    vm.set_tracer(Some(Box::new(profiler.clone())));
+    vm.run();
+    print!("{}", profiler.report());
+
+ Addresses are named after the closest code label at or before them, so with a symbol table
+ the counts are also added up per label. CALL and RET are followed to build folded stacks,
+ one `frame;frame count` line per call stack, which flamegraph tools read directly.
+*/
+#[derive(Debug, Default, Clone)]
+pub struct Profiler {
+    symbols: Arc<Vec<Symbol>>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Profiler {
+    pub fn new(symbols: Vec<Symbol>) -> Profiler {
+        let mut symbols: Vec<Symbol> = symbols.into_iter().filter(|symbol| symbol.section == Section::Code).collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+        Profiler { symbols: Arc::new(symbols), counts: Arc::default() }
+    }
+
+    // How many instructions ran
+    pub fn total(&self) -> u64 {
+        self.counts.lock().unwrap().total
+    }
+
+    // How many times the instruction at `address` ran
+    pub fn count_at(&self, address: usize) -> u64 {
+        self.counts.lock().unwrap().by_address.get(&address).map_or(0, |(count, _)| *count)
+    }
+
+    // How many times `opcode` ran
+    pub fn count_of(&self, opcode: Opcode) -> u64 {
+        self.counts.lock().unwrap().by_opcode.get(opcode.mnemonic()).copied().unwrap_or(0)
+    }
+
+    // The closest code label at or before `address`
+    pub fn label(&self, address: usize) -> Option<&str> {
+        let after = self.symbols.partition_point(|symbol| symbol.address as usize <= address);
+        after.checked_sub(1).map(|index| self.symbols[index].name.as_str())
+    }
+
+    // Counts per label, busiest first. Addresses before the first label are counted as `?`
+    pub fn by_label(&self) -> Vec<(String, u64)> {
+        let counts = self.counts.lock().unwrap();
+        let mut labels: HashMap<String, u64> = HashMap::new();
+        for (address, (count, _)) in &counts.by_address {
+            *labels.entry(self.label(*address).unwrap_or("?").to_string()).or_default() += count;
+        }
+        sorted(labels.into_iter().collect())
+    }
+
+    // The hot spot report: the busiest addresses, then the counts per opcode and per label
+    pub fn report(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let total = counts.total.max(1) as f64;
+        let mut report = String::new();
+        let _ = writeln!(report, "Instructions executed: {}", counts.total);
+
+        let mut addresses: Vec<(usize, u64, DecodedInstruction)> = counts.by_address.iter()
+            .map(|(address, (count, instruction))| (*address, *count, *instruction))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let _ = writeln!(report, "\nHot spots:");
+        for (address, count, instruction) in addresses.iter().take(REPORT_LIMIT) {
+            let label = self.label(*address).map(|label| format!("  ({})", label)).unwrap_or_default();
+            let _ = writeln!(report, "{:>12} {:>6.2}%  {:04X}: {}{}", count, *count as f64 * 100.0 / total, address, instruction, label);
+        }
+
+        let _ = writeln!(report, "\nBy opcode:");
+        let opcodes = sorted(counts.by_opcode.iter().map(|(name, count)| (name.to_string(), *count)).collect());
+        for (name, count) in opcodes {
+            let _ = writeln!(report, "{:>12} {:>6.2}%  {}", count, count as f64 * 100.0 / total, name);
+        }
+        drop(counts);
+
+        if !self.symbols.is_empty() {
+            let _ = writeln!(report, "\nBy label:");
+            for (label, count) in self.by_label() {
+                let _ = writeln!(report, "{:>12} {:>6.2}%  {}", count, count as f64 * 100.0 / total, label);
+            }
+        }
+        report
+    }
+
+    // Every call stack and how many instructions ran in it, in the folded format
+    pub fn folded(&self) -> String {
+        let counts = self.counts.lock().unwrap();
+        let mut stacks: Vec<(&String, &u64)> = counts.stacks.iter().zip(&counts.by_stack)
+            .filter(|(_, count)| **count > 0)
+            .collect();
+        stacks.sort();
+        stacks.iter().map(|(stack, count)| format!("{} {}\n", stack, count)).collect()
+    }
+
+    // Names a frame after its label, or its address without symbols
+    fn frame_name(&self, address: usize) -> String {
+        match self.label(address) {
+            Some(label) => label.to_string(),
+            None => format!("{:04X}", address),
+        }
+    }
+}
+
+impl TraceSink for Profiler {
+    fn record(&mut self, record: &TraceRecord) {
+        let mut counts = self.counts.lock().unwrap();
+        // The first instruction names the outermost frame
+        if counts.frames.is_empty() {
+            let root = counts.intern(self.frame_name(record.pc));
+            counts.frames.push(root);
+        }
+
+        counts.total += 1;
+        counts.by_address.entry(record.pc).or_insert((0, record.instruction)).0 += 1;
+        *counts.by_opcode.entry(record.instruction.opcode.mnemonic()).or_default() += 1;
+        let stack = *counts.frames.last().unwrap();
+        counts.by_stack[stack] += 1;
+
+        match record.instruction.opcode {
+            Opcode::CALL => {
+                // Stacks are only named the first time a call leads to them
+                let target = record.instruction.operands[0] as usize;
+                let callee = match counts.calls.get(&(stack, target)) {
+                    Some(callee) => *callee,
+                    None => {
+                        let name = format!("{};{}", counts.stacks[stack], self.frame_name(target));
+                        let callee = counts.intern(name);
+                        counts.calls.insert((stack, target), callee);
+                        callee
+                    },
+                };
+                counts.frames.push(callee);
+            },
+            // Returning from the outermost frame is an error, which ends the program anyway
+            Opcode::RET if counts.frames.len() > 1 => {
+                counts.frames.pop();
+            },
+            _ => (),
+        }
+    }
+}
+
+// Busiest first, then by name
+fn sorted(mut counts: Vec<(String, u64)>) -> Vec<(String, u64)> {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    counts
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::{VM, ExitReason};
+
+    fn profile(source: &str) -> Profiler {
+        let object = Assembler::new().assemble_object(source).unwrap();
+        let profiler = Profiler::new(object.symbols.clone().unwrap_or_default());
+        let mut test_vm = VM::new();
+        test_vm.load_object(object);
+        test_vm.set_tracer(Some(Box::new(profiler.clone())));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        profiler
+    }
+
+    #[test]
+    fn test_counts() {
+        let profiler = profile("main: LOAD $1 #3\nloop: CALL @square\nDEC $1\nJNZ @loop\nHLT\nsquare: MUL $1 $1 $2\nRET");
+        assert_eq!(profiler.total(), 17);
+        assert_eq!(profiler.count_at(4), 3);
+        assert_eq!(profiler.count_of(Opcode::MUL), 3);
+        // HLT comes after `loop`, so it is counted there
+        assert_eq!(profiler.by_label(), vec![
+            (String::from("loop"), 10),
+            (String::from("square"), 6),
+            (String::from("main"), 1),
+        ]);
+        assert_eq!(profiler.folded(), "main 11\nmain;square 6\n");
+    }
+
+    #[test]
+    fn test_report() {
+        let profiler = profile("LOAD $1 #2\nloop: DEC $1\nJNZ @loop\nHLT");
+        assert_eq!(profiler.report(), [
+            "Instructions executed: 6",
+            "",
+            "Hot spots:",
+            "           2  33.33%  0004: DEC $1  (loop)",
+            "           2  33.33%  0008: JNZ #4  (loop)",
+            "           1  16.67%  0000: LOAD $1 #2",
+            "           1  16.67%  000C: HLT  (loop)",
+            "",
+            "By opcode:",
+            "           2  33.33%  DEC",
+            "           2  33.33%  JNZ",
+            "           1  16.67%  HLT",
+            "           1  16.67%  LOAD",
+            "",
+            "By label:",
+            "           5  83.33%  loop",
+            "           1  16.67%  ?",
+            "",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_calls_into_the_same_label_share_a_stack() {
+        let symbol = |name: &str, address| Symbol { name: name.to_string(), section: Section::Code, address };
+        let profiler = Profiler::new(vec![symbol("square", 12), symbol("main", 0)]);
+        assert_eq!((profiler.label(0), profiler.label(11), profiler.label(12), profiler.label(100)), (Some("main"), Some("main"), Some("square"), Some("square")));
+        assert_eq!(Profiler::new(vec![symbol("square", 12)]).label(11), None);
+
+        let mut test_vm = VM::new();
+        // CALL 12, CALL 16, HLT, INC $1, RET
+        test_vm.set_program(vec![23, 0, 12, 0, 23, 0, 16, 0, 0, 0, 0, 0, 65, 1, 0, 0, 24, 0, 0, 0]);
+        test_vm.set_tracer(Some(Box::new(profiler.clone())));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(profiler.folded(), "main 3\nmain;square 3\n");
+    }
+
+    #[test]
+    fn test_folded_without_symbols() {
+        let profiler = Profiler::new(vec![]);
+        let mut test_vm = VM::new();
+        // CALL 8, HLT, RET
+        test_vm.set_program(vec![23, 0, 8, 0, 0, 0, 0, 0, 24, 0, 0, 0]);
+        test_vm.set_tracer(Some(Box::new(profiler.clone())));
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(profiler.folded(), "0000 2\n0000;0008 1\n");
+        assert!(!profiler.report().contains("By label"));
+    }
+}
@@ -1,14 +1,27 @@
-use crate::vm::VM;
+use crate::vm::ExitReason;
+use std::collections::VecDeque;
 use std::io;
 use std::io::Write;
 use std::num::ParseIntError;
-use crate::lexer::Lexer;
+use crate::assembler::Assembler;
+use crate::assembler::Checkpoint;
+use crate::disassembler::disassemble;
+use crate::debugger::{Debugger, StopReason};
+use crate::snapshot::Snapshot;
 
+// The most snapshots kept for .undo, and the most memory they may take up together. The
+// oldest are dropped first
+const UNDO_LIMIT: usize = 100;
+const UNDO_BYTE_LIMIT: usize = 16 * 1024 * 1024;
 
 pub struct REPL {
     command_buffer: Vec<String>,
     mode: Mode,
-    vm: VM      // The vm that the repel will use to execute the code
+    debugger: Debugger,     // Wraps the vm that the repl uses to execute the code
+    debugging: bool,        // In debug mode new code is only run by .step, .next and .continue
+    assembler: Assembler,   // Keeps the labels of every line entered so later lines can use them
+    undo: VecDeque<(Snapshot, Checkpoint)>,     // The vm and assembler before each line that changed them, newest last
+    undo_bytes: usize,      // The size of the snapshots in `undo`
 }
 
 // The mode that the VM is in
@@ -19,11 +32,21 @@ enum Mode {
     Assembly,
 }
 
+impl Default for REPL {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl REPL {
     pub fn new() -> REPL {
         REPL {
             command_buffer: vec![],     // stores the previous commands
-            vm: VM::new(),
+            debugger: Debugger::default(),
+            debugging: false,
+            assembler: repl_assembler(),
+            undo: VecDeque::new(),
+            undo_bytes: 0,
             mode: Mode::Nil,
         }
     }
@@ -89,14 +112,23 @@ impl REPL {
                 }
             },
             ".program" => {
-                for instruction in &self.vm.program {
+                for instruction in self.debugger.vm.program() {
                     println!("{}", instruction);
                 }
                 println!("End of program listing");
             },
+            ".disasm" => {
+                for instruction in disassemble(self.debugger.vm.program()) {
+                    println!("{}", instruction);
+                }
+                println!("End of disassembly");
+            },
             ".registers" => {
                 println!("Listing registers and all contents");
-                println!("{:?}", self.vm.registers);
+                println!("{:?}", self.debugger.vm.registers);
+                println!("sp: {}  fp: {}", self.debugger.vm.sp(), self.debugger.vm.fp());
+                println!("{:?}", self.debugger.vm.flags());
+                println!("{:?}", self.debugger.vm.float_registers);
                 println!("End of register listing");
             }
             ".mode" => {
@@ -105,35 +137,210 @@ impl REPL {
                     _ => println!("Current mode is: Hex"),
                 }
             }
+            ".debug" => {
+                self.debugging = true;
+                println!("Debug mode on. New code runs with .step, .next and .continue");
+                self.show_current_instruction();
+            },
+            ".enddebug" => {
+                self.debugging = false;
+                println!("Debug mode off");
+            },
+            ".undo" => match self.undo.pop_back() {
+                Some((snapshot, checkpoint)) => {
+                    self.undo_bytes -= snapshot.size();
+                    self.debugger.vm.restore(snapshot);
+                    self.assembler.rewind(checkpoint);
+                    self.debugger.set_symbols(self.assembler.object_symbols());
+                    println!("Undid the last change to the vm");
+                },
+                None => println!("Nothing to undo"),
+            },
+            _ if buf.starts_with(".memory") => self.memory(&buf[".memory".len()..]),
+            _ if self.debugging && buf.starts_with('.') => self.debug_command(buf),
             _ => {
-                match self.mode {
+                let before = self.checkpoint();
+                let applied = match self.mode {
                     Mode::Assembly => self.assembly_mode(buf),
                     Mode::Hex => self.hex_mode(buf),
                     _ => panic!("Invalid REPL mode"),
+                };
+                if applied {
+                    self.save_undo(before);
                 }
             }
         }
     }
 
-    fn assembly_mode(&mut self, buf: &str) {
-        let mut lexer = Lexer::new();
-        lexer.lex_line(buf, 1);
-        lexer.lex_debug();
+    // Hex dumps a range of the heap. EX: `.memory 16 32` dumps 32 bytes starting at 16.
+    // Without arguments the whole heap is dumped
+    fn memory(&mut self, args: &str) {
+        let args: Vec<&str> = args.split_whitespace().collect();
+        let heap = &self.debugger.vm.heap;
+        let (start, length) = match args.as_slice() {
+            [] => (0, heap.len()),
+            [start] => (start.parse().unwrap_or(usize::MAX), heap.len()),
+            [start, length] => (start.parse().unwrap_or(usize::MAX), length.parse().unwrap_or(0)),
+            _ => (usize::MAX, 0),
+        };
+        if start > heap.len() {
+            println!("Usage: .memory <start> <length> (the heap is {} bytes)", heap.len());
+            return;
+        }
+        let end = heap.len().min(start.saturating_add(length));
+
+        println!("Listing heap bytes {} to {}", start, end);
+        for (i, chunk) in heap[start..end].chunks(16).enumerate() {
+            let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+            println!("{:04X}: {}", start + i * 16, bytes.join(" "));
+        }
+        println!("End of memory listing");
     }
 
-    fn hex_mode(&mut self, buf: &str) {
-        let results = self.parse_hex(buf);
-        match results {
+    // The commands of the debug sub mode
+    fn debug_command(&mut self, buf: &str) {
+        let args: Vec<&str> = buf.split_whitespace().collect();
+        match args.as_slice() {
+            [".break"] => {
+                for address in self.debugger.breakpoints() {
+                    println!("Breakpoint at {:04X}", address);
+                }
+            },
+            [".break", location] => match self.debugger.add_breakpoint(location) {
+                Ok(address) => println!("Breakpoint set at {:04X}", address),
+                Err(e) => println!("Error: {}", e),
+            },
+            [".delete", location] => match self.debugger.remove_breakpoint(location) {
+                Ok(address) => println!("Breakpoint at {:04X} deleted", address),
+                Err(e) => println!("Error: {}", e),
+            },
+            [".watch", register] => {
+                let result = parse_register(register)
+                    .and_then(|register| self.debugger.watch(register).map(|_| register));
+                match result {
+                    Ok(register) => println!("Watching ${}", register),
+                    Err(e) => println!("Error: {}", e),
+                }
+            },
+            [".unwatch", register] => {
+                let result = parse_register(register)
+                    .and_then(|register| self.debugger.unwatch(register).map(|_| register));
+                match result {
+                    Ok(register) => println!("No longer watching ${}", register),
+                    Err(e) => println!("Error: {}", e),
+                }
+            },
+            [".step"] => self.report(|debugger| debugger.step()),
+            [".next"] => self.report(|debugger| debugger.step_over()),
+            [".continue"] => self.report(|debugger| debugger.cont()),
+            [".bt"] => {
+                for (depth, frame) in self.debugger.backtrace().iter().enumerate() {
+                    match &frame.label {
+                        Some(label) => println!("#{} {:04X} in {}", depth, frame.address, label),
+                        None => println!("#{} {:04X}", depth, frame.address),
+                    }
+                }
+            },
+            _ => println!("Debug commands: .break <address|label>, .delete <address|label>, .watch $<register>, .unwatch $<register>, .step, .next, .continue, .bt, .undo, .enddebug"),
+        }
+    }
+
+    // The vm and the assembled labels and data as they are now, for .undo to go back to
+    fn checkpoint(&self) -> (Snapshot, Checkpoint) {
+        (self.debugger.vm.snapshot(), self.assembler.checkpoint())
+    }
+
+    // Keeps a checkpoint taken before a change that went through, dropping the oldest ones
+    // once there are too many or they take up too much memory
+    fn save_undo(&mut self, checkpoint: (Snapshot, Checkpoint)) {
+        self.undo_bytes += checkpoint.0.size();
+        self.undo.push_back(checkpoint);
+        while self.undo.len() > UNDO_LIMIT || self.undo_bytes > UNDO_BYTE_LIMIT {
+            if let Some((snapshot, _)) = self.undo.pop_front() {
+                self.undo_bytes -= snapshot.size();
+            }
+        }
+    }
+
+    // Runs the debugger and shows why it stopped and where
+    fn report(&mut self, run: impl FnOnce(&mut Debugger) -> StopReason) {
+        let before = self.checkpoint();
+        let reason = run(&mut self.debugger);
+        // Nothing ran when the program had already ended
+        if self.debugger.vm.pc() != before.0.pc {
+            self.save_undo(before);
+        }
+        match reason {
+            StopReason::Stepped => (),
+            StopReason::Breakpoint(address) => println!("Breakpoint at {:04X}", address),
+            StopReason::Watchpoint { register, old, new } => println!("${} changed from {} to {}", register, old, new),
+            StopReason::Exited(ExitReason::Halted) => println!("HLT encountered"),
+            StopReason::Exited(ExitReason::Exit(code)) => println!("Program exited with code {}", code),
+            StopReason::Exited(_) => println!("End of program"),
+            StopReason::Error(e) => println!("Error: {}", e),
+        }
+        self.show_current_instruction();
+    }
+
+    fn show_current_instruction(&self) {
+        if self.debugger.vm.pc() < self.debugger.vm.program().len() {
+            println!("=> {}", self.debugger.current_instruction());
+        }
+    }
+
+    // Assembles a line after the code entered so far, so it can jump to labels declared on
+    // earlier lines and the debugger can break on them. Returns whether the line was added
+    fn assembly_mode(&mut self, buf: &str) -> bool {
+        let base = self.debugger.vm.program().len();
+        match self.assembler.assemble_at(buf, base) {
             Ok(bytes) => {
                 for byte in bytes {
-                    self.vm.add_byte(byte)
+                    self.debugger.vm.add_byte(byte)
                 }
+                self.debugger.vm.rodata = self.assembler.rodata().to_vec();
+                self.debugger.set_symbols(self.assembler.object_symbols());
+                self.execute();
+                true
+            },
+            Err(errors) => {
+                for error in errors {
+                    print!("{}", self.assembler.render(&error));
+                }
+                false
+            },
+        }
+    }
+
+    // Returns whether the bytes were added
+    fn hex_mode(&mut self, buf: &str) -> bool {
+        match self.parse_hex(buf) {
+            Ok(bytes) => {
+                for byte in bytes {
+                    self.debugger.vm.add_byte(byte)
+                }
+                self.execute();
+                true
             },
             Err(_e) => {
                 println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters.");
+                false
             },
-        };
-        self.vm.run_once();
+        }
+    }
+
+    // Runs the next instruction in the vm and reports how it went. While debugging the
+    // debugger decides when code runs
+    fn execute(&mut self) {
+        if self.debugging {
+            self.show_current_instruction();
+            return;
+        }
+        match self.debugger.vm.run_once() {
+            Ok(ExitReason::Halted) => println!("HLT encountered"),
+            Ok(ExitReason::Exit(code)) => println!("Program exited with code {}", code),
+            Ok(_) => (),
+            Err(e) => println!("Error: {}", e),
+        }
     }
 
     // Accepts a hexadecimal string WITHOUT a leading `0x` and returns a Vec of u8
@@ -144,7 +351,7 @@ impl REPL {
         let mut results: Vec<u8> = vec![];
 
         for hex_string in split {
-            let byte = u8::from_str_radix(&hex_string, 16);
+            let byte = u8::from_str_radix(hex_string, 16);
             match byte {
                 Ok(result) => results.push(result),
                 Err(error) => return Err(error),
@@ -153,4 +360,150 @@ impl REPL {
 
         Ok(results)
     }
+}
+
+fn repl_assembler() -> Assembler {
+    let mut assembler = Assembler::new();
+    assembler.set_source_name("<repl>");
+    assembler
+}
+
+fn parse_register(register: &str) -> Result<usize, String> {
+    register.trim_start_matches('$').parse()
+        .map_err(|_| format!("`{}` is not a register", register))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assembly_repl() -> REPL {
+        let mut repl = REPL::new();
+        repl.mode = Mode::Assembly;
+        repl
+    }
+
+    #[test]
+    fn test_labels_across_lines() {
+        let mut repl = assembly_repl();
+        for line in &["LOAD $1 #2", "loop: DEC $1", "JNZ @loop"] {
+            repl.parse_input(line);
+        }
+        // The jump goes back to `loop`, so the next line runs DEC again
+        assert_eq!(repl.debugger.vm.program().len(), 12);
+        assert_eq!(repl.debugger.vm.pc(), 4);
+        repl.parse_input("HLT");
+        assert_eq!(repl.debugger.vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_break_on_label() {
+        let mut repl = assembly_repl();
+        repl.parse_input(".debug");
+        for line in &["LOAD $1 #2", "loop: DEC $1", "JNZ @loop", "HLT", ".break loop"] {
+            repl.parse_input(line);
+        }
+        assert_eq!(repl.debugger.breakpoints(), vec![4]);
+
+        repl.parse_input(".continue");
+        assert_eq!((repl.debugger.vm.pc(), repl.debugger.vm.registers[1]), (4, 2));
+        repl.parse_input(".continue");
+        assert_eq!((repl.debugger.vm.pc(), repl.debugger.vm.registers[1]), (4, 1));
+    }
+
+    #[test]
+    fn test_delete_and_unwatch() {
+        let mut repl = assembly_repl();
+        repl.parse_input(".debug");
+        for line in &["LOAD $1 #2", "loop: DEC $1", "JNZ @loop", "HLT", ".break loop", ".break 0xC"] {
+            repl.parse_input(line);
+        }
+        repl.parse_input(".delete loop");
+        repl.parse_input(".delete nowhere");
+        assert_eq!(repl.debugger.breakpoints(), vec![12]);
+
+        repl.parse_input(".watch $1");
+        repl.parse_input(".unwatch $1");
+        repl.parse_input(".continue");
+        // Neither the deleted breakpoint nor the watchpoint stopped the loop
+        assert_eq!((repl.debugger.vm.pc(), repl.debugger.vm.registers[1]), (12, 0));
+        assert!(repl.debugger.unwatch(1).is_err());
+        assert!(repl.debugger.remove_breakpoint("loop").is_err());
+    }
+
+    #[test]
+    fn test_data_across_lines() {
+        let mut repl = assembly_repl();
+        for line in &[".data", "v: .double 2.5", ".code", "LOADF $f0 @v"] {
+            repl.parse_input(line);
+        }
+        assert_eq!(repl.debugger.vm.float_registers[0], 2.5);
+
+        // Undoing a data line takes its bytes and label back out of the assembler too
+        repl.parse_input(".data");
+        repl.parse_input("w: .double 4.5");
+        repl.parse_input(".undo");
+        assert_eq!(repl.assembler.rodata().len(), 8);
+        repl.parse_input("w: .double 1.5");
+        repl.parse_input(".code");
+        repl.parse_input("LOADF $f1 @w");
+        assert_eq!(repl.debugger.vm.float_registers[..2], [2.5, 1.5]);
+    }
+
+    #[test]
+    fn test_undo_forgets_labels() {
+        let mut repl = assembly_repl();
+        repl.parse_input(".debug");
+        repl.parse_input("start: LOAD $1 #2");
+        repl.parse_input(".undo");
+        repl.parse_input("start: LOAD $1 #3");
+        assert_eq!(repl.debugger.vm.program(), vec![1, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_undo_skips_lines_that_fail() {
+        let mut repl = assembly_repl();
+        repl.parse_input("LOAD $1 #2");
+        repl.parse_input("LAOD $1 #3");
+        repl.parse_input("JMP @nowhere");
+        assert_eq!(repl.undo.len(), 1);
+        repl.parse_input(".undo");
+        assert_eq!((repl.debugger.vm.program().len(), repl.debugger.vm.registers[1]), (0, 0));
+
+        let mut repl = REPL::new();
+        repl.mode = Mode::Hex;
+        repl.parse_input("01 01 zz 02");
+        assert!(repl.undo.is_empty());
+        assert!(repl.debugger.vm.program().is_empty());
+    }
+
+    #[test]
+    fn test_undo_steps() {
+        let mut repl = assembly_repl();
+        for line in &[".debug", "LOAD $1 #2", ".step", ".step"] {
+            repl.parse_input(line);
+        }
+        // The last step found nothing left to run
+        assert_eq!(repl.undo.len(), 2);
+        repl.parse_input(".undo");
+        assert_eq!((repl.debugger.vm.pc(), repl.debugger.vm.registers[1]), (0, 0));
+        assert_eq!(repl.debugger.vm.program().len(), 4);
+        repl.parse_input(".undo");
+        assert!(repl.debugger.vm.program().is_empty());
+    }
+
+    #[test]
+    fn test_undo_is_bounded_by_size() {
+        let mut repl = assembly_repl();
+        repl.debugger.vm.heap = vec![0; UNDO_BYTE_LIMIT / 3];
+        for _ in 0..4 {
+            repl.parse_input("INC $1");
+        }
+        assert_eq!(repl.undo.len(), 2);
+        assert_eq!(repl.undo_bytes, repl.undo.iter().map(|(snapshot, _)| snapshot.size()).sum::<usize>());
+        repl.parse_input(".undo");
+        repl.parse_input(".undo");
+        repl.parse_input(".undo");
+        assert_eq!((repl.debugger.vm.registers[1], repl.undo_bytes), (2, 0));
+    }
 }
\ No newline at end of file
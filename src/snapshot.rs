@@ -0,0 +1,261 @@
+use std::error;
+use std::fmt;
+use crate::object::{LoadError, Reader};
+use crate::vm::{Flags, OverflowPolicy, REGISTER_COUNT};
+
+/* The complete state of a paused VM, taken with `VM::snapshot` and put back with `VM::restore`.
+   The syscall handler, the cost table and the deadline belong to whoever runs the VM, so they
+   are not part of a snapshot.
+
+ On disk every number is stored high byte first:
+    0   magic           4 bytes, always `TFSN`
+    4   version         u16
+    6   pc              u32
+    10  fp              u32
+    14  remainder       u32
+    18  flags           u8, bit 0 zero, bit 1 negative, bit 2 overflow, bit 3 carry
+    19  overflow policy u8, 0 wrap, 1 saturate, 2 trap
+    20  registers       32 x i32
+    148 float registers 32 x f64 bits
+    404 stack limit     u64
+    412 heap limit      u64
+    420 program, read-only data, stack and heap, each as a u32 count followed by its items
+*/
+pub const MAGIC: [u8; 4] = *b"TFSN";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Snapshot {
+    pub registers: [i32; REGISTER_COUNT],
+    pub float_registers: [f64; REGISTER_COUNT],
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub rodata: Vec<u8>,
+    pub remainder: u32,
+    pub flags: Flags,
+    pub overflow_policy: OverflowPolicy,
+    pub stack: Vec<i32>,
+    pub stack_limit: usize,
+    pub fp: usize,
+    pub heap: Vec<u8>,
+    pub heap_limit: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated(&'static str),    // the part of the snapshot that is cut short
+    Invalid(String),            // why the state cannot be resumed
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "Not a Teflon snapshot (bad magic bytes)"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "Unsupported snapshot format version {}", version),
+            SnapshotError::Truncated(part) => write!(f, "The {} of the snapshot is truncated", part),
+            SnapshotError::Invalid(reason) => write!(f, "Invalid snapshot: {}", reason),
+            SnapshotError::TrailingBytes(count) => write!(f, "{} unexpected bytes after the end of the snapshot", count),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {}
+
+// The reader shared with the program file format only ever runs out of bytes
+impl From<LoadError> for SnapshotError {
+    fn from(error: LoadError) -> Self {
+        match error {
+            LoadError::Truncated(part) => SnapshotError::Truncated(part),
+            other => SnapshotError::Invalid(other.to_string()),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        for value in &[self.pc, self.fp, self.remainder as usize] {
+            bytes.extend_from_slice(&(*value as u32).to_be_bytes());
+        }
+        let flags = [self.flags.zero, self.flags.negative, self.flags.overflow, self.flags.carry];
+        bytes.push(flags.iter().enumerate().fold(0, |byte, (bit, set)| byte | (*set as u8) << bit));
+        bytes.push(match self.overflow_policy {
+            OverflowPolicy::Wrap => 0,
+            OverflowPolicy::Saturate => 1,
+            OverflowPolicy::Trap => 2,
+        });
+        for register in &self.registers {
+            bytes.extend_from_slice(&register.to_be_bytes());
+        }
+        for register in &self.float_registers {
+            bytes.extend_from_slice(&register.to_bits().to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.stack_limit as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.heap_limit as u64).to_be_bytes());
+
+        for section in &[&self.program, &self.rodata] {
+            bytes.extend_from_slice(&(section.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(section);
+        }
+        bytes.extend_from_slice(&(self.stack.len() as u32).to_be_bytes());
+        for value in &self.stack {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        bytes.extend_from_slice(&(self.heap.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.heap);
+        bytes
+    }
+
+    // Roughly how much memory the snapshot takes up
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Snapshot>() + self.program.len() + self.rodata.len()
+            + self.stack.len() * std::mem::size_of::<i32>() + self.heap.len()
+    }
+
+    // Reads and validates a snapshot written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let mut reader = Reader::new(&bytes[MAGIC.len()..]);
+        let version = reader.u16("header")?;
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let pc = reader.u32("header")? as usize;
+        let fp = reader.u32("header")? as usize;
+        let remainder = reader.u32("header")?;
+        let flags = reader.u8("header")?;
+        let flags = Flags {
+            zero: flags & 1 != 0,
+            negative: flags & (1 << 1) != 0,
+            overflow: flags & (1 << 2) != 0,
+            carry: flags & (1 << 3) != 0,
+        };
+        let overflow_policy = match reader.u8("header")? {
+            0 => OverflowPolicy::Wrap,
+            1 => OverflowPolicy::Saturate,
+            2 => OverflowPolicy::Trap,
+            other => return Err(SnapshotError::Invalid(format!("unknown overflow policy {}", other))),
+        };
+        let mut registers = [0; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = reader.u32("registers")? as i32;
+        }
+        let mut float_registers = [0.0; REGISTER_COUNT];
+        for register in float_registers.iter_mut() {
+            *register = f64::from_bits(reader.u64("float registers")?);
+        }
+        let stack_limit = reader.u64("limits")? as usize;
+        let heap_limit = reader.u64("limits")? as usize;
+
+        let length = reader.u32("program")? as usize;
+        let program = reader.take(length, "program")?.to_vec();
+        let length = reader.u32("read-only data")? as usize;
+        let rodata = reader.take(length, "read-only data")?.to_vec();
+        let length = reader.u32("stack")? as usize;
+        let stack = (0..length)
+            .map(|_| reader.u32("stack").map(|value| value as i32))
+            .collect::<Result<Vec<i32>, LoadError>>()?;
+        let length = reader.u32("heap")? as usize;
+        let heap = reader.take(length, "heap")?.to_vec();
+        if reader.remaining() > 0 {
+            return Err(SnapshotError::TrailingBytes(reader.remaining()));
+        }
+
+        // Jumps may go to the very end of the program, so pc can be the program's length
+        if pc > program.len() {
+            return Err(SnapshotError::Invalid(format!("pc {} is outside of the program", pc)));
+        }
+        if stack.len() > stack_limit || heap.len() > heap_limit {
+            return Err(SnapshotError::Invalid(String::from("the stack or heap is larger than its limit")));
+        }
+
+        Ok(Snapshot {
+            registers,
+            float_registers,
+            pc,
+            program,
+            rodata,
+            remainder,
+            flags,
+            overflow_policy,
+            stack,
+            stack_limit,
+            fp,
+            heap,
+            heap_limit,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vm::{VM, ExitReason};
+
+    // Counts $1 down from 5, pushing every value, then stores $1 on the heap
+    const PROGRAM: [u8; 36] = [
+        1, 1, 0, 5,     // LOAD $1 #5
+        1, 2, 0, 4,     // LOAD $2 #4
+        25, 2, 0, 0,    // ALOC $2
+        21, 1, 0, 0,    // PUSH $1
+        66, 1, 0, 0,    // DEC $1
+        44, 0, 12, 0,   // JNZ 12
+        59, 1, 3, 0,    // ITOF $1 $f3
+        31, 1, 0, 0,    // STOREM $1 $0 #0
+        0, 0, 0, 0,     // HLT
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        let mut test_vm = VM::new();
+        test_vm.set_program(PROGRAM.to_vec());
+        test_vm.rodata = b"hi\0".to_vec();
+        test_vm.overflow_policy = OverflowPolicy::Trap;
+        test_vm.float_registers[31] = -0.5;
+        assert_eq!(test_vm.run_with_fuel(8), Ok(ExitReason::OutOfFuel));
+        let snapshot = test_vm.snapshot();
+        assert_eq!(snapshot.stack, vec![5, 4]);
+        assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+    }
+
+    #[test]
+    fn test_resume_in_another_vm() {
+        let mut uninterrupted = VM::new();
+        uninterrupted.set_program(PROGRAM.to_vec());
+        assert_eq!(uninterrupted.run(), Ok(ExitReason::Halted));
+
+        let mut paused = VM::new();
+        paused.set_program(PROGRAM.to_vec());
+        for _ in 0..10 {
+            paused.run_once().unwrap();
+        }
+        let bytes = paused.snapshot().to_bytes();
+        let mut resumed = VM::new();
+        resumed.restore(Snapshot::from_bytes(&bytes).unwrap());
+        assert_eq!(resumed.run(), Ok(ExitReason::Halted));
+        assert_eq!(resumed.snapshot(), uninterrupted.snapshot());
+    }
+
+    #[test]
+    fn test_rejects_bad_snapshots() {
+        let bytes = VM::new().snapshot().to_bytes();
+        assert_eq!(Snapshot::from_bytes(b"TFLN"), Err(SnapshotError::BadMagic));
+        let mut version = bytes.clone();
+        version[5] = 9;
+        assert_eq!(Snapshot::from_bytes(&version), Err(SnapshotError::UnsupportedVersion(9)));
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]), Err(SnapshotError::Truncated("heap")));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Snapshot::from_bytes(&trailing), Err(SnapshotError::TrailingBytes(1)));
+        let mut pc = bytes;
+        pc[9] = 4;
+        assert!(matches!(Snapshot::from_bytes(&pc), Err(SnapshotError::Invalid(_))));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+use crate::vm::REGISTER_COUNT;
+
+/* The system calls a program makes with `SYSCALL $r`. The call number is the value of
+   register r, its argument is in $0 and any result is written back to $0.
+
+    1 => print the integer in $0
+    2 => print the NUL terminated string at offset $0 of the read-only data
+    3 => read an integer from the input into $0
+    4 => exit with the code in $0
+*/
+pub const PRINT_INT: i32 = 1;
+pub const PRINT_STRING: i32 = 2;
+pub const READ_INT: i32 = 3;
+pub const EXIT: i32 = 4;
+
+// The parts of the VM a system call may look at or change
+pub struct SyscallContext<'a> {
+    pub registers: &'a mut [i32; REGISTER_COUNT],
+    pub rodata: &'a [u8],
+    pub heap: &'a mut Vec<u8>,
+}
+
+// What the VM should do once a system call returns
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SyscallResult {
+    Continue,
+    Exit(i32),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SyscallError {
+    Unknown,
+    Failed(String),     // why the call could not be completed
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::Unknown => write!(f, "unknown system call"),
+            SyscallError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+// Handles the system calls of a VM. Register one with `VM::set_syscall_handler`
+pub trait SyscallHandler: Send {
+    fn syscall(&mut self, number: i32, context: &mut SyscallContext) -> Result<SyscallResult, SyscallError>;
+}
+
+// The default handler, which prints to stdout and reads from stdin
+#[derive(Debug, Default)]
+pub struct StdioHandler;
+
+impl SyscallHandler for StdioHandler {
+    fn syscall(&mut self, number: i32, context: &mut SyscallContext) -> Result<SyscallResult, SyscallError> {
+        let stdout = io::stdout();
+        let result = standard_syscall(number, context, &mut stdout.lock(), || {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)
+                .map_err(|e| SyscallError::Failed(format!("unable to read from stdin: {}", e)))?;
+            Ok(line)
+        });
+        stdout.lock().flush().map_err(|e| SyscallError::Failed(e.to_string()))?;
+        result
+    }
+}
+
+/* A handler that collects everything a program prints and feeds it queued input, so
+   programs can be tested. Clones share the same buffers:
+
+    let handler = CaptureHandler::new();
+    vm.set_syscall_handler(Box::new(handler.clone()));
+    vm.run();
+    assert_eq!(handler.output(), "42");
+*/
+#[derive(Debug, Default, Clone)]
+pub struct CaptureHandler {
+    output: Arc<Mutex<Vec<u8>>>,
+    input: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl CaptureHandler {
+    pub fn new() -> CaptureHandler {
+        CaptureHandler::default()
+    }
+
+    // Queues a line of input for the next read
+    pub fn push_input(&self, line: &str) {
+        self.input.lock().unwrap().push_back(line.to_string());
+    }
+
+    // Everything printed so far
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output.lock().unwrap()).into_owned()
+    }
+}
+
+impl SyscallHandler for CaptureHandler {
+    fn syscall(&mut self, number: i32, context: &mut SyscallContext) -> Result<SyscallResult, SyscallError> {
+        let input = self.input.clone();
+        let mut output = self.output.lock().unwrap();
+        standard_syscall(number, context, &mut *output, || {
+            input.lock().unwrap().pop_front()
+                .ok_or_else(|| SyscallError::Failed(String::from("no input left to read")))
+        })
+    }
+}
+
+// The system calls both built in handlers share. `read_line` supplies the next line of input
+fn standard_syscall<W: Write>(
+    number: i32,
+    context: &mut SyscallContext,
+    output: &mut W,
+    read_line: impl FnOnce() -> Result<String, SyscallError>,
+) -> Result<SyscallResult, SyscallError> {
+    let write_error = |e: io::Error| SyscallError::Failed(format!("unable to write output: {}", e));
+    match number {
+        PRINT_INT => {
+            write!(output, "{}", context.registers[0]).map_err(write_error)?;
+        },
+        PRINT_STRING => {
+            let string = rodata_string(context.rodata, context.registers[0])?;
+            output.write_all(string).map_err(write_error)?;
+        },
+        READ_INT => {
+            let line = read_line()?;
+            context.registers[0] = line.trim().parse()
+                .map_err(|_| SyscallError::Failed(format!("`{}` is not an integer", line.trim())))?;
+        },
+        EXIT => return Ok(SyscallResult::Exit(context.registers[0])),
+        _ => return Err(SyscallError::Unknown),
+    }
+    Ok(SyscallResult::Continue)
+}
+
+// The bytes of the NUL terminated string that starts at `offset` in the read-only data
+fn rodata_string(rodata: &[u8], offset: i32) -> Result<&[u8], SyscallError> {
+    if offset < 0 || offset as usize >= rodata.len() {
+        return Err(SyscallError::Failed(format!("string offset {} is outside of the read-only data", offset)));
+    }
+    let bytes = &rodata[offset as usize..];
+    match bytes.iter().position(|byte| *byte == 0) {
+        Some(end) => Ok(&bytes[..end]),
+        None => Err(SyscallError::Failed(format!("string at offset {} is not terminated", offset))),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(handler: &mut CaptureHandler, number: i32, registers: &mut [i32; REGISTER_COUNT], rodata: &[u8]) -> Result<SyscallResult, SyscallError> {
+        let mut heap = vec![];
+        let mut context = SyscallContext { registers, rodata, heap: &mut heap };
+        handler.syscall(number, &mut context)
+    }
+
+    #[test]
+    fn test_print_int_and_string() {
+        let mut handler = CaptureHandler::new();
+        let mut registers = [0; REGISTER_COUNT];
+        let rodata = b"x\0hello\0";
+        registers[0] = -12;
+        assert_eq!(call(&mut handler, PRINT_INT, &mut registers, rodata), Ok(SyscallResult::Continue));
+        registers[0] = 2;
+        assert_eq!(call(&mut handler, PRINT_STRING, &mut registers, rodata), Ok(SyscallResult::Continue));
+        assert_eq!(handler.output(), "-12hello");
+    }
+
+    #[test]
+    fn test_print_string_errors() {
+        let mut handler = CaptureHandler::new();
+        let mut registers = [0; REGISTER_COUNT];
+        registers[0] = 5;
+        assert!(call(&mut handler, PRINT_STRING, &mut registers, b"abc\0").is_err());
+        registers[0] = 0;
+        assert!(call(&mut handler, PRINT_STRING, &mut registers, b"abc").is_err());
+    }
+
+    #[test]
+    fn test_read_int() {
+        let mut handler = CaptureHandler::new();
+        let mut registers = [0; REGISTER_COUNT];
+        handler.push_input("42\n");
+        handler.push_input("forty");
+        assert_eq!(call(&mut handler, READ_INT, &mut registers, &[]), Ok(SyscallResult::Continue));
+        assert_eq!(registers[0], 42);
+        assert!(call(&mut handler, READ_INT, &mut registers, &[]).is_err());
+        assert!(call(&mut handler, READ_INT, &mut registers, &[]).is_err());
+    }
+
+    #[test]
+    fn test_exit_and_unknown() {
+        let mut handler = CaptureHandler::new();
+        let mut registers = [0; REGISTER_COUNT];
+        registers[0] = 3;
+        assert_eq!(call(&mut handler, EXIT, &mut registers, &[]), Ok(SyscallResult::Exit(3)));
+        assert_eq!(call(&mut handler, 99, &mut registers, &[]), Err(SyscallError::Unknown));
+    }
+}
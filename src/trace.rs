@@ -0,0 +1,311 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::decoder::DecodedInstruction;
+use crate::instructions::OperandKind;
+
+// A register of either bank
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Register {
+    Integer(usize),
+    Float(usize),
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::Integer(register) => write!(f, "${}", register),
+            Register::Float(register) => write!(f, "$f{}", register),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+    Integer(i32),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegisterRead {
+    pub register: Register,
+    pub value: Value,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct RegisterWrite {
+    pub register: Register,
+    pub before: Value,
+    pub after: Value,
+}
+
+/* One executed instruction, with the registers it read and the registers it wrote. Records
+   are made even for instructions that stop with an error, in which case the written registers
+   usually keep their values.
+
+ EX: 0008: ADD $0 $1 $3  reads $0=15 $1=5  writes $3 0 -> 20
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub instruction: DecodedInstruction,
+    pub reads: Vec<RegisterRead>,
+    pub writes: Vec<RegisterWrite>,
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}: {}", self.pc, self.instruction)?;
+        if !self.reads.is_empty() {
+            write!(f, "  reads")?;
+            for read in &self.reads {
+                write!(f, " {}={}", read.register, read.value)?;
+            }
+        }
+        if !self.writes.is_empty() {
+            write!(f, "  writes")?;
+            for write in &self.writes {
+                write!(f, " {} {} -> {}", write.register, write.before, write.after)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TraceRecord {
+    // The record as a single line of JSON
+    pub fn to_json(&self) -> String {
+        let reads: Vec<String> = self.reads.iter()
+            .map(|read| format!("{{\"register\":\"{}\",\"value\":{}}}", read.register, json_value(read.value)))
+            .collect();
+        let writes: Vec<String> = self.writes.iter()
+            .map(|write| format!(
+                "{{\"register\":\"{}\",\"before\":{},\"after\":{}}}",
+                write.register, json_value(write.before), json_value(write.after),
+            ))
+            .collect();
+        format!(
+            "{{\"pc\":{},\"opcode\":\"{}\",\"instruction\":\"{}\",\"reads\":[{}],\"writes\":[{}]}}",
+            self.pc, self.instruction.opcode.mnemonic(), self.instruction, reads.join(","), writes.join(","),
+        )
+    }
+}
+
+// JSON has no infinities or NaN, so those are written as null
+fn json_value(value: Value) -> String {
+    match value {
+        Value::Float(value) if !value.is_finite() => String::from("null"),
+        value => value.to_string(),
+    }
+}
+
+// The registers an instruction reads and the registers it writes, in operand order, as the
+// instruction table describes them. A register read twice is only listed once
+pub fn accesses(instruction: &DecodedInstruction) -> (Vec<Register>, Vec<Register>) {
+    let (mut reads, mut writes) = (vec![], vec![]);
+    let info = match instruction.opcode.info() {
+        Some(info) => info,
+        None => return (reads, writes),
+    };
+    let operands = info.operands.iter().zip(info.access).zip(&instruction.operands)
+        .filter_map(|((kind, access), value)| match kind {
+            OperandKind::Register => Some((Register::Integer(*value as usize), *access)),
+            OperandKind::FloatRegister => Some((Register::Float(*value as usize), *access)),
+            _ => None,
+        });
+    let implicit = instruction.opcode.implicit_registers().iter()
+        .map(|(register, access)| (Register::Integer(*register), *access));
+    for (register, access) in operands.chain(implicit) {
+        if access.reads() && !reads.contains(&register) {
+            reads.push(register);
+        }
+        if access.writes() {
+            writes.push(register);
+        }
+    }
+    (reads, writes)
+}
+
+// Receives a record for every instruction a VM executes. Register one with `VM::set_tracer`.
+// Tracing never stops a program, so sinks that write somewhere ignore write errors
+pub trait TraceSink: Send {
+    fn record(&mut self, record: &TraceRecord);
+}
+
+// Sends every record to each sink in turn, so a trace and a profile can be taken in one run
+impl TraceSink for Vec<Box<dyn TraceSink>> {
+    fn record(&mut self, record: &TraceRecord) {
+        for sink in self.iter_mut() {
+            sink.record(record);
+        }
+    }
+}
+
+// Writes every record as a line of text
+pub struct TextSink<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> TextSink<W> {
+    pub fn new(writer: W) -> TextSink<W> {
+        TextSink { writer }
+    }
+}
+
+impl<W: Write + Send> TraceSink for TextSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        let _ = writeln!(self.writer, "{}", record);
+    }
+}
+
+// Writes every record as a line of JSON
+pub struct JsonLinesSink<W: Write + Send> {
+    writer: W,
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+    pub fn new(writer: W) -> JsonLinesSink<W> {
+        JsonLinesSink { writer }
+    }
+}
+
+impl<W: Write + Send> TraceSink for JsonLinesSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        let _ = writeln!(self.writer, "{}", record.to_json());
+    }
+}
+
+/* A sink that keeps every record so tests can look at them. Clones share the same records:
+
+    let sink = CaptureSink::new();
+    vm.set_tracer(Some(Box::new(sink.clone())));
+    vm.run();
+    assert_eq!(sink.records().len(), 3);
+*/
+#[derive(Debug, Default, Clone)]
+pub struct CaptureSink {
+    records: Arc<Mutex<Vec<TraceRecord>>>,
+}
+
+impl CaptureSink {
+    pub fn new() -> CaptureSink {
+        CaptureSink::default()
+    }
+
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl TraceSink for CaptureSink {
+    fn record(&mut self, record: &TraceRecord) {
+        self.records.lock().unwrap().push(record.clone());
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::decode_instruction;
+    use crate::instructions::INSTRUCTIONS;
+    use crate::syscall::CaptureHandler;
+    use crate::vm::{VM, REGISTER_COUNT};
+
+    fn decode(bytes: &[u8]) -> DecodedInstruction {
+        decode_instruction(bytes, 0).unwrap()
+    }
+
+    #[test]
+    fn test_accesses() {
+        assert_eq!(accesses(&decode(&[2, 0, 1, 3])), (vec![Register::Integer(0), Register::Integer(1)], vec![Register::Integer(3)]));
+        assert_eq!(accesses(&decode(&[65, 4, 0, 0])), (vec![Register::Integer(4)], vec![Register::Integer(4)]));
+        assert_eq!(accesses(&decode(&[59, 1, 2, 0])), (vec![Register::Integer(1)], vec![Register::Float(2)]));
+        assert_eq!(accesses(&decode(&[31, 1, 2, 4])), (vec![Register::Integer(1), Register::Integer(2)], vec![]));
+        assert_eq!(accesses(&decode(&[33, 5, 0, 0])), (vec![Register::Integer(5), Register::Integer(0)], vec![Register::Integer(0)]));
+    }
+
+    // Runs every instruction once with registers 1, 2 and 3 as its register operands and checks
+    // that the registers it changed are the ones the instruction table says it writes
+    #[test]
+    fn test_accesses_match_the_vm() {
+        for info in INSTRUCTIONS {
+            let mut bytes = vec![info.byte, 0, 0, 0];
+            let mut offset = 1;
+            for (position, kind) in info.operands.iter().enumerate() {
+                if matches!(kind, OperandKind::Register | OperandKind::FloatRegister) {
+                    bytes[offset] = position as u8 + 1;
+                }
+                offset += kind.width();
+            }
+            let instruction = decode(&bytes);
+
+            let mut test_vm = VM::new();
+            // PUSH $4 first so POP has something to pop
+            test_vm.set_program([vec![21, 4, 0, 0], bytes].concat());
+            test_vm.rodata = 2.5f64.to_be_bytes().to_vec();
+            test_vm.heap = vec![0x7F; 64];
+            for i in 0..REGISTER_COUNT {
+                test_vm.registers[i] = (i * i + 2) as i32;
+                test_vm.float_registers[i] = i as f64 * 1.5 + 0.25;
+            }
+            // $1 is 3, so SYSCALL $1 reads an integer into $0
+            let handler = CaptureHandler::new();
+            handler.push_input("42");
+            test_vm.set_syscall_handler(Box::new(handler));
+            test_vm.run_once().unwrap();
+
+            let (registers, float_registers) = (test_vm.registers, test_vm.float_registers);
+            let result = test_vm.run_once();
+            let changed: Vec<Register> = (0..REGISTER_COUNT)
+                .filter(|i| test_vm.registers[*i] != registers[*i]).map(Register::Integer)
+                .chain((0..REGISTER_COUNT).filter(|i| test_vm.float_registers[*i] != float_registers[*i]).map(Register::Float))
+                .collect();
+            let (_, writes) = accesses(&instruction);
+            assert!(changed.iter().all(|register| writes.contains(register)), "{} changed {:?}", instruction, changed);
+            if result.is_ok() {
+                assert_eq!(changed.len(), writes.len(), "{} changed {:?}", instruction, changed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_formats() {
+        let record = TraceRecord {
+            pc: 8,
+            instruction: decode(&[2, 0, 1, 3]),
+            reads: vec![
+                RegisterRead { register: Register::Integer(0), value: Value::Integer(15) },
+                RegisterRead { register: Register::Integer(1), value: Value::Integer(5) },
+            ],
+            writes: vec![RegisterWrite { register: Register::Integer(3), before: Value::Integer(0), after: Value::Integer(20) }],
+        };
+        assert_eq!(record.to_string(), "0008: ADD $0 $1 $3  reads $0=15 $1=5  writes $3 0 -> 20");
+        assert_eq!(
+            record.to_json(),
+            r#"{"pc":8,"opcode":"ADD","instruction":"ADD $0 $1 $3","reads":[{"register":"$0","value":15},{"register":"$1","value":5}],"writes":[{"register":"$3","before":0,"after":20}]}"#,
+        );
+
+        let mut sink = JsonLinesSink::new(vec![]);
+        let division = TraceRecord {
+            pc: 0,
+            instruction: decode(&[52, 0, 1, 2]),
+            reads: vec![],
+            writes: vec![RegisterWrite { register: Register::Float(2), before: Value::Float(0.0), after: Value::Float(f64::INFINITY) }],
+        };
+        sink.record(&division);
+        assert_eq!(
+            String::from_utf8(sink.writer).unwrap(),
+            "{\"pc\":0,\"opcode\":\"DIVF\",\"instruction\":\"DIVF $f0 $f1 $f2\",\"reads\":[],\"writes\":[{\"register\":\"$f2\",\"before\":0.0,\"after\":null}]}\n",
+        );
+    }
+}
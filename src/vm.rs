@@ -1,190 +1,698 @@
+use std::error;
+use std::fmt;
+use std::time::Instant;
 use crate::instructions::Opcode;
+use crate::assembler::instruction_parsers::INSTRUCTION_SIZE;
+use crate::snapshot::Snapshot;
+use crate::trace::{self, TraceSink, TraceRecord, RegisterRead, RegisterWrite, Value};
+use crate::decoder::{DecodedInstruction, decode_instruction, decode_program};
+use crate::object::{ObjectFile, LoadError};
+use crate::syscall::{SyscallHandler, SyscallContext, SyscallResult, SyscallError, StdioHandler};
+
+// The number of general purpose registers available to a program
+pub const REGISTER_COUNT: usize = 32;
+
+// The default number of values the call stack can hold
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+// The default number of bytes the heap may grow to
+pub const DEFAULT_HEAP_LIMIT: usize = 16 * 1024 * 1024;
+
+// How many instructions `run_with_fuel` executes between checks of the deadline
+const DEADLINE_CHECK_INTERVAL: u32 = 1024;
+
+// What executing each opcode costs `run_with_fuel`. Every opcode costs 1 until it is set, so by
+// default fuel is a count of instructions
+#[derive(Debug, PartialEq, Clone)]
+pub struct CostTable {
+    costs: [u64; 256],     // Indexed by opcode byte
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable { costs: [1; 256] }
+    }
+}
+
+impl CostTable {
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[u8::from(opcode) as usize]
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[u8::from(opcode) as usize] = cost;
+    }
+}
+
+// What ADD, SUB, MUL and DIV do when their signed result does not fit in 32 bits. Whatever the
+// policy, the result is the same in debug and release builds
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    #[default]
+    Wrap,       // Keep the low 32 bits of the result, like two's complement hardware
+    Saturate,   // Clamp the result to i32::MIN or i32::MAX
+    Trap,       // Stop with VmError::ArithmeticOverflow
+}
+
+// Status flags describing the result of the last ADD, SUB, MUL or DIV, tested by JZ, JNZ, JN, JO and JC
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Flags {
+    pub zero: bool,         // The result stored was 0
+    pub negative: bool,     // The result stored was below 0
+    pub overflow: bool,     // The signed result did not fit in 32 bits
+    pub carry: bool,        // The unsigned result did not fit in 32 bits (a borrow for SUB)
+}
+
+// Why a call to `run` or `run_once` stopped without an error
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitReason {
+    Halted,         // A HLT instruction was executed
+    EndOfProgram,   // The program counter ran past the last instruction
+    Stepped,        // `run_once` executed a single instruction and the program can continue
+    Exit(i32),      // The program made the exit system call with this code
+    OutOfFuel,      // `run_with_fuel` could not pay for the next instruction. The program can continue
+    DeadlineExceeded,   // `run_with_fuel` passed the deadline. The program can continue
+}
+
+// Errors that can occur while executing a program. `pc` is always the address of the
+// instruction that caused the error
+#[derive(Debug, PartialEq, Clone)]
+pub enum VmError {
+    IllegalOpcode { opcode: u8, pc: usize },
+    TruncatedInstruction { pc: usize },
+    InvalidRegister { register: u8, pc: usize },
+    DivisionByZero { pc: usize },
+    ArithmeticOverflow { pc: usize },
+    JumpOutOfRange { target: i64, pc: usize },
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    InvalidAllocation { bytes: i64, pc: usize },
+    MemoryOutOfBounds { address: i64, pc: usize },
+    DataOutOfBounds { address: usize, pc: usize },
+    UnknownSyscall { number: i32, pc: usize },
+    SyscallFailed { number: i32, reason: String, pc: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::IllegalOpcode { opcode, pc } => write!(f, "Illegal opcode {:#04X} at address {}", opcode, pc),
+            VmError::TruncatedInstruction { pc } => write!(f, "Instruction at address {} is missing operand bytes", pc),
+            VmError::InvalidRegister { register, pc } => write!(f, "Invalid register ${} used at address {}", register, pc),
+            VmError::DivisionByZero { pc } => write!(f, "Division by zero at address {}", pc),
+            VmError::ArithmeticOverflow { pc } => write!(f, "Arithmetic overflow at address {}", pc),
+            VmError::JumpOutOfRange { target, pc } => write!(f, "Jump to {} at address {} is outside of the program", target, pc),
+            VmError::StackOverflow { pc } => write!(f, "Stack overflow at address {}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "Stack underflow at address {}", pc),
+            VmError::InvalidAllocation { bytes, pc } => write!(f, "Unable to allocate {} bytes at address {}", bytes, pc),
+            VmError::MemoryOutOfBounds { address, pc } => write!(f, "Heap address {} used at address {} is out of bounds", address, pc),
+            VmError::DataOutOfBounds { address, pc } => write!(f, "Read-only data offset {} used at address {} is out of bounds", address, pc),
+            VmError::UnknownSyscall { number, pc } => write!(f, "Unknown system call {} at address {}", number, pc),
+            VmError::SyscallFailed { number, reason, pc } => write!(f, "System call {} at address {} failed: {}", number, pc, reason),
+        }
+    }
+}
+
+impl error::Error for VmError {}
 
 pub struct VM {
-    pub registers: [i32; 32],   // Use an array because we know the size at compile time 
-    pc: usize,                  // The program counter
-    pub program: Vec<u8>,       // A vector to store the program bytecode
-    remainder: u32,             // Contains the remainder of modulo division ops
+    pub registers: [i32; REGISTER_COUNT],   // Use an array because we know the size at compile time 
+    pub float_registers: [f64; REGISTER_COUNT],     // A separate bank for floating point numbers
+    pc: usize,                              // The program counter
+    program: Vec<u8>,                       // A vector to store the program bytecode
+    decoded: Vec<Option<DecodedInstruction>>,   // The program decoded ahead of time by `predecode`, cleared whenever `program` changes
+    pub rodata: Vec<u8>,                    // Read-only data, such as string constants, loaded with the program
+    remainder: u32,                         // Contains the remainder of modulo division ops
+    flags: Flags,                           // The status flags of the last arithmetic instruction
+    pub overflow_policy: OverflowPolicy,    // What arithmetic does when a result overflows
+    instruction_start: usize,               // Address of the instruction currently being executed
+    stack: Vec<i32>,                        // The call stack. Its length is the stack pointer
+    pub stack_limit: usize,                 // The most values the stack may hold
+    fp: usize,                              // The frame pointer, where the current call's frame starts
+    pub heap: Vec<u8>,                      // Byte addressable memory that grows with ALOC
+    pub heap_limit: usize,                  // The most bytes the heap may hold
+    syscall_handler: Box<dyn SyscallHandler>,   // Handles the SYSCALL instruction
+    pub costs: CostTable,                   // What each instruction costs `run_with_fuel`
+    fuel: u64,                              // The fuel `run_with_fuel` has left
+    pub deadline: Option<Instant>,          // When `run_with_fuel` stops, however much fuel is left
+    tracer: Option<Box<dyn TraceSink>>,     // Receives a record of every executed instruction
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
-            registers: [0; 32],  // initialize all registers to 0
+            registers: [0; REGISTER_COUNT],  // initialize all registers to 0
+            float_registers: [0.0; REGISTER_COUNT],
             pc: 0,
             program: vec![],
-            remainder: 0
+            decoded: vec![],
+            rodata: vec![],
+            remainder: 0,
+            flags: Flags::default(),
+            overflow_policy: OverflowPolicy::default(),
+            instruction_start: 0,
+            stack: vec![],
+            stack_limit: DEFAULT_STACK_LIMIT,
+            fp: 0,
+            heap: vec![],
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            syscall_handler: Box::new(StdioHandler),
+            costs: CostTable::default(),
+            fuel: 0,
+            deadline: None,
+            tracer: None,
+        }
+    }
+
+    // Replaces the handler that services SYSCALL instructions
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscall_handler = handler;
+    }
+
+    // Starts sending a record of every executed instruction to `tracer`, or stops tracing with None
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn TraceSink>>) {
+        self.tracer = tracer;
+    }
+
+    // The address of the next instruction to execute
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // The stack pointer, which is the number of values on the stack
+    pub fn sp(&self) -> usize {
+        self.stack.len()
+    }
+
+    // The frame pointer
+    pub fn fp(&self) -> usize {
+        self.fp
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    // Validates an assembled program file and loads it, ready to run from its entry point
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), LoadError> {
+        let object = ObjectFile::from_bytes(bytes)?;
+        self.load_object(object);
+        Ok(())
+    }
+
+    // Replaces the program and starts it over: registers, flags, the stack and the heap are
+    // cleared, while the limits, handlers and policies set on the VM are kept
+    pub fn load_object(&mut self, object: ObjectFile) {
+        self.program = object.code;
+        self.decoded.clear();
+        self.rodata = object.rodata;
+        self.pc = object.entry_point as usize;
+        self.registers = [0; REGISTER_COUNT];
+        self.float_registers = [0.0; REGISTER_COUNT];
+        self.remainder = 0;
+        self.flags = Flags::default();
+        self.instruction_start = 0;
+        self.stack.clear();
+        self.fp = 0;
+        self.heap.clear();
+    }
+
+    // Captures everything needed to resume the program later, possibly in another process
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pc: self.pc,
+            program: self.program.clone(),
+            rodata: self.rodata.clone(),
+            remainder: self.remainder,
+            flags: self.flags,
+            overflow_policy: self.overflow_policy,
+            stack: self.stack.clone(),
+            stack_limit: self.stack_limit,
+            fp: self.fp,
+            heap: self.heap.clone(),
+            heap_limit: self.heap_limit,
         }
     }
 
+    // Puts back the state from `snapshot`. The syscall handler, cost table and deadline are kept
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.registers = snapshot.registers;
+        self.float_registers = snapshot.float_registers;
+        self.pc = snapshot.pc;
+        self.program = snapshot.program;
+        self.decoded.clear();
+        self.rodata = snapshot.rodata;
+        self.remainder = snapshot.remainder;
+        self.flags = snapshot.flags;
+        self.overflow_policy = snapshot.overflow_policy;
+        self.stack = snapshot.stack;
+        self.stack_limit = snapshot.stack_limit;
+        self.fp = snapshot.fp;
+        self.heap = snapshot.heap;
+        self.heap_limit = snapshot.heap_limit;
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    // Replaces the program bytecode without touching the rest of the state
+    pub fn set_program(&mut self, program: Vec<u8>) {
+        self.program = program;
+        self.decoded.clear();
+    }
+
     // Adds a byte to the program bytecode
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte);
+        self.decoded.clear();
     }
 
     // Loops as long as there are still instructions available
-    pub fn run(&mut self) {
-        let mut is_done: bool = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    pub fn run(&mut self) -> Result<ExitReason, VmError> {
+        loop {
+            match self.execute_instruction()? {
+                ExitReason::Stepped => (),
+                reason => return Ok(reason),
+            }
         }
     }
 
+    // Decodes the whole program once so `run_predecoded` does not have to decode an instruction
+    // every time it is executed. Changing the program drops the decoded instructions, so call it
+    // again afterwards to keep the speedup
+    pub fn predecode(&mut self) {
+        self.decoded = decode_program(&self.program);
+    }
+
+    // Runs like `run`, but executes the instructions decoded by `predecode`
+    pub fn run_predecoded(&mut self) -> Result<ExitReason, VmError> {
+        loop {
+            match self.execute_predecoded()? {
+                ExitReason::Stepped => (),
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    // Runs with `fuel` to spend on instructions, each costing what the cost table says. Stops
+    // with OutOfFuel before an instruction it cannot pay for, or with DeadlineExceeded once the
+    // deadline has passed. Either way the program can be resumed with another call
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<ExitReason, VmError> {
+        self.fuel = fuel;
+        let mut until_deadline_check = 0;
+        loop {
+            if until_deadline_check == 0 {
+                if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Ok(ExitReason::DeadlineExceeded);
+                }
+                until_deadline_check = DEADLINE_CHECK_INTERVAL;
+            }
+            until_deadline_check -= 1;
+
+            if let Some(&byte) = self.program.get(self.pc) {
+                let cost = self.costs.cost(Opcode::from(byte));
+                if cost > self.fuel {
+                    return Ok(ExitReason::OutOfFuel);
+                }
+                self.fuel -= cost;
+            }
+            match self.execute_predecoded()? {
+                ExitReason::Stepped => (),
+                reason => return Ok(reason),
+            }
+        }
+    }
+
+    // The fuel left over from the last call to `run_with_fuel`
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
     // Executes only one instruction. Meant for debugging the VM
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    pub fn run_once(&mut self) -> Result<ExitReason, VmError> {
+        self.execute_instruction()
     }
 
-    // Executes the next instruction that is read from the program
-    fn execute_instruction(&mut self) -> bool {
+    // Executes the instruction at the program counter, decoding it from the program bytes
+    fn execute_instruction(&mut self) -> Result<ExitReason, VmError> {
         if self.pc >= self.program.len() {
-            return true;
+            return Ok(ExitReason::EndOfProgram);
         }
+        self.instruction_start = self.pc;
+        // An instruction that fails to decode leaves the program counter after its opcode
+        self.pc += 1;
+        let instruction = decode_instruction(&self.program, self.instruction_start)?;
+        self.execute(instruction)
+    }
 
-        match self.decode_opcode() {
-            Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number: u16 = self.next_16_bits();
-                self.registers[register] = number as i32;
+    // Executes the instruction at the program counter from the pre-decoded stream. Addresses
+    // that have no decoded instruction, such as the target of an unaligned jump, are decoded
+    // from the program bytes instead
+    fn execute_predecoded(&mut self) -> Result<ExitReason, VmError> {
+        let decoded = match self.pc % INSTRUCTION_SIZE {
+            0 => self.decoded.get(self.pc / INSTRUCTION_SIZE).copied().flatten(),
+            _ => None,
+        };
+        match decoded {
+            Some(instruction) if self.pc < self.program.len() => {
+                self.instruction_start = self.pc;
+                self.execute(instruction)
             },
-            Opcode::ADD => {
-                // Value from first register
-                let register1 = self.registers[self.next_8_bits() as usize];
-                // Value from second register
-                let register2 = self.registers[self.next_8_bits() as usize];
-                // Place the new value in the specified register
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+            _ => self.execute_instruction(),
+        }
+    }
+
+    // Executes a decoded instruction that starts at `instruction_start`, tracing it when a
+    // tracer is set
+    fn execute(&mut self, instruction: DecodedInstruction) -> Result<ExitReason, VmError> {
+        if self.tracer.is_none() {
+            return self.dispatch(instruction);
+        }
+        let (reads, writes) = trace::accesses(&instruction);
+        let reads = reads.into_iter()
+            .map(|register| RegisterRead { register, value: self.register_value(register) })
+            .collect();
+        let before: Vec<Value> = writes.iter().map(|register| self.register_value(*register)).collect();
+
+        let result = self.dispatch(instruction);
+
+        let writes = writes.into_iter().zip(before)
+            .map(|(register, before)| RegisterWrite { register, before, after: self.register_value(register) })
+            .collect();
+        let record = TraceRecord { pc: self.instruction_start, instruction, reads, writes };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.record(&record);
+        }
+        result
+    }
+
+    fn register_value(&self, register: trace::Register) -> Value {
+        match register {
+            trace::Register::Integer(register) => Value::Integer(self.registers[register]),
+            trace::Register::Float(register) => Value::Float(self.float_registers[register]),
+        }
+    }
+
+    fn dispatch(&mut self, instruction: DecodedInstruction) -> Result<ExitReason, VmError> {
+        self.pc = self.instruction_start + instruction.length;
+        let opcode = instruction.opcode;
+        let [a, b, c] = instruction.operands;
+        let (a, b, c) = (a as usize, b as usize, c as usize);
+        match opcode {
+            Opcode::LOAD => self.registers[a] = b as i32,
+            // The immediate is signed, so #-1 is stored as 0xFFFF
+            Opcode::LOADS => self.registers[a] = b as u16 as i16 as i32,
+            Opcode::ADD | Opcode::SUB | Opcode::MUL | Opcode::DIV => {
+                self.registers[c] = self.arithmetic(opcode, self.registers[a], self.registers[b])?;
             },
-            Opcode::SUB => {
-                // Value from first register
-                let register1 = self.registers[self.next_8_bits() as usize];
-                // Value from second register
-                let register2 = self.registers[self.next_8_bits() as usize];
-                // Place the new value in the specified register
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI => {
+                let immediate = b as u8 as i8 as i32;
+                let opcode = match opcode {
+                    Opcode::ADDI => Opcode::ADD,
+                    Opcode::SUBI => Opcode::SUB,
+                    _ => Opcode::MUL,
+                };
+                self.registers[c] = self.arithmetic(opcode, self.registers[a], immediate)?;
             },
-            Opcode::MUL => {
-                // Value from first register
-                let register1 = self.registers[self.next_8_bits() as usize];
-                // Value from second register
-                let register2 = self.registers[self.next_8_bits() as usize];
-                // Place the new value in the specified register
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+            Opcode::INC | Opcode::DEC => {
+                let opcode = if opcode == Opcode::INC { Opcode::ADD } else { Opcode::SUB };
+                self.registers[a] = self.arithmetic(opcode, self.registers[a], 1)?;
             },
-            Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 /register2;
-                self.remainder = (register1 % register2) as u32;
-
+            Opcode::MOV => self.registers[b] = self.registers[a],
+            Opcode::CMP => self.compare(self.registers[a], self.registers[b])?,
+            Opcode::CMPI => self.compare(self.registers[a], b as u16 as i16 as i32)?,
+            Opcode::MOD => {
+                if self.registers[b] == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.instruction_start });
+                }
+                // i32::MIN % -1 overflows, but its remainder is 0
+                self.registers[c] = self.registers[a].wrapping_rem(self.registers[b]);
             },
-            Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-
-                match register1 == register2 {
-                    true => self.registers[self.next_8_bits() as usize] = true as i32,
-                    _ => self.registers[self.next_8_bits() as usize] = false as i32,
+            Opcode::GETREM => self.registers[a] = self.remainder as i32,
+            Opcode::AND => self.registers[c] = self.registers[a] & self.registers[b],
+            Opcode::OR => self.registers[c] = self.registers[a] | self.registers[b],
+            Opcode::XOR => self.registers[c] = self.registers[a] ^ self.registers[b],
+            Opcode::NOT => self.registers[b] = !self.registers[a],
+            // Shift amounts wrap at 32, so only their low 5 bits count
+            Opcode::SHL => self.registers[c] = self.registers[a].wrapping_shl(self.registers[b] as u32),
+            Opcode::SHR => self.registers[c] = (self.registers[a] as u32).wrapping_shr(self.registers[b] as u32) as i32,
+            Opcode::SAR => self.registers[c] = self.registers[a].wrapping_shr(self.registers[b] as u32),
+            Opcode::LOADF => {
+                let bytes = self.rodata.get(b..b + 8)
+                    .ok_or(VmError::DataOutOfBounds { address: b, pc: self.instruction_start })?;
+                let mut value = [0; 8];
+                value.copy_from_slice(bytes);
+                self.float_registers[a] = f64::from_be_bytes(value);
+            },
+            Opcode::ADDF | Opcode::SUBF | Opcode::MULF | Opcode::DIVF => {
+                let (register1, register2) = (self.float_registers[a], self.float_registers[b]);
+                // IEEE 754 rules apply, so dividing by zero gives an infinity or NaN
+                self.float_registers[c] = match opcode {
+                    Opcode::ADDF => register1 + register2,
+                    Opcode::SUBF => register1 - register2,
+                    Opcode::MULF => register1 * register2,
+                    _ => register1 / register2,
+                };
+            },
+            Opcode::EQF | Opcode::NEQF | Opcode::GTF | Opcode::LTF | Opcode::GQTF | Opcode::LQTF => {
+                let (register1, register2) = (self.float_registers[a], self.float_registers[b]);
+                // Every comparison with NaN but NEQF is false
+                self.registers[c] = match opcode {
+                    Opcode::EQF => register1 == register2,
+                    Opcode::NEQF => register1 != register2,
+                    Opcode::GTF => register1 > register2,
+                    Opcode::LTF => register1 < register2,
+                    Opcode::GQTF => register1 >= register2,
+                    _ => register1 <= register2,
+                } as i32;
+            },
+            Opcode::ITOF => self.float_registers[b] = self.registers[a] as f64,
+            // `as` rounds toward zero, clamps to the i32 range and turns NaN into 0
+            Opcode::FTOI => self.registers[b] = self.float_registers[a] as i32,
+            Opcode::EQ => self.registers[c] = (self.registers[a] == self.registers[b]) as i32,
+            Opcode::NEQ => self.registers[c] = (self.registers[a] != self.registers[b]) as i32,
+            Opcode::GT => self.registers[c] = (self.registers[a] > self.registers[b]) as i32,
+            Opcode::LT => self.registers[c] = (self.registers[a] < self.registers[b]) as i32,
+            Opcode::LQT => self.registers[c] = (self.registers[a] <= self.registers[b]) as i32,
+            Opcode::GQT => self.registers[c] = (self.registers[a] >= self.registers[b]) as i32,
+            Opcode::JEQ => {
+                if self.registers[a] == 1 {
+                    self.jump_to(self.registers[b] as i64)?;
                 }
             },
-            Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-
-                match register1 > register2 {
-                    true => self.registers[self.next_8_bits() as usize] = true as i32,
-                    _ => self.registers[self.next_8_bits() as usize] = false as i32,
+            Opcode::JNEQ => {
+                if self.registers[a] == 0 {
+                    self.jump_to(self.registers[b] as i64)?;
                 }
             },
-            Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-
-                match register1 < register2 {
-                    true => self.registers[self.next_8_bits() as usize] = true as i32,
-                    _ => self.registers[self.next_8_bits() as usize] = false as i32,
+            Opcode::JMP => self.jump_to(self.registers[a] as i64)?,
+            // JMPF and JMPB are relative to the byte after their register
+            Opcode::JMPF => self.jump_to(self.instruction_start as i64 + 2 + self.registers[a] as i64)?,
+            Opcode::JMPB => self.jump_to(self.instruction_start as i64 + 2 - self.registers[a] as i64)?,
+            Opcode::JMPI => self.jump_to(a as i64)?,
+            // The immediate offsets are relative to the next instruction
+            Opcode::JMPFI => self.jump_to(self.pc as i64 + a as i64)?,
+            Opcode::JMPBI => self.jump_to(self.pc as i64 - a as i64)?,
+            Opcode::JEQI => {
+                if self.registers[a] == 1 {
+                    self.jump_to(b as i64)?;
                 }
             },
-            Opcode::LQT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-
-                match register1 <= register2 {
-                    true => self.registers[self.next_8_bits() as usize] = true as i32,
-                    _ => self.registers[self.next_8_bits() as usize] = false as i32,
+            Opcode::JNEQI => {
+                if self.registers[a] == 0 {
+                    self.jump_to(b as i64)?;
                 }
             },
-            Opcode::GQT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-
-                match register1 >= register2 {
-                    true => self.registers[self.next_8_bits() as usize] = true as i32,
-                    _ => self.registers[self.next_8_bits() as usize] = false as i32,
+            Opcode::JZ | Opcode::JNZ | Opcode::JN | Opcode::JO | Opcode::JC => {
+                let taken = match opcode {
+                    Opcode::JZ => self.flags.zero,
+                    Opcode::JNZ => !self.flags.zero,
+                    Opcode::JN => self.flags.negative,
+                    Opcode::JO => self.flags.overflow,
+                    _ => self.flags.carry,
+                };
+                if taken {
+                    self.jump_to(a as i64)?;
                 }
             },
-            Opcode::JEQ => {
-                let is_equal = self.registers[self.next_8_bits() as usize];
-                let jump_register = self.registers[self.next_8_bits() as usize];
-
-                if is_equal == 1 {
-                    self.pc = jump_register as usize;
-                } else{
-                    // Need to trash the remaining 8 bits
-                    self.next_8_bits();
+            Opcode::PUSH => self.push(self.registers[a])?,
+            Opcode::POP => self.registers[a] = self.pop()?,
+            Opcode::CALL => {
+                // A frame holds the return address followed by the caller's frame pointer
+                self.push(self.pc as i32)?;
+                self.push(self.fp as i32)?;
+                self.fp = self.stack.len();
+                self.jump_to(a as i64)?;
+            },
+            Opcode::RET => {
+                // Returning without a frame (or after the frame was popped) is an underflow
+                if self.fp < 2 || self.fp > self.stack.len() {
+                    return Err(VmError::StackUnderflow { pc: self.instruction_start });
                 }
+                // Throw away anything the subroutine left on the stack
+                self.stack.truncate(self.fp);
+                self.fp = self.pop()? as usize;
+                let return_address = self.pop()?;
+                self.jump_to(return_address as i64)?;
             },
-            Opcode::JNEQ => {
-                let is_equal = self.registers[self.next_8_bits() as usize];
-                let jump_register = self.registers[self.next_8_bits() as usize];
-
-                if is_equal == 0 {
-                    self.pc = jump_register as usize;
-                } else {
-                    // Need to trash the remaining 8 bits
-                    self.next_8_bits();
+            Opcode::ALOC => {
+                let bytes = self.registers[a] as i64;
+                let new_size = self.heap.len() as i64 + bytes;
+                if bytes < 0 || new_size > self.heap_limit as i64 {
+                    return Err(VmError::InvalidAllocation { bytes, pc: self.instruction_start });
                 }
+                self.heap.resize(new_size as usize, 0);
             },
-            Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
-                self.pc = target as usize;
+            Opcode::LOADB => {
+                let address = self.heap_address(b, c, 1)?;
+                self.registers[a] = self.heap[address] as i32;
             },
-            Opcode::JMPF => {
-                let jump_value = self.registers[self.next_8_bits() as usize];
-                self.pc += jump_value as usize;
+            Opcode::LOADH => {
+                let address = self.heap_address(b, c, 2)?;
+                self.registers[a] = u16::from_be_bytes([self.heap[address], self.heap[address + 1]]) as i32;
+            },
+            Opcode::LOADM => {
+                let address = self.heap_address(b, c, 4)?;
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(&self.heap[address..address + 4]);
+                self.registers[a] = i32::from_be_bytes(bytes);
+            },
+            Opcode::STOREB => {
+                let address = self.heap_address(b, c, 1)?;
+                self.heap[address] = self.registers[a] as u8;
+            },
+            Opcode::STOREH => {
+                let address = self.heap_address(b, c, 2)?;
+                let bytes = (self.registers[a] as u16).to_be_bytes();
+                self.heap[address..address + 2].copy_from_slice(&bytes);
+            },
+            Opcode::STOREM => {
+                let address = self.heap_address(b, c, 4)?;
+                let bytes = self.registers[a].to_be_bytes();
+                self.heap[address..address + 4].copy_from_slice(&bytes);
+            },
+            Opcode::SYSCALL => {
+                let number = self.registers[a];
+                let mut context = SyscallContext {
+                    registers: &mut self.registers,
+                    rodata: &self.rodata,
+                    heap: &mut self.heap,
+                };
+                let pc = self.instruction_start;
+                match self.syscall_handler.syscall(number, &mut context) {
+                    Ok(SyscallResult::Continue) => (),
+                    Ok(SyscallResult::Exit(code)) => return Ok(ExitReason::Exit(code)),
+                    Err(SyscallError::Unknown) => return Err(VmError::UnknownSyscall { number, pc }),
+                    Err(SyscallError::Failed(reason)) => return Err(VmError::SyscallFailed { number, reason, pc }),
+                }
             },
-            Opcode::JMPB => {
-                let jump_value = self.registers[self.next_8_bits() as usize];
-                self.pc -= jump_value as usize;
-            }
             Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
+                return Ok(ExitReason::Halted);
+            },
+            Opcode::IGL => {
+                return Err(VmError::IllegalOpcode {
+                    opcode: self.program[self.instruction_start],
+                    pc: self.instruction_start,
+                });
+            },
+        }
+        Ok(ExitReason::Stepped)
+    }
+
+    // Computes `a op b` for ADD, SUB, MUL or DIV. The overflow policy decides what the result is
+    // when it overflows, and the flags are set from that result
+    fn arithmetic(&mut self, opcode: Opcode, a: i32, b: i32) -> Result<i32, VmError> {
+        let (wrapped, overflow, carry) = self.overflowing(opcode, a, b)?;
+        let result = match (overflow, self.overflow_policy) {
+            (false, _) | (true, OverflowPolicy::Wrap) => wrapped,
+            (true, OverflowPolicy::Saturate) => match opcode {
+                Opcode::ADD => a.saturating_add(b),
+                Opcode::SUB => a.saturating_sub(b),
+                Opcode::MUL => a.saturating_mul(b),
+                _ => i32::MAX,
+            },
+            (true, OverflowPolicy::Trap) => return Err(VmError::ArithmeticOverflow { pc: self.instruction_start }),
+        };
+        self.flags = Flags { zero: result == 0, negative: result < 0, overflow, carry };
+        Ok(result)
+    }
+
+    // Sets the flags from `a - b` without storing it. Comparing never traps
+    fn compare(&mut self, a: i32, b: i32) -> Result<(), VmError> {
+        let (result, overflow, carry) = self.overflowing(Opcode::SUB, a, b)?;
+        self.flags = Flags { zero: result == 0, negative: result < 0, overflow, carry };
+        Ok(())
+    }
+
+    // The wrapped result of `a op b` along with whether it overflowed and whether it carried
+    fn overflowing(&mut self, opcode: Opcode, a: i32, b: i32) -> Result<(i32, bool, bool), VmError> {
+        Ok(match opcode {
+            Opcode::ADD => {
+                let (result, overflow) = a.overflowing_add(b);
+                (result, overflow, (a as u32).overflowing_add(b as u32).1)
+            },
+            Opcode::SUB => {
+                let (result, overflow) = a.overflowing_sub(b);
+                (result, overflow, (a as u32) < (b as u32))
+            },
+            Opcode::MUL => {
+                let (result, overflow) = a.overflowing_mul(b);
+                (result, overflow, (a as u32).overflowing_mul(b as u32).1)
             },
             _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return true;
+                if b == 0 {
+                    return Err(VmError::DivisionByZero { pc: self.instruction_start });
+                }
+                // Only i32::MIN / -1 overflows
+                let (result, overflow) = a.overflowing_div(b);
+                self.remainder = a.wrapping_rem(b) as u32;
+                (result, overflow, false)
             },
+        })
+    }
+
+    // The heap address `$base #offset` of a load or store, checking that `width` bytes fit there
+    fn heap_address(&self, base: usize, offset: usize, width: usize) -> Result<usize, VmError> {
+        let address = self.registers[base] as i64 + offset as i64;
+        if address < 0 || address as usize + width > self.heap.len() {
+            return Err(VmError::MemoryOutOfBounds { address, pc: self.instruction_start });
         }
-        false
+        Ok(address as usize)
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    fn push(&mut self, value: i32) -> Result<(), VmError> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(VmError::StackOverflow { pc: self.instruction_start });
+        }
+        self.stack.push(value);
+        Ok(())
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    fn pop(&mut self) -> Result<i32, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow { pc: self.instruction_start })
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+    // Moves the program counter to `target`. Jumping to the very end of the program is
+    // allowed and simply ends execution
+    fn jump_to(&mut self, target: i64) -> Result<(), VmError> {
+        if target < 0 || target as usize > self.program.len() {
+            return Err(VmError::JumpOutOfRange { target, pc: self.instruction_start });
+        }
+        self.pc = target as usize;
+        Ok(())
     }
 }
 
@@ -192,6 +700,7 @@ impl VM {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::syscall::CaptureHandler;
 
     #[test]
     fn test_create_vm() {
@@ -204,7 +713,7 @@ mod test {
         let mut test_vm = VM::new();
         let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -213,15 +722,200 @@ mod test {
         let mut test_vm = VM::new();
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run();
+        assert_eq!(test_vm.run(), Err(VmError::IllegalOpcode { opcode: 200, pc: 0 }));
         assert_eq!(test_vm.pc, 1);
     }
 
+    #[test]
+    fn test_hlt_returns_halted() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![0, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+    }
+
+    #[test]
+    fn test_end_of_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 15];
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::Stepped));
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::EndOfProgram));
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 15, 1, 0];
+        assert_eq!(test_vm.run(), Err(VmError::TruncatedInstruction { pc: 4 }));
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![2, 0, 32, 1];
+        assert_eq!(test_vm.run(), Err(VmError::InvalidRegister { register: 32, pc: 0 }));
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![5, 0, 1, 3];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 0 }));
+        assert_eq!(test_vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_overflow_wraps_by_default() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![2, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert_eq!(test_vm.flags(), Flags { zero: false, negative: true, overflow: true, carry: false });
+    }
+
+    #[test]
+    fn test_overflow_saturates() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_policy = OverflowPolicy::Saturate;
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = 2;
+        test_vm.registers[4] = -1;
+        // SUB $0 $1 $2, MUL $0 $1 $3, DIV $0 $4 $5
+        test_vm.program = vec![3, 0, 1, 2, 4, 0, 1, 3, 5, 0, 4, 5];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..4], [i32::MIN, i32::MIN]);
+        assert_eq!(test_vm.registers[5], i32::MAX);
+        assert!(test_vm.flags().overflow);
+    }
+
+    #[test]
+    fn test_overflow_traps() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_policy = OverflowPolicy::Trap;
+        test_vm.registers[0] = 0x10000;
+        // ADD $0 $0 $1, MUL $0 $0 $2
+        test_vm.program = vec![2, 0, 0, 1, 4, 0, 0, 2];
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 4 }));
+        assert_eq!(test_vm.registers[1], 0x20000);
+        assert_eq!(test_vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_carry_and_zero_flags() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.registers[1] = 1;
+        test_vm.program = vec![2, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.flags(), Flags { zero: true, negative: false, overflow: false, carry: true });
+        // 1 - (-1) borrows when the registers are read as unsigned
+        test_vm.program = vec![3, 1, 0, 2];
+        test_vm.pc = 0;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.flags(), Flags { zero: false, negative: false, overflow: false, carry: true });
+    }
+
+    #[test]
+    fn test_flag_jump_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 1;
+        // 0-3: SUB $0 $1 $2 (-1)
+        // 4-7: JN to 12
+        // 8-11: HLT
+        // 12-15: JZ to 0, not taken
+        // 16-19: JNZ to 24
+        // 24-27: JC to 32
+        test_vm.program = vec![3, 0, 1, 2, 45, 0, 12, 0, 0, 0, 0, 0, 43, 0, 0, 0, 44, 0, 24, 0, 0, 0, 0, 0, 47, 0, 32, 0, 0, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.pc, 32);
+    }
+
+    #[test]
+    fn test_loadf_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.rodata = [vec![0xAA], 2.5f64.to_be_bytes().to_vec()].concat();
+        // LOADF $f3 at offset 1
+        test_vm.program = vec![48, 3, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[3], 2.5);
+        test_vm.program = vec![48, 3, 0, 2];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Err(VmError::DataOutOfBounds { address: 2, pc: 0 }));
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 0.5;
+        // ADDF, SUBF, MULF and DIVF $f0 $f1 into $f2 through $f5, then DIVF $f0 by $f6 (0) into $f7
+        test_vm.program = vec![49, 0, 1, 2, 50, 0, 1, 3, 51, 0, 1, 4, 52, 0, 1, 5, 52, 0, 6, 7];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2..6], [2.0, 1.0, 0.75, 3.0]);
+        assert_eq!(test_vm.float_registers[7], f64::INFINITY);
+    }
+
+    #[test]
+    fn test_float_comparison_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.float_registers[2] = f64::NAN;
+        // EQF, NEQF, GTF, LTF, GQTF and LQTF $f0 $f1 into $0 through $5, then EQF $f2 $f2 into $6
+        test_vm.program = vec![53, 0, 1, 0, 54, 0, 1, 1, 55, 0, 1, 2, 56, 0, 1, 3, 57, 0, 1, 4, 58, 0, 1, 5, 53, 2, 2, 6];
+        test_vm.registers[6] = 1;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[..7], [0, 1, 0, 1, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_float_conversion_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[1] = -2.75;
+        test_vm.float_registers[2] = 1e12;
+        // ITOF $0 $f0, FTOI $f1 $1, FTOI $f2 $2
+        test_vm.program = vec![59, 0, 0, 0, 60, 1, 1, 0, 60, 2, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], -7.0);
+        assert_eq!(test_vm.registers[1..3], [-2, i32::MAX]);
+    }
+
+    #[test]
+    fn test_arithmetic_overflow_wraps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.registers[2] = 1;
+        // DIV $0 $1 $3, ADD $0 $1 $4, SUB $0 $2 $5, MUL $0 $1 $6
+        test_vm.program = vec![5, 0, 1, 3, 2, 0, 1, 4, 3, 0, 2, 5, 4, 0, 1, 6];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3..7], [i32::MIN, i32::MAX, i32::MAX, i32::MIN]);
+    }
+
+    #[test]
+    fn test_jump_out_of_range() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.program = vec![6, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::JumpOutOfRange { target: 100, pc: 0 }));
+    }
+
+    #[test]
+    fn test_jmpb_before_start_of_program() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 3;
+        test_vm.program = vec![8, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::JumpOutOfRange { target: -1, pc: 0 }));
+    }
+
     #[test]
     fn test_load_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244]; // this is how we represent 500 using two u8s in little endian format
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -229,10 +923,68 @@ mod test {
     fn test_load_opcode2() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 0, 15]; // this is how we represent 500 using two u8s in little endian format
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 15);
     }
 
+    #[test]
+    fn test_load_is_unsigned() {
+        let mut test_vm = VM::new();
+        // LOAD $1 #0xFFFF, LOADS $2 #-1
+        test_vm.program = vec![1, 1, 0xFF, 0xFF, 69, 2, 0xFF, 0xFF];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1..3], [65535, -1]);
+    }
+
+    #[test]
+    fn test_immediate_arithmetic_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        // 0-3: ADDI $0 #-3 $1
+        // 4-7: SUBI $1 #4 $2
+        // 8-11: MULI $2 #-2 $3
+        // 12-15: MOV $3 $4
+        // 16-19: INC $4
+        // 20-23: DEC $0
+        test_vm.program = vec![62, 0, 253, 1, 63, 1, 4, 2, 64, 2, 254, 3, 61, 3, 4, 0, 65, 4, 0, 0, 66, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[..5], [9, 7, 3, -6, -5]);
+        assert_eq!(test_vm.flags(), Flags { zero: false, negative: false, overflow: false, carry: false });
+    }
+
+    #[test]
+    fn test_increment_follows_the_overflow_policy() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_policy = OverflowPolicy::Trap;
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![65, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::ArithmeticOverflow { pc: 0 }));
+        assert_eq!(test_vm.registers[0], i32::MAX);
+    }
+
+    #[test]
+    fn test_compare_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.overflow_policy = OverflowPolicy::Trap;
+        test_vm.registers[0] = 5;
+        test_vm.registers[1] = 5;
+        test_vm.program = vec![67, 0, 1, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.flags(), Flags { zero: true, negative: false, overflow: false, carry: false });
+        // CMPI $0 #-300 leaves both registers untouched
+        test_vm.program = vec![68, 0, 254, 212];
+        test_vm.pc = 0;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.flags(), Flags { zero: false, negative: false, overflow: false, carry: true });
+        assert_eq!(test_vm.registers[..2], [5, 5]);
+        // Comparing never traps, even when the subtraction overflows
+        test_vm.registers[0] = i32::MIN;
+        test_vm.program = vec![67, 0, 1, 0];
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert!(test_vm.flags().overflow);
+    }
+
     #[test]
     fn test_add_opcode() {
         let mut test_vm = VM::new();
@@ -240,7 +992,7 @@ mod test {
         // 4-7: Load 5 into register 1
         // 7-10: Add register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 15, 1, 1, 0, 5, 2, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 20);
     }
 
@@ -251,7 +1003,7 @@ mod test {
         // 4-7: Load 5 into register 1
         // 7-10: Subtract register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 15, 1, 1, 0, 5, 3, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 10);
     }
 
@@ -262,7 +1014,7 @@ mod test {
         // 4-7: Load 15 into register 1
         // 7-10: Subtract register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 5, 1, 1, 0, 15, 3, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], -10);
     }
 
@@ -273,7 +1025,7 @@ mod test {
         // 4-7: Load 5 into register 1
         // 7-10: Subtract register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 15, 1, 1, 0, 5, 4, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 75);
     }
 
@@ -284,7 +1036,7 @@ mod test {
         // 4-7: Load 2 into register 1
         // 7-10: divide register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 10, 1, 1, 0, 2, 5, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 5);
         assert_eq!(test_vm.remainder, 0);
     }
@@ -296,17 +1048,215 @@ mod test {
         // 4-7: Load 2 into register 1
         // 7-10: Subtract register 0 and 1 and load into register 3
         test_vm.program = vec![1, 0, 0, 15, 1, 1, 0, 2, 5, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 7);
         assert_eq!(test_vm.remainder, 1);
     }
 
+    #[test]
+    fn test_mod_opcode() {
+        let mut test_vm = VM::new();
+        // 0-3: load -17 into register 0
+        // 4-7: Load 5 into register 1
+        // 8-11: store the remainder of register 0 and 1 in register 3
+        test_vm.program = vec![69, 0, 0xFF, 0xEF, 1, 1, 0, 5, 41, 0, 1, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], -2);
+    }
+
+    #[test]
+    fn test_mod_by_zero() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.program = vec![41, 0, 1, 3];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_getrem_opcode() {
+        let mut test_vm = VM::new();
+        // 0-3: load -15 into register 0
+        // 4-7: Load 2 into register 1
+        // 8-11: divide register 0 and 1 and load into register 3
+        // 12-15: copy the remainder into register 4
+        // 16-19: Load 1 into register 5
+        test_vm.program = vec![69, 0, 0xFF, 0xF1, 1, 1, 0, 2, 5, 0, 1, 3, 42, 4, 0, 0, 1, 5, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[5], 1);
+        assert_eq!(test_vm.registers[3], -7);
+        assert_eq!(test_vm.registers[4], -1);
+    }
+
+    #[test]
+    fn test_and_or_xor_opcodes() {
+        let mut test_vm = VM::new();
+        // 0-3: load 12 into register 0
+        // 4-7: Load 10 into register 1
+        // 8-19: AND, OR and XOR register 0 and 1 into registers 2, 3 and 4
+        test_vm.program = vec![1, 0, 0, 12, 1, 1, 0, 10, 34, 0, 1, 2, 35, 0, 1, 3, 36, 0, 1, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2..5], [8, 14, 6]);
+    }
+
+    #[test]
+    fn test_not_opcode() {
+        let mut test_vm = VM::new();
+        // 0-3: load 5 into register 0
+        // 4-7: store the complement of register 0 in register 1
+        // 8-11: Load 1 into register 2
+        test_vm.program = vec![1, 0, 0, 5, 37, 0, 1, 0, 1, 2, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[1], -6);
+        assert_eq!(test_vm.registers[2], 1);
+    }
+
+    #[test]
+    fn test_shift_opcodes() {
+        let mut test_vm = VM::new();
+        // 0-3: load -16 into register 0
+        // 4-7: Load 2 into register 1
+        // 8-19: SHL, SHR and SAR register 0 by register 1 into registers 2, 3 and 4
+        test_vm.program = vec![69, 0, 0xFF, 0xF0, 1, 1, 0, 2, 38, 0, 1, 2, 39, 0, 1, 3, 40, 0, 1, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -64);
+        assert_eq!(test_vm.registers[3], 0x3FFFFFFC);
+        assert_eq!(test_vm.registers[4], -4);
+    }
+
+    #[test]
+    fn test_run_predecoded_matches_run() {
+        let programs = vec![
+            // Counts register 1 down to 0, then calls a subroutine
+            vec![1, 1, 0, 5, 66, 1, 0, 0, 44, 0, 4, 0, 23, 0, 20, 0, 0, 0, 0, 0, 65, 2, 0, 0, 24, 0, 0, 0],
+            // JMP $0 to the unaligned address 2, which is decoded from the bytes
+            vec![6, 0, 0, 0],
+            // Illegal opcode after a LOAD
+            vec![1, 0, 0, 15, 200, 0, 0, 0],
+            vec![1, 0, 0, 15, 1, 0],
+        ];
+        for program in programs {
+            let mut byte_level = VM::new();
+            byte_level.registers[0] = 2;
+            byte_level.program = program.clone();
+            let mut predecoded = VM::new();
+            predecoded.registers[0] = 2;
+            predecoded.program = program;
+            predecoded.predecode();
+            assert_eq!(predecoded.run_predecoded(), byte_level.run());
+            assert_eq!(predecoded.registers, byte_level.registers);
+            assert_eq!(predecoded.pc, byte_level.pc);
+        }
+    }
+
+    #[test]
+    fn test_run_predecoded_without_predecode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 15, 0, 0, 0, 0];
+        assert_eq!(test_vm.run_predecoded(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 15);
+    }
+
+    #[test]
+    fn test_changing_the_program_drops_predecoded_instructions() {
+        let mut test_vm = VM::new();
+        test_vm.set_program(vec![1, 0, 0, 15, 0, 0, 0, 0]);
+        test_vm.predecode();
+        test_vm.set_program(vec![1, 0, 0, 7, 0, 0, 0, 0]);
+        assert_eq!(test_vm.run_predecoded(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 7);
+    }
+
+    #[test]
+    fn test_run_with_fuel() {
+        let mut test_vm = VM::new();
+        // 0-3: INC $1
+        // 4-7: JMPI to 0, forever
+        test_vm.program = vec![65, 1, 0, 0, 16, 0, 0, 0];
+        assert_eq!(test_vm.run_with_fuel(5), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[1], 3);
+        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.fuel(), 0);
+        // Resumes from the instruction it could not pay for
+        assert_eq!(test_vm.run_with_fuel(2), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[1], 4);
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_run_with_fuel_costs() {
+        let mut test_vm = VM::new();
+        test_vm.costs.set(Opcode::INC, 10);
+        test_vm.program = vec![65, 1, 0, 0, 65, 1, 0, 0, 0, 0, 0, 0];
+        assert_eq!(test_vm.run_with_fuel(15), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[1], 1);
+        assert_eq!(test_vm.fuel(), 5);
+        assert_eq!(test_vm.run_with_fuel(11), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.fuel(), 0);
+    }
+
+    #[test]
+    fn test_run_with_fuel_deadline() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 0;
+        test_vm.program = vec![6, 0, 0, 0]; // infinite loop
+        test_vm.deadline = Some(Instant::now());
+        assert_eq!(test_vm.run_with_fuel(u64::MAX), Ok(ExitReason::DeadlineExceeded));
+        test_vm.deadline = Some(Instant::now() + std::time::Duration::from_millis(10));
+        assert_eq!(test_vm.run_with_fuel(u64::MAX), Ok(ExitReason::DeadlineExceeded));
+    }
+
+    #[test]
+    fn test_tracer() {
+        let sink = trace::CaptureSink::new();
+        let mut test_vm = VM::new();
+        test_vm.set_tracer(Some(Box::new(sink.clone())));
+        test_vm.float_registers[1] = 2.5;
+        // LOAD $0 #15, ADD $0 $0 $1, FTOI $f1 $2, DIV $0 $3 $4
+        test_vm.program = vec![1, 0, 0, 15, 2, 0, 0, 1, 60, 1, 2, 0, 5, 0, 3, 4];
+        assert_eq!(test_vm.run(), Err(VmError::DivisionByZero { pc: 12 }));
+        let lines: Vec<String> = sink.records().iter().map(|record| record.to_string()).collect();
+        assert_eq!(lines, vec![
+            "0000: LOAD $0 #15  writes $0 0 -> 15",
+            "0004: ADD $0 $0 $1  reads $0=15  writes $1 0 -> 30",
+            "0008: FTOI $f1 $2  reads $f1=2.5  writes $2 0 -> 2",
+            "000C: DIV $0 $3 $4  reads $0=15 $3=0  writes $4 0 -> 0",
+        ]);
+        test_vm.set_tracer(None);
+        test_vm.program = vec![1, 0, 0, 1];
+        test_vm.pc = 0;
+        test_vm.run().unwrap();
+        assert_eq!(sink.records().len(), 4);
+    }
+
+    #[test]
+    fn test_shift_amount_wraps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = 33;
+        test_vm.program = vec![38, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 2);
+    }
+
+    #[test]
+    fn test_every_instruction_moves_to_the_next() {
+        let jumps = ["JMP", "JMPF", "JMPB", "JMPI", "JMPFI", "JMPBI", "JEQ", "JNEQ", "JEQI", "JNEQI", "CALL", "JZ", "JNZ", "JN", "JO", "JC"];
+        for info in crate::instructions::INSTRUCTIONS.iter().filter(|info| !jumps.contains(&info.mnemonic)) {
+            let mut test_vm = VM::new();
+            test_vm.set_syscall_handler(Box::new(CaptureHandler::new()));
+            test_vm.program = vec![info.byte, 0, 0, 0];
+            if let Ok(ExitReason::Stepped) = test_vm.run_once() {
+                assert_eq!(test_vm.pc, 4, "{} does not skip its padding", info.mnemonic);
+            }
+        }
+    }
+
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 1;
         test_vm.program = vec![6, 0, 0, 0]; // infinite loop
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -315,7 +1265,7 @@ mod test {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
     }
 
@@ -324,7 +1274,7 @@ mod test {
         let mut test_vm = VM::new();
         test_vm.registers[0] = 2;
         test_vm.program = vec![8, 0, 0, 0, 6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 0);
     }
 
@@ -334,7 +1284,7 @@ mod test {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 8;
         test_vm.program = vec![9, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 0);
     }
 
@@ -344,8 +1294,19 @@ mod test {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[3], 1);
+    }
+
+    #[test]
+    fn test_neq_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 10;
+        test_vm.registers[1] = 8;
+        test_vm.program = vec![32, 0, 1, 3, 32, 0, 0, 4];
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 1);
+        assert_eq!(test_vm.registers[4], 0);
     }
 
     #[test]
@@ -354,7 +1315,7 @@ mod test {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 90;
         test_vm.program = vec![10, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 0);
     }
 
@@ -364,7 +1325,7 @@ mod test {
         test_vm.registers[0] = 12;
         test_vm.registers[1] = 8;
         test_vm.program = vec![10, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 1);
     }
 
@@ -374,7 +1335,7 @@ mod test {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 9;
         test_vm.program = vec![11, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 0);
     }
 
@@ -384,7 +1345,7 @@ mod test {
         test_vm.registers[0] = 8;
         test_vm.registers[1] = 9;
         test_vm.program = vec![11, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 1);
     }
 
@@ -395,7 +1356,7 @@ mod test {
         test_vm.registers[1] = 9;
         test_vm.registers[2] = 10;
         test_vm.program = vec![12, 0, 1, 3, 12, 0, 2, 4];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 1);
         assert_eq!(test_vm.registers[4], 1);
     }
@@ -406,7 +1367,7 @@ mod test {
         test_vm.registers[0] = 8;
         test_vm.registers[1] = 9;
         test_vm.program = vec![12, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 0);
     }
 
@@ -417,7 +1378,7 @@ mod test {
         test_vm.registers[1] = 9;
         test_vm.registers[2] = 8;
         test_vm.program = vec![13, 0, 1, 3, 13, 0, 2, 4];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 1);
         assert_eq!(test_vm.registers[4], 1);
     }
@@ -428,7 +1389,7 @@ mod test {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 9;
         test_vm.program = vec![13, 0, 1, 3];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[3], 0);
     }
 
@@ -439,7 +1400,7 @@ mod test {
         test_vm.registers[0] = 0; // destination register if equal
         test_vm.registers[3] = 1; // boolean value
         test_vm.program = vec![14, 3, 0, 0, 9, 0, 1, 3];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 0);
     }
 
@@ -449,7 +1410,7 @@ mod test {
         test_vm.registers[0] = 0; // destination register if equal
         test_vm.registers[3] = 0; // boolean value
         test_vm.program = vec![14, 3, 1, 0, 9, 0, 1, 3];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
     }
 
@@ -459,7 +1420,7 @@ mod test {
         test_vm.registers[0] = 0; // destination register if not equal
         test_vm.registers[3] = 1; // boolean value
         test_vm.program = vec![15, 3, 0, 0, 9, 0, 1, 3];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 4);
     }
 
@@ -469,7 +1430,246 @@ mod test {
         test_vm.registers[0] = 0; // destination register if not equal
         test_vm.registers[3] = 0; // boolean value
         test_vm.program = vec![15, 3, 1, 0, 9, 0, 1, 3];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pc, 0);
     }
+
+    #[test]
+    fn test_jmpi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![16, 0, 8, 0, 1, 0, 0, 1, 1, 1, 0, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_jmpfi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![17, 0, 4, 0, 1, 0, 0, 1, 1, 1, 0, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_jmpbi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![1, 0, 0, 1, 18, 0, 8, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_jeqi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[3] = 1;
+        test_vm.program = vec![19, 3, 0, 0, 20, 3, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 0);
+    }
+
+    #[test]
+    fn test_jneqi_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[3] = 1;
+        test_vm.program = vec![20, 3, 0, 0, 19, 3, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_push_and_pop_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 42;
+        test_vm.program = vec![21, 0, 0, 0, 22, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.sp(), 1);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.sp(), 0);
+        assert_eq!(test_vm.registers[1], 42);
+    }
+
+    #[test]
+    fn test_pop_empty_stack() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![22, 1, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut test_vm = VM::new();
+        test_vm.stack_limit = 1;
+        test_vm.program = vec![21, 0, 0, 0, 21, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackOverflow { pc: 4 }));
+    }
+
+    #[test]
+    fn test_call_and_ret_opcodes() {
+        let mut test_vm = VM::new();
+        // 0: CALL 8, 4: HLT, 8: LOAD $1 #7, 12: RET
+        test_vm.program = vec![23, 0, 8, 0, 0, 0, 0, 0, 1, 1, 0, 7, 24, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.fp(), 2);
+        assert_eq!(test_vm.stack(), &[4, 0]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[1], 7);
+        assert_eq!(test_vm.sp(), 0);
+        assert_eq!(test_vm.fp(), 0);
+    }
+
+    #[test]
+    fn test_ret_discards_values_left_on_the_stack() {
+        let mut test_vm = VM::new();
+        // 0: CALL 8, 4: HLT, 8: PUSH $0, 12: RET
+        test_vm.program = vec![23, 0, 8, 0, 0, 0, 0, 0, 21, 0, 0, 0, 24, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.sp(), 0);
+    }
+
+    #[test]
+    fn test_ret_without_call() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![24, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::StackUnderflow { pc: 0 }));
+    }
+
+    #[test]
+    fn test_aloc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 16;
+        test_vm.program = vec![25, 0, 0, 0, 25, 0, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap.len(), 32);
+    }
+
+    #[test]
+    fn test_aloc_negative_bytes() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![25, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::InvalidAllocation { bytes: -1, pc: 0 }));
+    }
+
+    #[test]
+    fn test_aloc_past_heap_limit() {
+        let mut test_vm = VM::new();
+        test_vm.heap_limit = 8;
+        test_vm.registers[0] = 9;
+        test_vm.program = vec![25, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::InvalidAllocation { bytes: 9, pc: 0 }));
+    }
+
+    #[test]
+    fn test_store_and_load_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = -2;
+        test_vm.registers[1] = 2;
+        // STOREM $0 $1 #0, LOADM $2 $1 #0, LOADH $3 $1 #2, LOADB $4 $1 #3
+        test_vm.program = vec![31, 0, 1, 0, 28, 2, 1, 0, 27, 3, 1, 2, 26, 4, 1, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 255, 255, 255, 254, 0, 0]);
+        assert_eq!(test_vm.registers[2], -2);
+        assert_eq!(test_vm.registers[3], 65534);
+        assert_eq!(test_vm.registers[4], 254);
+    }
+
+    #[test]
+    fn test_storeb_and_storeh_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x1234;
+        // STOREB $0 $1 #0, STOREH $0 $1 #2
+        test_vm.program = vec![29, 0, 1, 0, 30, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0x34, 0, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_load_out_of_bounds() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![28, 0, 1, 0];
+        assert_eq!(test_vm.run(), Err(VmError::MemoryOutOfBounds { address: 2, pc: 0 }));
+    }
+
+    #[test]
+    fn test_load_program_file() {
+        let mut object = ObjectFile::new(vec![1, 0, 0, 15, 1, 1, 0, 5, 0, 0, 0, 0]);
+        object.entry_point = 4;
+        object.rodata = vec![7];
+        let mut test_vm = VM::new();
+        test_vm.load(&object.to_bytes()).unwrap();
+        assert_eq!(test_vm.rodata, vec![7]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(test_vm.registers[1], 5);
+    }
+
+    #[test]
+    fn test_load_starts_over() {
+        let mut test_vm = VM::new();
+        // PUSH $0 after LOAD $0 #15, ALOC $0 and DIV $0 $0 $1 leave state behind
+        test_vm.program = vec![1, 0, 0, 15, 25, 0, 0, 0, 5, 0, 0, 1, 21, 0, 0, 0];
+        test_vm.stack_limit = 8;
+        test_vm.run().unwrap();
+        test_vm.float_registers[3] = 1.5;
+        test_vm.load(&ObjectFile::new(vec![0, 0, 0, 0]).to_bytes()).unwrap();
+        assert_eq!((test_vm.registers, test_vm.float_registers), ([0; REGISTER_COUNT], [0.0; REGISTER_COUNT]));
+        assert_eq!((test_vm.sp(), test_vm.fp(), test_vm.heap.len(), test_vm.flags()), (0, 0, 0, Flags::default()));
+        assert_eq!((test_vm.pc(), test_vm.remainder, test_vm.stack_limit), (0, 0, 8));
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_file() {
+        let mut test_vm = VM::new();
+        assert_eq!(test_vm.load(&[1, 0, 0, 15]), Err(LoadError::BadMagic));
+        assert!(test_vm.program.is_empty());
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = VM::new();
+        let handler = CaptureHandler::new();
+        handler.push_input("41");
+        test_vm.set_syscall_handler(Box::new(handler.clone()));
+        test_vm.rodata = b"sum: \0".to_vec();
+        // $1 = read, $2 = print int, $3 = print string, $4 = exit
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 2;
+        test_vm.registers[4] = 4;
+        test_vm.registers[5] = 1;
+        // SYSCALL $1, ADD $0 $5 $6, LOAD $0 #0, SYSCALL $3, ADD $6 $0 $0, SYSCALL $2, SYSCALL $4
+        test_vm.program = vec![33, 1, 0, 0, 2, 0, 5, 6, 1, 0, 0, 0, 33, 3, 0, 0, 2, 6, 0, 0, 33, 2, 0, 0, 33, 4, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Exit(42)));
+        assert_eq!(handler.output(), "sum: 42");
+    }
+
+    #[test]
+    fn test_unknown_syscall() {
+        let mut test_vm = VM::new();
+        test_vm.set_syscall_handler(Box::new(CaptureHandler::new()));
+        test_vm.registers[1] = 77;
+        test_vm.program = vec![33, 1, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::UnknownSyscall { number: 77, pc: 0 }));
+    }
+
+    #[test]
+    fn test_failed_syscall() {
+        let mut test_vm = VM::new();
+        test_vm.set_syscall_handler(Box::new(CaptureHandler::new()));
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![33, 1, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmError::SyscallFailed {
+            number: 3,
+            reason: String::from("no input left to read"),
+            pc: 0,
+        }));
+    }
 }
\ No newline at end of file